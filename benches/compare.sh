@@ -0,0 +1,29 @@
+#!/usr/bin/env bash
+# Times a benchmark script on several revisions of the interpreter, built in release mode in temporary
+# worktrees: benches/compare.sh benches/fib.dl <revision>...
+# Prints the fastest and the median of 10 runs of each revision, in seconds.
+set -euo pipefail
+
+script=$(realpath "$1")
+shift
+runs=10
+
+for revision in "$@"; do
+    dir=$(mktemp -d)
+    git worktree add --quiet --detach "$dir" "$revision"
+    (cd "$dir" && cargo build --release --quiet)
+
+    times=()
+    for _ in $(seq $runs); do
+        start=$(date +%s%N)
+        "$dir/target/release/demo_lang" run "$script" > /dev/null
+        end=$(date +%s%N)
+        times+=("$(( (end - start) / 1000000 ))")
+    done
+
+    sorted=($(printf '%s\n' "${times[@]}" | sort -n))
+    printf '%-12s fastest %d.%03ds  median %d.%03ds\n' "$revision" \
+        $((sorted[0] / 1000)) $((sorted[0] % 1000)) $((sorted[runs / 2] / 1000)) $((sorted[runs / 2] % 1000))
+
+    git worktree remove --force "$dir"
+done
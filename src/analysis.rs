@@ -0,0 +1,501 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::ast::{Expression, MatchArm, Pattern, Program, Statement, TypeDef, Variable};
+use crate::compiler::{CompileError, CompileWarning};
+use crate::exhaustiveness::{check_match, Signature};
+use crate::source::SourceSpan;
+
+/// What is known at compile time about the value of a name
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Binding {
+    /// Assigned exactly once, to a lambda with this number of arguments, or a builtin function
+    Lambda(usize),
+    /// Argument of a lambda or variable with a value unknown at compile time
+    Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct VariantInfo {
+    pub typedef: String,
+    pub properties: Vec<String>,
+    pub span: SourceSpan,
+    /// Every variant of the type, to check if a match covers all of them
+    pub signature: Signature,
+}
+
+/// Names that exist before the program runs: builtins and globals defined by previously compiled
+/// programs, like the previous inputs of the REPL
+#[derive(Debug, Clone, Default)]
+pub struct Globals {
+    pub names: HashMap<String, Binding>,
+    pub variants: HashMap<String, VariantInfo>,
+    /// Names of `names` that are builtin functions, assigning one inside a lambda creates a local variable
+    pub builtins: HashSet<String>,
+}
+
+struct Scope {
+    /// Every name assigned in the function, nested lambdas can see all of them because they may run
+    /// after the whole function body
+    locals: HashMap<String, Binding>,
+    /// Names assigned so far in the function body
+    assigned: HashSet<String>,
+    variants: HashMap<String, VariantInfo>,
+    is_lambda: bool,
+    /// Scope of the pattern variables of a `match` arm, part of the function of the scope before it
+    is_arm: bool,
+}
+
+/// What the analyzer found out about a program
+pub struct Analysis {
+    /// Globals after the program, so later programs can use them
+    pub globals: Globals,
+    pub warnings: Vec<CompileWarning>,
+    /// Variables each lambda creates, by the span of the lambda. Assigning any other name in a lambda
+    /// changes a variable of an enclosing function, the code generator follows this decision.
+    pub lambda_locals: HashMap<SourceSpan, HashSet<String>>,
+}
+
+/// Resolution pass that runs before code generation, reports every mistake that can be found
+/// without running the program
+pub struct Analyzer<'a> {
+    globals: &'a Globals,
+    scopes: Vec<Scope>,
+    lambda_locals: HashMap<SourceSpan, HashSet<String>>,
+    /// Variants defined by the program, to detect duplicates
+    defined_variants: HashMap<String, VariantInfo>,
+    errors: Vec<CompileError>,
+    warnings: Vec<CompileWarning>,
+}
+
+impl<'a> Analyzer<'a> {
+    pub fn new(globals: &'a Globals) -> Self {
+        Analyzer {
+            globals,
+            scopes: vec![],
+            lambda_locals: Default::default(),
+            defined_variants: Default::default(),
+            errors: vec![],
+            warnings: vec![],
+        }
+    }
+
+    /// Checks the program, returns the errors found or what code generation needs to know
+    pub fn analyze(mut self, program: &Program) -> Result<Analysis, Vec<CompileError>> {
+        self.analyze_function(&[], &program.statements, None);
+
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+
+        let root = self.scopes.pop().unwrap();
+        let mut globals = self.globals.clone();
+
+        let mut changed_by_lambdas = HashSet::new();
+        for stm in &program.statements {
+            assigned_in_lambdas(stm, &mut changed_by_lambdas);
+        }
+
+        for name in changed_by_lambdas {
+            if let Some(binding) = globals.names.get_mut(&name) {
+                *binding = Binding::Value;
+            }
+        }
+
+        for name in root.locals.keys() {
+            globals.builtins.remove(name);
+        }
+        globals.names.extend(root.locals);
+        globals.variants.extend(root.variants);
+
+        Ok(Analysis { globals, warnings: self.warnings, lambda_locals: self.lambda_locals })
+    }
+
+    /// Analyzes the root of the program, or a lambda when `lambda` is its span
+    fn analyze_function(&mut self, args: &[String], code: &[Statement], lambda: Option<SourceSpan>) {
+        let is_lambda = lambda.is_some();
+        let mut scope = Scope {
+            locals: Default::default(),
+            assigned: Default::default(),
+            variants: Default::default(),
+            is_lambda,
+            is_arm: false,
+        };
+
+        for arg in args {
+            scope.locals.insert(arg.to_string(), Binding::Value);
+            scope.assigned.insert(arg.to_string());
+        }
+
+        // Lambdas may change these variables, so their value is not known at compile time
+        let mut changed_by_lambdas = HashSet::new();
+        for stm in code {
+            assigned_in_lambdas(stm, &mut changed_by_lambdas);
+        }
+
+        for stm in code {
+            match stm {
+                Statement::Variable(var) => {
+                    // Assigning a variable of an enclosing function changes it instead of creating a new one
+                    if is_lambda && !scope.locals.contains_key(&var.name) && self.is_outer_variable(&var.name) {
+                        continue;
+                    }
+
+                    let binding = match &var.value {
+                        Expression::Lambda { args, .. } if !changed_by_lambdas.contains(&var.name) => {
+                            Binding::Lambda(args.len())
+                        }
+                        _ => Binding::Value,
+                    };
+
+                    scope.locals.entry(var.name.to_string())
+                        .and_modify(|it| *it = Binding::Value)
+                        .or_insert(binding);
+                }
+                Statement::TypeDef(def) => {
+                    self.define_variants(&mut scope, def);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(span) = lambda {
+            self.lambda_locals.insert(span, scope.locals.keys().cloned().collect());
+        }
+
+        self.scopes.push(scope);
+
+        for stm in code {
+            self.analyze_statement(stm);
+        }
+
+        // The root scope is kept, so `analyze` can export its names
+        if is_lambda {
+            self.scopes.pop();
+        }
+    }
+
+    /// Checks if `name` is a variable of any enclosing function or a global, builtins are not
+    fn is_visible(&self, name: &str) -> bool {
+        self.scopes.iter().any(|it| it.locals.contains_key(name))
+            || (self.globals.names.contains_key(name) && !self.globals.builtins.contains(name))
+    }
+
+    /// Checks if a lambda created here that assigns `name` changes an existing variable: one assigned
+    /// before the lambda by an enclosing function, or a global of a previous program. Variables
+    /// assigned after the lambda don't count, so adding one doesn't change the meaning of earlier code.
+    fn is_outer_variable(&self, name: &str) -> bool {
+        self.scopes.iter().any(|it| it.assigned.contains(name))
+            || (self.globals.names.contains_key(name) && !self.globals.builtins.contains(name))
+    }
+
+    fn define_variants(&mut self, scope: &mut Scope, def: &TypeDef) {
+        let signature: Signature = Rc::new(
+            def.variants.iter().map(|it| (it.name.to_string(), it.properties.len())).collect()
+        );
+
+        for variant in &def.variants {
+            let previous = self.defined_variants.get(&variant.name)
+                .or_else(|| self.globals.variants.get(&variant.name).filter(|it| it.typedef != def.name));
+
+            if let Some(previous) = previous {
+                self.errors.push(CompileError::DuplicateVariant {
+                    name: variant.name.to_string(),
+                    typedef: previous.typedef.to_string(),
+                    span: variant.span,
+                    previous: previous.span,
+                });
+                continue;
+            }
+
+            let info = VariantInfo {
+                typedef: def.name.to_string(),
+                properties: variant.properties.clone(),
+                span: variant.span,
+                signature: signature.clone(),
+            };
+
+            self.defined_variants.insert(variant.name.to_string(), info.clone());
+            scope.variants.insert(variant.name.to_string(), info);
+        }
+    }
+
+    fn analyze_statement(&mut self, stm: &Statement) {
+        match stm {
+            Statement::Variable(var) => {
+                self.analyze_expression(&var.value);
+                self.scopes.last_mut().unwrap().assigned.insert(var.name.to_string());
+            }
+            Statement::Expression(expr) => {
+                self.analyze_expression(expr);
+            }
+            Statement::Import(import) => {
+                self.errors.push(CompileError::UnresolvedImport { path: import.path.to_string(), span: import.span });
+            }
+            Statement::TypeDef(_) | Statement::Error(_) => {}
+        }
+    }
+
+    fn analyze_expression(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Int { .. } | Expression::BigInt { .. } | Expression::Float { .. } | Expression::String { .. } => {}
+            Expression::FunCall { name, args, span } => {
+                for arg in args {
+                    self.analyze_expression(arg);
+                }
+                self.resolve_call(name, Some(args.len()), *span);
+            }
+            Expression::Reference { name, span } => {
+                self.resolve_call(name, None, *span);
+            }
+            Expression::Operator { left, right, .. } => {
+                self.analyze_expression(left);
+                self.analyze_expression(right);
+            }
+            Expression::UnaryOperator { expr, .. } => {
+                self.analyze_expression(expr);
+            }
+            Expression::List { items: values, .. } |
+            Expression::Tuple { values, .. } |
+            Expression::Interpolated { parts: values, .. } => {
+                for value in values {
+                    self.analyze_expression(value);
+                }
+            }
+            Expression::Lambda { args, code, span } => {
+                self.analyze_function(args, code, Some(*span));
+            }
+            Expression::Return { value, span } => {
+                if !self.scopes.last().unwrap().is_lambda {
+                    self.errors.push(CompileError::ReturnOutsideLambda { span: *span });
+                }
+                self.analyze_expression(value);
+            }
+            Expression::Match { value, arms, span } => {
+                self.analyze_expression(value);
+
+                for arm in arms {
+                    // Pattern variables are only visible in their arm
+                    let is_lambda = self.scopes.last().unwrap().is_lambda;
+                    self.scopes.push(Scope {
+                        locals: Default::default(),
+                        assigned: Default::default(),
+                        variants: Default::default(),
+                        is_lambda,
+                        is_arm: true,
+                    });
+                    self.analyze_pattern(&arm.pattern);
+
+                    if let Some(guard) = &arm.guard {
+                        self.analyze_expression(guard);
+                    }
+                    self.analyze_expression(&arm.body);
+                    self.scopes.pop();
+                }
+
+                self.check_match(arms, *span);
+            }
+            Expression::Property { value, name, span } => {
+                self.analyze_expression(value);
+
+                if !self.is_property(name) {
+                    if self.is_callable(name) {
+                        self.resolve_call(name, Some(1), *span);
+                    } else {
+                        self.errors.push(CompileError::UnknownProperty { name: name.to_string(), span: *span });
+                    }
+                }
+            }
+            Expression::Update { value, properties, .. } => {
+                self.analyze_expression(value);
+                let mut updated: HashMap<&str, SourceSpan> = HashMap::new();
+
+                for property in properties {
+                    self.analyze_expression(&property.value);
+
+                    if let Some(previous) = updated.insert(&property.name, property.span) {
+                        self.errors.push(CompileError::DuplicateProperty {
+                            name: property.name.to_string(),
+                            span: property.span,
+                            previous,
+                        });
+                    }
+
+                    if !self.is_property(&property.name) {
+                        self.errors.push(CompileError::UnknownProperty { name: property.name.to_string(), span: property.span });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks if some variant visible from here has a property named `name`
+    fn is_property(&self, name: &str) -> bool {
+        self.scopes.iter().flat_map(|it| it.variants.values())
+            .chain(self.globals.variants.values())
+            .any(|it| it.properties.iter().any(|property| property == name))
+    }
+
+    /// Checks if there is a variable, function, builtin or constructor named `name`
+    fn is_callable(&self, name: &str) -> bool {
+        self.is_visible(name) || self.globals.builtins.contains(name) || self.find_variant(name).is_some()
+    }
+
+    fn analyze_pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Binding { name, .. } => {
+                let scope = self.scopes.last_mut().unwrap();
+                scope.locals.insert(name.to_string(), Binding::Value);
+                scope.assigned.insert(name.to_string());
+            }
+            Pattern::Variant { name, args, span } => {
+                if !self.resolve_variant(name, Some(args.len()), *span) {
+                    self.errors.push(CompileError::UndefinedName { name: name.to_string(), span: *span });
+                }
+                args.iter().for_each(|it| self.analyze_pattern(it));
+            }
+            Pattern::Tuple { items, .. } => {
+                items.iter().for_each(|it| self.analyze_pattern(it));
+            }
+            Pattern::List { items, rest, .. } => {
+                items.iter().for_each(|it| self.analyze_pattern(it));
+                if let Some(rest) = rest {
+                    self.analyze_pattern(rest);
+                }
+            }
+            Pattern::Wildcard { .. } | Pattern::Int { .. } | Pattern::BigInt { .. } | Pattern::Float { .. } | Pattern::String { .. } => {}
+        }
+    }
+
+    /// Warns about arms that never match and values that no arm matches
+    fn check_match(&mut self, arms: &[MatchArm], span: SourceSpan) {
+        let patterns: Vec<(&Pattern, bool)> = arms.iter().map(|it| (&it.pattern, it.guard.is_some())).collect();
+        let report = check_match(&patterns, &|name| self.find_variant(name).map(|it| it.signature.clone()));
+
+        for index in report.unreachable {
+            self.warnings.push(CompileWarning::UnreachableArm { span: arms[index].span });
+        }
+
+        if !report.missing.is_empty() {
+            self.warnings.push(CompileWarning::NonExhaustiveMatch { missing: report.missing, span });
+        }
+    }
+
+    fn find_variant(&self, name: &str) -> Option<&VariantInfo> {
+        self.scopes.iter().rev()
+            .find_map(|it| it.variants.get(name))
+            .or_else(|| self.globals.variants.get(name))
+    }
+
+    /// Checks the number of properties given to a variant, if any, returns false if there is no
+    /// variant named `name`
+    fn resolve_variant(&mut self, name: &str, args: Option<usize>, span: SourceSpan) -> bool {
+        let expected = match self.find_variant(name) {
+            Some(variant) => variant.properties.len(),
+            None => return false,
+        };
+
+        if let Some(args) = args.filter(|it| *it != expected) {
+            self.errors.push(CompileError::ConstructorArity { name: name.to_string(), expected, found: args, span });
+        }
+
+        true
+    }
+
+    /// Checks that `name` exists, and if it's a lambda or a constructor that the number of arguments
+    /// matches. `args` is `None` for `@name`, which doesn't call it.
+    fn resolve_call(&mut self, name: &str, args: Option<usize>, span: SourceSpan) {
+        let mut binding = None;
+        let mut used_early = false;
+        let mut in_function = true;
+
+        for scope in self.scopes.iter().rev() {
+            let same_function = in_function;
+            in_function &= scope.is_arm;
+
+            if let Some(it) = scope.locals.get(name) {
+                // Code in the same function runs in order, so the variable must be already assigned,
+                // unless an outer scope also has it
+                if same_function && !scope.assigned.contains(name) {
+                    used_early = true;
+                    continue;
+                }
+                binding = Some(*it);
+                break;
+            }
+        }
+
+        if let Some(binding) = binding.or_else(|| self.globals.names.get(name).copied()) {
+            if let (Binding::Lambda(expected), Some(args)) = (binding, args) {
+                if expected != args {
+                    self.errors.push(CompileError::LambdaArity { name: name.to_string(), expected, found: args, span });
+                }
+            }
+            return;
+        }
+
+        if self.resolve_variant(name, args, span) {
+            return;
+        }
+
+        if used_early {
+            self.errors.push(CompileError::UsedBeforeAssignment { name: name.to_string(), span });
+        } else {
+            self.errors.push(CompileError::UndefinedName { name: name.to_string(), span });
+        }
+    }
+}
+
+/// Collects the names of the variables assigned inside the lambdas of a statement, at any depth
+fn assigned_in_lambdas(stm: &Statement, names: &mut HashSet<String>) {
+    fn visit(expr: &Expression, names: &mut HashSet<String>) {
+        match expr {
+            Expression::Int { .. } | Expression::BigInt { .. } | Expression::Float { .. } | Expression::String { .. } |
+            Expression::Reference { .. } => {}
+            Expression::FunCall { args, .. } => {
+                args.iter().for_each(|it| visit(it, names));
+            }
+            Expression::Operator { left, right, .. } => {
+                visit(left, names);
+                visit(right, names);
+            }
+            Expression::UnaryOperator { expr, .. } |
+            Expression::Return { value: expr, .. } |
+            Expression::Property { value: expr, .. } => {
+                visit(expr, names);
+            }
+            Expression::Update { value, properties, .. } => {
+                visit(value, names);
+                properties.iter().for_each(|it| visit(&it.value, names));
+            }
+            Expression::Match { value, arms, .. } => {
+                visit(value, names);
+                for arm in arms {
+                    if let Some(guard) = &arm.guard {
+                        visit(guard, names);
+                    }
+                    visit(&arm.body, names);
+                }
+            }
+            Expression::List { items: values, .. } |
+            Expression::Tuple { values, .. } |
+            Expression::Interpolated { parts: values, .. } => {
+                values.iter().for_each(|it| visit(it, names));
+            }
+            Expression::Lambda { code, .. } => {
+                for stm in code {
+                    if let Statement::Variable(var) = stm {
+                        names.insert(var.name.to_string());
+                    }
+                    if let Statement::Variable(Variable { value: expr, .. }) | Statement::Expression(expr) = stm {
+                        visit(expr, names);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Statement::Variable(Variable { value: expr, .. }) | Statement::Expression(expr) = stm {
+        visit(expr, names);
+    }
+}
@@ -1,3 +1,7 @@
+use num_bigint::BigInt;
+
+use crate::source::SourceSpan;
+
 #[derive(Debug, Clone)]
 pub struct Program {
     pub statements: Vec<Statement>
@@ -8,38 +12,182 @@ pub enum Statement {
     Variable(Variable),
     Expression(Expression),
     TypeDef(TypeDef),
+    Import(Import),
+    /// Placeholder for a statement that failed to parse
+    Error(SourceSpan),
 }
 
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub name: String,
     pub value: Expression,
+    pub span: SourceSpan,
 }
 
 #[derive(Debug, Clone)]
 pub struct TypeDef {
     pub name: String,
     pub variants: Vec<TypeDefVariant>,
+    pub span: SourceSpan,
+}
+
+/// `import "path" as name` or `import { a, b } from "path"`
+#[derive(Debug, Clone)]
+pub struct Import {
+    /// Path of the file as written, relative to the importing file or to a directory of the search path
+    pub path: String,
+    pub names: ImportNames,
+    pub span: SourceSpan,
+}
+
+#[derive(Debug, Clone)]
+pub enum ImportNames {
+    /// The exports are used as `name.export`
+    Alias(String),
+    /// Exports used without qualifying them, with the span of each name
+    Names(Vec<(String, SourceSpan)>),
 }
 
 #[derive(Debug, Clone)]
 pub struct TypeDefVariant {
     pub name: String,
     pub properties: Vec<String>,
+    pub span: SourceSpan,
 }
 
 #[derive(Debug, Clone)]
 pub enum Expression {
-    Int { value: i32 },
-    Float { value: f32 },
-    String { value: String },
-    FunCall { name: String, args: Vec<Expression> },
-    Operator { operator: Operator, left: Box<Expression>, right: Box<Expression> },
-    UnaryOperator { operator: UnaryOperator, expr: Box<Expression> },
-    List { items: Vec<Expression> },
-    Tuple { values: Vec<Expression> },
-    Lambda { args: Vec<String>, code: Vec<Statement> },
-    Return { value: Box<Expression> }
+    Int { value: i64, span: SourceSpan },
+    /// Int literal too big for an `i64`
+    BigInt { value: BigInt, span: SourceSpan },
+    Float { value: f64, span: SourceSpan },
+    String { value: String, span: SourceSpan },
+    FunCall { name: String, args: Vec<Expression>, span: SourceSpan },
+    /// `@name`, the value of a variable without calling it, or a function that calls the builtin or
+    /// constructor `name`
+    Reference { name: String, span: SourceSpan },
+    Operator { operator: Operator, left: Box<Expression>, right: Box<Expression>, span: SourceSpan },
+    UnaryOperator { operator: UnaryOperator, expr: Box<Expression>, span: SourceSpan },
+    List { items: Vec<Expression>, span: SourceSpan },
+    Tuple { values: Vec<Expression>, span: SourceSpan },
+    Lambda { args: Vec<String>, code: Vec<Statement>, span: SourceSpan },
+    Return { value: Box<Expression>, span: SourceSpan },
+    Match { value: Box<Expression>, arms: Vec<MatchArm>, span: SourceSpan },
+    /// `value.name`, reads a property of an instance, or calls `name value` if it's not an instance
+    /// with that property
+    Property { value: Box<Expression>, name: String, span: SourceSpan },
+    /// `value with { name = new_value }`, copy of an instance with some properties changed
+    Update { value: Box<Expression>, properties: Vec<PropertyUpdate>, span: SourceSpan },
+    /// `"text ${value} text"`, the parts are `String` expressions for the text and the interpolated
+    /// expressions, in order
+    Interpolated { parts: Vec<Expression>, span: SourceSpan },
+}
+
+#[derive(Debug, Clone)]
+pub struct PropertyUpdate {
+    pub name: String,
+    pub value: Expression,
+    pub span: SourceSpan,
+}
+
+/// `pattern if guard -> body`
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Expression>,
+    pub body: Expression,
+    pub span: SourceSpan,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`, matches anything
+    Wildcard { span: SourceSpan },
+    /// Matches anything and assigns it to a variable
+    Binding { name: String, span: SourceSpan },
+    Int { value: i64, span: SourceSpan },
+    BigInt { value: BigInt, span: SourceSpan },
+    Float { value: f64, span: SourceSpan },
+    String { value: String, span: SourceSpan },
+    Tuple { items: Vec<Pattern>, span: SourceSpan },
+    /// `[first, second, ...rest]`, without `rest` the list must have exactly the length of `items`
+    List { items: Vec<Pattern>, rest: Option<Box<Pattern>>, span: SourceSpan },
+    /// Instance of a type variant, matching its properties in order
+    Variant { name: String, args: Vec<Pattern>, span: SourceSpan },
+}
+
+impl Statement {
+    pub fn span(&self) -> SourceSpan {
+        match self {
+            Statement::Variable(var) => var.span,
+            Statement::Expression(expr) => expr.span(),
+            Statement::TypeDef(def) => def.span,
+            Statement::Import(import) => import.span,
+            Statement::Error(span) => *span,
+        }
+    }
+}
+
+impl Expression {
+    pub fn span(&self) -> SourceSpan {
+        match self {
+            Expression::Int { span, .. } |
+            Expression::BigInt { span, .. } |
+            Expression::Float { span, .. } |
+            Expression::String { span, .. } |
+            Expression::FunCall { span, .. } |
+            Expression::Reference { span, .. } |
+            Expression::Operator { span, .. } |
+            Expression::UnaryOperator { span, .. } |
+            Expression::List { span, .. } |
+            Expression::Tuple { span, .. } |
+            Expression::Lambda { span, .. } |
+            Expression::Return { span, .. } |
+            Expression::Match { span, .. } |
+            Expression::Property { span, .. } |
+            Expression::Update { span, .. } |
+            Expression::Interpolated { span, .. } => *span,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn span(&self) -> SourceSpan {
+        match self {
+            Pattern::Wildcard { span } |
+            Pattern::Binding { span, .. } |
+            Pattern::Int { span, .. } |
+            Pattern::BigInt { span, .. } |
+            Pattern::Float { span, .. } |
+            Pattern::String { span, .. } |
+            Pattern::Tuple { span, .. } |
+            Pattern::List { span, .. } |
+            Pattern::Variant { span, .. } => *span,
+        }
+    }
+
+    /// Names of the variables assigned when the pattern matches, in order
+    pub fn bindings(&self) -> Vec<&str> {
+        let mut names = vec![];
+        self.collect_bindings(&mut names);
+        names
+    }
+
+    fn collect_bindings<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Pattern::Binding { name, .. } => names.push(name),
+            Pattern::Tuple { items, .. } | Pattern::Variant { args: items, .. } => {
+                items.iter().for_each(|it| it.collect_bindings(names));
+            }
+            Pattern::List { items, rest, .. } => {
+                items.iter().for_each(|it| it.collect_bindings(names));
+                if let Some(rest) = rest {
+                    rest.collect_bindings(names);
+                }
+            }
+            Pattern::Wildcard { .. } | Pattern::Int { .. } | Pattern::BigInt { .. } | Pattern::Float { .. } | Pattern::String { .. } => {}
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -1,46 +1,226 @@
+use std::io::{stdout, Write};
+
+use num_bigint::{BigInt, Sign};
+
 use crate::ast::{TypeDef, TypeDefVariant};
-use crate::run::Value;
+use crate::operators::{int_result, register_operators};
+use crate::run::{Step, Value};
 use crate::runtime::{Runtime, RuntimeError};
 
 pub fn register_builtins(runtime: &mut Runtime) {
-    runtime.register_func("print", 1, |_, args| {
+    register_operators(runtime);
+
+    runtime.register_func("print", 1, |run, args| {
+        let param = args.into_iter().next().unwrap();
+
+        print!("{}", run.display_value(&param));
+        stdout().flush().ok();
+
+        Ok(Value::Unit)
+    });
+
+    runtime.register_func("println", 1, |run, args| {
         let param = args.into_iter().next().unwrap();
 
-        println!("{:?}", param);
+        println!("{}", run.display_value(&param));
 
-        Ok(param)
+        Ok(Value::Unit)
     });
 
-    runtime.register_func("unary_minus", 1, |_, args| {
+    runtime.register_func("debug", 1, |run, args| {
+        let param = args.into_iter().next().unwrap();
+
+        println!("{}", run.debug_value(&param));
+
+        Ok(Value::Unit)
+    });
+
+    runtime.register_func("unary_minus", 1, |run, args| {
         let param = args.into_iter().next().unwrap();
         match &param {
-            Value::Int(value) => Ok(Value::Int(-(*value))),
+            Value::Int(value) => {
+                match value.checked_neg() {
+                    Some(value) => Ok(Value::Int(value)),
+                    None => int_result(run, "-", -BigInt::from(*value)),
+                }
+            }
+            Value::BigInt(value) => int_result(run, "-", -value),
             Value::Float(value) => Ok(Value::Float(-(*value))),
-            _ => Err(RuntimeError::Custom(format!("Unable to negate non numeric value: {:?}", param)))
+            _ => Err(RuntimeError::InvalidOperand { operator: "-".to_string(), operand: run.type_name(&param) })
         }
     });
 
-    runtime.register_func("unary_plus", 1, |_, args| {
+    runtime.register_func("unary_plus", 1, |run, args| {
         let param = args.into_iter().next().unwrap();
         match &param {
-            Value::Int(value) => Ok(Value::Int(*value)),
-            Value::Float(value) => Ok(Value::Float(*value)),
-            _ => Err(RuntimeError::Custom(format!("Unable to use unary plus on non numeric value: {:?}", param)))
+            Value::Int(_) | Value::BigInt(_) | Value::Float(_) => Ok(param.clone()),
+            _ => Err(RuntimeError::InvalidOperand { operator: "+".to_string(), operand: run.type_name(&param) })
         }
     });
 
-    // runtime.register_func("unary_not", 1, |run, args| {
-    //     let param = args.into_iter().next().unwrap();
-    //     let inst: Instance = param.try_into()?;
-    //
-    // });
+    runtime.register_func("unary_not", 1, |run, args| {
+        let param = args.into_iter().next().unwrap();
+
+        if let Some(value) = run.as_boolean(&param) {
+            return Ok(run.boolean(!value));
+        }
+
+        match &param {
+            Value::Int(value) => Ok(Value::Int(!value)),
+            Value::BigInt(value) => int_result(run, "!", !value),
+            _ => Err(RuntimeError::InvalidOperand { operator: "!".to_string(), operand: run.type_name(&param) })
+        }
+    });
+
+
+    runtime.register_control("if", 2, |run, args| {
+        let mut args = args.into_iter();
+        let (condition, then) = (args.next().unwrap(), args.next().unwrap());
+
+        if expect_boolean(run, &condition)? {
+            Ok(Step::Call(then, vec![]))
+        } else {
+            Ok(Step::Return(Value::Unit))
+        }
+    });
+
+    runtime.register_control("while", 2, |_, args| {
+        let mut args = args.into_iter();
+        let (condition, body) = (args.next().unwrap(), args.next().unwrap());
+
+        Ok(while_loop(condition, body))
+    });
+
+    runtime.register_control("repeat", 2, |run, args| {
+        let mut args = args.into_iter();
+        let (times, body) = (args.next().unwrap(), args.next().unwrap());
 
+        let times = match times {
+            Value::Int(value) => value,
+            // Like negative `Int`s, runs the body no times
+            Value::BigInt(value) if value.sign() == Sign::Minus => 0,
+            Value::BigInt(_) => return Err(RuntimeError::Custom("repeat count too large".to_string())),
+            _ => return Err(type_mismatch(run, "Int", &times)),
+        };
+
+        Ok(call_each(body, (0..times).map(|i| vec![Value::Int(i)])))
+    });
+
+    runtime.register_control("foreach", 2, |run, args| {
+        let mut args = args.into_iter();
+        let (list, body) = (args.next().unwrap(), args.next().unwrap());
+
+        let values = expect_list(run, list)?.into_iter().enumerate();
+        Ok(call_each(body, values.map(|(index, value)| vec![Value::Int(index as i64), value])))
+    });
+
+    runtime.register_control("each", 2, |run, args| {
+        let mut args = args.into_iter();
+        let (list, body) = (args.next().unwrap(), args.next().unwrap());
+
+        Ok(call_each(body, expect_list(run, list)?.into_iter().map(|value| vec![value])))
+    });
 
     runtime.register_type(TypeDef {
         name: "Boolean".to_string(),
         variants: vec![
-            TypeDefVariant { name: "True".to_string(), properties: vec![] },
-            TypeDefVariant { name: "False".to_string(), properties: vec![] },
+            TypeDefVariant { name: "True".to_string(), properties: vec![], span: Default::default() },
+            TypeDefVariant { name: "False".to_string(), properties: vec![], span: Default::default() },
         ],
+        span: Default::default(),
     });
-}
\ No newline at end of file
+}
+
+/// Calls the condition, then the body and the loop again while the condition is true
+fn while_loop(condition: Value, body: Value) -> Step {
+    Step::Then(condition.clone(), vec![], Box::new(move |run, value| {
+        if !expect_boolean(run, &value)? {
+            return Ok(Step::Return(Value::Unit));
+        }
+
+        Ok(Step::Then(body.clone(), vec![], Box::new(move |_, _| Ok(while_loop(condition, body)))))
+    }))
+}
+
+/// Calls `body` with each list of arguments in turn
+fn call_each(body: Value, mut args: impl Iterator<Item=Vec<Value>> + 'static) -> Step {
+    match args.next() {
+        Some(next) => Step::Then(body.clone(), next, Box::new(move |_, _| Ok(call_each(body, args)))),
+        None => Step::Return(Value::Unit),
+    }
+}
+
+fn expect_boolean(run: &Runtime, value: &Value) -> Result<bool, RuntimeError> {
+    run.as_boolean(value).ok_or_else(|| type_mismatch(run, "Boolean", value))
+}
+
+fn expect_list(run: &Runtime, value: Value) -> Result<Vec<Value>, RuntimeError> {
+    match value {
+        Value::List(values) => Ok(values),
+        _ => Err(type_mismatch(run, "List", &value)),
+    }
+}
+
+fn type_mismatch(run: &Runtime, expected: &str, found: &Value) -> RuntimeError {
+    RuntimeError::TypeMismatch { expected: expected.to_string(), found: run.type_name(found) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::run;
+
+    use super::*;
+
+    #[test]
+    fn if_runs_lambda_when_true() {
+        assert!(matches!(run("if 1 < 2, { 5 }"), Ok(Value::Int(5))));
+        assert!(matches!(run("if 1 > 2, { 5 }"), Ok(Value::Unit)));
+
+        let error = run("if 1, { 5 }").unwrap_err();
+        assert_eq!("expected `Boolean` but found `Int`", error.to_string());
+    }
+
+    #[test]
+    fn while_loop() {
+        let value = run("x = 0\nwhile { x < 5 }, {\n  x = x + 1\n}\nx").unwrap();
+        assert!(matches!(value, Value::Int(5)));
+    }
+
+    #[test]
+    fn repeat_passes_index() {
+        let value = run("total = 0\nrepeat 4, { i | total = total + i }\ntotal").unwrap();
+        assert!(matches!(value, Value::Int(6)));
+    }
+
+    #[test]
+    fn repeat_big_counts() {
+        let error = run("repeat 100000000000000000000, { i | i }").unwrap_err();
+        assert_eq!("repeat count too large", error.to_string());
+
+        let value = run("total = 0\nrepeat -100000000000000000000, { i | total = total + 1 }\ntotal").unwrap();
+        assert!(matches!(value, Value::Int(0)));
+    }
+
+    #[test]
+    fn iterate_lists() {
+        let value = run("total = 0\nforeach [10, 20], { index, value | total = total + index * value }\ntotal").unwrap();
+        assert!(matches!(value, Value::Int(20)));
+
+        let value = run("total = \"\"\neach [\"a\", \"b\"], { value | total = total + value }\ntotal").unwrap();
+        assert!(matches!(value, Value::String(text) if text == "ab"));
+
+        let error = run("each 3, { value | value }").unwrap_err();
+        assert_eq!("expected `List` but found `Int`", error.to_string());
+    }
+
+    #[test]
+    fn callback_errors() {
+        let error = run("each [1], { a, b | a }").unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::ArityMismatch { expected: 2, got: 1, .. }));
+
+        // The trace goes through the lambda called by the builtin
+        let error = run("each [1], { value |\n  value + \"a\"\n}").unwrap_err();
+        let lines: Vec<u32> = error.trace().iter().map(|it| it.span.start.line).collect();
+        assert_eq!(vec![2, 1], lines);
+    }
+}
@@ -0,0 +1,930 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+
+use crate::ast::{Pattern, TypeDef, TypeDefVariant};
+use crate::run::{CompiledFunction, CompiledProgram, Inst, InstanceClass, Var};
+use crate::source::{FileId, SourceMap, SourceSpan, Span};
+
+/// First bytes of every `.dlc` file
+pub const MAGIC: &[u8; 4] = b"\0DLC";
+
+/// Version of the format, files of other versions are rejected
+pub const VERSION: u16 = 1;
+
+/// A `.dlc` file that could not be read
+#[derive(Debug)]
+pub enum BytecodeError {
+    Io(io::Error),
+    /// The file doesn't start with `MAGIC`
+    NotBytecode,
+    UnsupportedVersion(u16),
+    /// Truncated file or inconsistent contents
+    Malformed(String),
+}
+
+/// Layout of a `.dlc` file, integers are LEB128 unless stated otherwise:
+///
+/// - `MAGIC` and `VERSION` as 2 little endian bytes
+/// - string table: count, then the length and UTF-8 bytes of each string
+/// - constant pool: count, then a tag and the value of each Int, BigInt, Float and String
+/// - files the spans refer to: count and the string index of each name
+/// - types of the instance classes: count and each `TypeDef`
+/// - globals: count and the string index of each name
+/// - root function, nested functions are written inside the function that creates them
+///
+/// Functions have their name, span, arity, local and upvalue names, captures, code, line table, nested
+/// function table and instance classes. Names are indices into the string table, literals into the
+/// constant pool.
+impl CompiledProgram {
+    /// Writes the program in the `.dlc` format, with the names of the files in `sources` so errors can
+    /// point to them when it runs
+    pub fn write_to(&self, out: &mut dyn Write, sources: &SourceMap) -> io::Result<()> {
+        let mut encoder = Encoder::default();
+
+        let files: Vec<usize> = sources.files().iter().map(|it| encoder.string(&it.name)).collect();
+        let globals: Vec<usize> = self.globals.iter().map(|it| encoder.string(it)).collect();
+        encoder.function(&self.root_function);
+
+        let mut header = Buffer::default();
+        header.bytes.extend_from_slice(MAGIC);
+        header.bytes.extend_from_slice(&VERSION.to_le_bytes());
+
+        header.uint(encoder.strings.len());
+        for string in &encoder.strings {
+            header.uint(string.len());
+            header.bytes.extend_from_slice(string.as_bytes());
+        }
+
+        header.uint(encoder.constants.len());
+        for constant in &encoder.constants {
+            header.bytes.extend_from_slice(constant);
+        }
+
+        header.indices(&files);
+        header.uint(encoder.typedefs.len());
+        header.bytes.append(&mut encoder.typedef_bytes.bytes);
+        header.indices(&globals);
+
+        out.write_all(&header.bytes)?;
+        out.write_all(&encoder.body.bytes)
+    }
+
+    /// Reads a program written by `write_to`, its files are added to `sources` without their code
+    pub fn read_from(input: &mut dyn Read, sources: &mut SourceMap) -> Result<CompiledProgram, BytecodeError> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes).map_err(BytecodeError::Io)?;
+
+        if !bytes.starts_with(MAGIC) {
+            return Err(BytecodeError::NotBytecode);
+        }
+
+        let mut decoder = Decoder { bytes: &bytes, pos: MAGIC.len(), ..Decoder::default() };
+
+        let version = u16::from_le_bytes([decoder.byte()?, decoder.byte()?]);
+        if version != VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+
+        for _ in 0..decoder.uint()? {
+            let len = decoder.uint()?;
+            let string = String::from_utf8(decoder.take(len)?.to_vec())
+                .map_err(|_| malformed("invalid UTF-8 in the string table"))?;
+            decoder.strings.push(string);
+        }
+
+        for _ in 0..decoder.uint()? {
+            let constant = decoder.constant()?;
+            decoder.constants.push(constant);
+        }
+
+        for _ in 0..decoder.uint()? {
+            let name = decoder.string()?;
+            decoder.files.push(sources.add(name, ""));
+        }
+
+        for _ in 0..decoder.uint()? {
+            let typedef = decoder.typedef()?;
+            decoder.typedefs.push(Rc::new(typedef));
+        }
+
+        let globals = decoder.list(|it| it.string())?;
+        decoder.globals = globals.len();
+        let root_function = decoder.function()?;
+
+        if !root_function.captures.is_empty() {
+            return Err(malformed("the root function captures variables"));
+        }
+
+        if decoder.pos != bytes.len() {
+            return Err(malformed("unexpected data after the root function"));
+        }
+
+        Ok(CompiledProgram { root_function, globals })
+    }
+}
+
+/// Starts a `.dlc` file, tells it apart from source code
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Constant pool tags
+const INT: u8 = 0;
+const BIG_INT: u8 = 1;
+const FLOAT: u8 = 2;
+const STRING: u8 = 3;
+
+// Instruction opcodes, `Int`, `BigInt`, `Float` and `String` are all `CONSTANT`
+const LOAD_LOCAL: u8 = 0;
+const STORE_LOCAL: u8 = 1;
+const LOAD_UPVALUE: u8 = 2;
+const STORE_UPVALUE: u8 = 3;
+const LOAD_GLOBAL: u8 = 4;
+const STORE_GLOBAL: u8 = 5;
+const CALL: u8 = 6;
+const TAIL_CALL: u8 = 7;
+const CALL_VALUE: u8 = 8;
+const CONSTANT: u8 = 9;
+const CALL_NAMED: u8 = 10;
+const LOAD_NAMED: u8 = 11;
+const LIST: u8 = 12;
+const TUPLE: u8 = 13;
+const FUNCTION: u8 = 14;
+const RETURN: u8 = 15;
+const JUMP: u8 = 16;
+const JUMP_IF_FALSE: u8 = 17;
+const DESTRUCTURE: u8 = 18;
+const POP: u8 = 19;
+const NO_MATCH: u8 = 20;
+const PROPERTY: u8 = 21;
+const CONCAT: u8 = 22;
+const UPDATE: u8 = 23;
+
+// Pattern tags
+const WILDCARD: u8 = 0;
+const BINDING: u8 = 1;
+const LITERAL: u8 = 2;
+const TUPLE_PATTERN: u8 = 3;
+const LIST_PATTERN: u8 = 4;
+const VARIANT: u8 = 5;
+
+#[derive(Default)]
+struct Buffer {
+    bytes: Vec<u8>,
+}
+
+impl Buffer {
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    /// Unsigned LEB128, 7 bits per byte with the high bit set on all but the last one
+    fn uint(&mut self, value: usize) {
+        let mut value = value as u64;
+
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn indices(&mut self, indices: &[usize]) {
+        self.uint(indices.len());
+        indices.iter().for_each(|it| self.uint(*it));
+    }
+
+    fn span(&mut self, span: SourceSpan) {
+        self.uint(span.file);
+        for pos in &[span.start, span.end] {
+            self.uint(pos.line as usize);
+            self.uint(pos.column as usize);
+        }
+    }
+}
+
+/// Collects the strings, constants and types of a program while writing its functions
+#[derive(Default)]
+struct Encoder {
+    strings: Vec<String>,
+    string_indices: HashMap<String, usize>,
+    /// Encoded constants, with their tag
+    constants: Vec<Vec<u8>>,
+    constant_indices: HashMap<Vec<u8>, usize>,
+    /// Types by address, instance classes of the same type share it
+    typedefs: HashMap<*const TypeDef, usize>,
+    typedef_bytes: Buffer,
+    body: Buffer,
+}
+
+impl Encoder {
+    fn string(&mut self, string: &str) -> usize {
+        if let Some(index) = self.string_indices.get(string) {
+            return *index;
+        }
+
+        self.strings.push(string.to_string());
+        self.string_indices.insert(string.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    fn constant(&mut self, tag: u8, value: &[u8]) -> usize {
+        let mut constant = vec![tag];
+        constant.extend_from_slice(value);
+
+        if let Some(index) = self.constant_indices.get(&constant) {
+            return *index;
+        }
+
+        self.constants.push(constant.clone());
+        self.constant_indices.insert(constant, self.constants.len() - 1);
+        self.constants.len() - 1
+    }
+
+    fn int(&mut self, value: i64) -> usize {
+        self.constant(INT, &value.to_le_bytes())
+    }
+
+    fn big_int(&mut self, value: &BigInt) -> usize {
+        let mut bytes = Buffer::default();
+        let value = value.to_signed_bytes_le();
+        bytes.uint(value.len());
+        bytes.bytes.extend_from_slice(&value);
+
+        self.constant(BIG_INT, &bytes.bytes)
+    }
+
+    fn float(&mut self, value: f64) -> usize {
+        self.constant(FLOAT, &value.to_bits().to_le_bytes())
+    }
+
+    fn string_constant(&mut self, value: &str) -> usize {
+        let mut bytes = Buffer::default();
+        bytes.uint(self.string(value));
+
+        self.constant(STRING, &bytes.bytes)
+    }
+
+    fn write_string(&mut self, string: &str) {
+        let index = self.string(string);
+        self.body.uint(index);
+    }
+
+    fn write_strings(&mut self, strings: &[String]) {
+        self.body.uint(strings.len());
+        strings.iter().for_each(|it| self.write_string(it));
+    }
+
+    fn typedef(&mut self, typedef: &Rc<TypeDef>) -> usize {
+        if let Some(index) = self.typedefs.get(&Rc::as_ptr(typedef)) {
+            return *index;
+        }
+
+        let name = self.string(&typedef.name);
+        let mut bytes = std::mem::take(&mut self.typedef_bytes);
+        bytes.uint(name);
+        bytes.span(typedef.span);
+        bytes.uint(typedef.variants.len());
+
+        for variant in &typedef.variants {
+            bytes.uint(self.string(&variant.name));
+            bytes.uint(variant.properties.len());
+            variant.properties.iter().for_each(|it| bytes.uint(self.string(it)));
+            bytes.span(variant.span);
+        }
+
+        self.typedef_bytes = bytes;
+        self.typedefs.insert(Rc::as_ptr(typedef), self.typedefs.len());
+        self.typedefs.len() - 1
+    }
+
+    fn function(&mut self, func: &CompiledFunction) {
+        self.write_string(&func.name);
+        self.body.span(func.span);
+        self.body.uint(func.args);
+        self.write_strings(&func.locals);
+        self.write_strings(&func.upvalues);
+
+        self.body.uint(func.captures.len());
+        func.captures.iter().for_each(|it| self.var(Some(*it)));
+
+        self.body.uint(func.code.len());
+        func.code.iter().for_each(|it| self.inst(it));
+
+        let entries = func.lines.entries();
+        self.body.uint(entries.len());
+        for (index, span) in entries {
+            self.body.uint(*index);
+            self.body.span(*span);
+        }
+
+        // Sorted so the same program always gives the same file
+        let mut functions: Vec<_> = func.functions.iter().collect();
+        functions.sort_by_key(|(id, _)| **id);
+
+        self.body.uint(functions.len());
+        for (id, nested) in functions {
+            self.body.uint(*id);
+            self.function(nested);
+        }
+
+        let mut classes: Vec<_> = func.instance_classes.iter().collect();
+        classes.sort_by_key(|(name, _)| name.as_str());
+
+        self.body.uint(classes.len());
+        for (name, class) in classes {
+            self.write_string(name);
+            self.body.uint(class.id);
+            let typedef = self.typedef(&class.typedef);
+            self.body.uint(typedef);
+            self.write_string(&class.variant);
+            self.write_strings(&class.properties);
+        }
+    }
+
+    fn var(&mut self, var: Option<Var>) {
+        let (tag, slot) = match var {
+            None => (0, 0),
+            Some(Var::Local(slot)) => (1, slot),
+            Some(Var::Upvalue(slot)) => (2, slot),
+            Some(Var::Global(slot)) => (3, slot),
+        };
+
+        self.body.byte(tag);
+        self.body.uint(slot);
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        let operand = match inst {
+            Inst::LoadLocal(slot) => Some((LOAD_LOCAL, *slot)),
+            Inst::StoreLocal(slot) => Some((STORE_LOCAL, *slot)),
+            Inst::LoadUpvalue(slot) => Some((LOAD_UPVALUE, *slot)),
+            Inst::StoreUpvalue(slot) => Some((STORE_UPVALUE, *slot)),
+            Inst::LoadGlobal(slot) => Some((LOAD_GLOBAL, *slot)),
+            Inst::StoreGlobal(slot) => Some((STORE_GLOBAL, *slot)),
+            Inst::Call(argc) => Some((CALL, *argc)),
+            Inst::TailCall(argc) => Some((TAIL_CALL, *argc)),
+            Inst::Int(value) => Some((CONSTANT, self.int(*value))),
+            Inst::BigInt(value) => Some((CONSTANT, self.big_int(value))),
+            Inst::Float(value) => Some((CONSTANT, self.float(*value))),
+            Inst::String(value) => Some((CONSTANT, self.string_constant(value))),
+            Inst::LoadNamed(name) => Some((LOAD_NAMED, self.string(name))),
+            Inst::List(count) => Some((LIST, *count)),
+            Inst::Tuple(count) => Some((TUPLE, *count)),
+            Inst::Function(id) => Some((FUNCTION, *id)),
+            Inst::Jump(target) => Some((JUMP, *target)),
+            Inst::JumpIfFalse(target) => Some((JUMP_IF_FALSE, *target)),
+            Inst::Concat(count) => Some((CONCAT, *count)),
+            _ => None,
+        };
+
+        if let Some((opcode, operand)) = operand {
+            self.body.byte(opcode);
+            self.body.uint(operand);
+            return;
+        }
+
+        match inst {
+            Inst::CallValue => self.body.byte(CALL_VALUE),
+            Inst::Return => self.body.byte(RETURN),
+            Inst::Pop => self.body.byte(POP),
+            Inst::NoMatch => self.body.byte(NO_MATCH),
+            Inst::CallNamed { name, argc } => {
+                self.body.byte(CALL_NAMED);
+                self.write_string(name);
+                self.body.uint(*argc);
+            }
+            Inst::Destructure { pattern, otherwise } => {
+                self.body.byte(DESTRUCTURE);
+                self.body.uint(*otherwise);
+                self.pattern(pattern);
+            }
+            Inst::Property { name, method } => {
+                self.body.byte(PROPERTY);
+                self.write_string(name);
+                self.var(*method);
+            }
+            Inst::Update(names) => {
+                self.body.byte(UPDATE);
+                self.write_strings(names);
+            }
+            other => unreachable!("{:?} has a single operand", other),
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Wildcard { span } => {
+                self.body.byte(WILDCARD);
+                self.body.span(*span);
+            }
+            Pattern::Binding { name, span } => {
+                self.body.byte(BINDING);
+                self.body.span(*span);
+                self.write_string(name);
+            }
+            Pattern::Int { span, .. } | Pattern::BigInt { span, .. } | Pattern::Float { span, .. } | Pattern::String { span, .. } => {
+                let constant = match pattern {
+                    Pattern::Int { value, .. } => self.int(*value),
+                    Pattern::BigInt { value, .. } => self.big_int(value),
+                    Pattern::Float { value, .. } => self.float(*value),
+                    Pattern::String { value, .. } => self.string_constant(value),
+                    _ => unreachable!(),
+                };
+                self.body.byte(LITERAL);
+                self.body.span(*span);
+                self.body.uint(constant);
+            }
+            Pattern::Tuple { items, span } => {
+                self.body.byte(TUPLE_PATTERN);
+                self.body.span(*span);
+                self.body.uint(items.len());
+                items.iter().for_each(|it| self.pattern(it));
+            }
+            Pattern::List { items, rest, span } => {
+                self.body.byte(LIST_PATTERN);
+                self.body.span(*span);
+                self.body.uint(items.len());
+                items.iter().for_each(|it| self.pattern(it));
+
+                match rest {
+                    Some(rest) => {
+                        self.body.byte(1);
+                        self.pattern(rest);
+                    }
+                    None => self.body.byte(0),
+                }
+            }
+            Pattern::Variant { name, args, span } => {
+                self.body.byte(VARIANT);
+                self.body.span(*span);
+                self.write_string(name);
+                self.body.uint(args.len());
+                args.iter().for_each(|it| self.pattern(it));
+            }
+        }
+    }
+}
+
+enum Constant {
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+    String(String),
+}
+
+#[derive(Default)]
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    strings: Vec<String>,
+    constants: Vec<Constant>,
+    /// Ids the files of the program got in the `SourceMap`, by their index in the file
+    files: Vec<FileId>,
+    typedefs: Vec<Rc<TypeDef>>,
+    /// Number of globals of the program
+    globals: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(malformed("unexpected end of file"));
+        }
+
+        self.pos += len;
+        Ok(&self.bytes[self.pos - len..self.pos])
+    }
+
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<usize, BytecodeError> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.byte()?;
+            if shift >= 64 {
+                return Err(malformed("integer too big"));
+            }
+
+            value |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        Ok(value as usize)
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        let value = self.uint()?;
+        if value > u32::MAX as usize {
+            return Err(malformed("integer too big"));
+        }
+
+        Ok(value as u32)
+    }
+
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T, BytecodeError>) -> Result<Vec<T>, BytecodeError> {
+        let count = self.uint()?;
+        // Every item takes at least a byte, this avoids huge allocations for corrupt counts
+        let mut items = Vec::with_capacity(count.min(self.bytes.len() - self.pos));
+
+        for _ in 0..count {
+            items.push(item(self)?);
+        }
+
+        Ok(items)
+    }
+
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let index = self.uint()?;
+        self.strings.get(index).cloned().ok_or_else(|| malformed("string index out of range"))
+    }
+
+    fn span(&mut self) -> Result<SourceSpan, BytecodeError> {
+        let file = self.uint()?;
+        let file = *self.files.get(file).ok_or_else(|| malformed("file index out of range"))?;
+        let start = Span { line: self.u32()?, column: self.u32()? };
+        let end = Span { line: self.u32()?, column: self.u32()? };
+
+        Ok(SourceSpan::new(file, start, end))
+    }
+
+    fn constant(&mut self) -> Result<Constant, BytecodeError> {
+        match self.byte()? {
+            INT => {
+                let bytes = self.take(8)?;
+                Ok(Constant::Int(i64::from_le_bytes(bytes.try_into().unwrap())))
+            }
+            BIG_INT => {
+                let len = self.uint()?;
+                Ok(Constant::BigInt(BigInt::from_signed_bytes_le(self.take(len)?)))
+            }
+            FLOAT => {
+                let bytes = self.take(8)?;
+                Ok(Constant::Float(f64::from_bits(u64::from_le_bytes(bytes.try_into().unwrap()))))
+            }
+            STRING => Ok(Constant::String(self.string()?)),
+            tag => Err(malformed(format!("unknown constant tag {}", tag))),
+        }
+    }
+
+    fn constant_at(&mut self) -> Result<&Constant, BytecodeError> {
+        let index = self.uint()?;
+        self.constants.get(index).ok_or_else(|| malformed("constant index out of range"))
+    }
+
+    fn typedef(&mut self) -> Result<TypeDef, BytecodeError> {
+        let name = self.string()?;
+        let span = self.span()?;
+        let variants = self.list(|it| {
+            let name = it.string()?;
+            let properties = it.list(|it| it.string())?;
+            Ok(TypeDefVariant { name, properties, span: it.span()? })
+        })?;
+
+        Ok(TypeDef { name, variants, span })
+    }
+
+    fn function(&mut self) -> Result<CompiledFunction, BytecodeError> {
+        let name = self.string()?;
+        let span = self.span()?;
+        let mut func = CompiledFunction::new(&name, span, self.uint()?);
+
+        func.locals = self.list(|it| it.string())?;
+        func.upvalues = self.list(|it| it.string())?;
+        func.captures = self.list(|it| it.var()?.ok_or_else(|| malformed("missing captured variable")))?;
+        func.code = self.list(|it| it.inst())?;
+
+        for _ in 0..self.uint()? {
+            let index = self.uint()?;
+            let previous = func.lines.entries().last().map(|(start, _)| *start);
+
+            if index >= func.code.len() || previous.is_some_and(|it| index <= it) {
+                return Err(malformed(format!("line entry for instruction {} out of order or range", index)));
+            }
+            func.lines.add(index, self.span()?);
+        }
+
+        for _ in 0..self.uint()? {
+            let id = self.uint()?;
+            func.functions.insert(id, Rc::new(self.function()?));
+        }
+
+        for _ in 0..self.uint()? {
+            let name = self.string()?;
+            let id = self.uint()?;
+            let typedef = self.uint()?;
+            let typedef = self.typedefs.get(typedef).cloned().ok_or_else(|| malformed("type index out of range"))?;
+            let variant = self.string()?;
+            let properties = self.list(|it| it.string())?;
+
+            func.instance_classes.insert(name, InstanceClass { id, typedef, variant, properties });
+        }
+
+        self.check_operands(&func)?;
+        Ok(func)
+    }
+
+    /// Checks that the operands of the code of `func`, and the captures of the functions it creates,
+    /// point inside it. The runtime indexes with them without checking.
+    fn check_operands(&self, func: &CompiledFunction) -> Result<(), BytecodeError> {
+        if func.args > func.locals.len() {
+            return Err(malformed(format!("function `{}` has fewer locals than arguments", func.name)));
+        }
+
+        let var = |var: Var| match var {
+            Var::Local(slot) if slot >= func.locals.len() => Err(malformed(format!("local slot {} out of range", slot))),
+            Var::Upvalue(index) if index >= func.upvalues.len() => Err(malformed(format!("upvalue {} out of range", index))),
+            Var::Global(slot) if slot >= self.globals => Err(malformed(format!("global slot {} out of range", slot))),
+            _ => Ok(()),
+        };
+
+        for inst in &func.code {
+            match inst {
+                Inst::LoadLocal(slot) | Inst::StoreLocal(slot) => var(Var::Local(*slot))?,
+                Inst::LoadUpvalue(index) | Inst::StoreUpvalue(index) => var(Var::Upvalue(*index))?,
+                Inst::LoadGlobal(slot) | Inst::StoreGlobal(slot) => var(Var::Global(*slot))?,
+                Inst::Property { method: Some(method), .. } => var(*method)?,
+                Inst::Function(id) if !func.functions.contains_key(id) => {
+                    return Err(malformed(format!("unknown function {}", id)));
+                }
+                Inst::Jump(target) | Inst::JumpIfFalse(target) | Inst::Destructure { otherwise: target, .. } if *target > func.code.len() => {
+                    return Err(malformed(format!("jump target {} out of range", target)));
+                }
+                _ => {}
+            }
+        }
+
+        for nested in func.functions.values() {
+            for capture in &nested.captures {
+                var(*capture)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn var(&mut self) -> Result<Option<Var>, BytecodeError> {
+        let tag = self.byte()?;
+        let slot = self.uint()?;
+
+        match tag {
+            0 => Ok(None),
+            1 => Ok(Some(Var::Local(slot))),
+            2 => Ok(Some(Var::Upvalue(slot))),
+            3 => Ok(Some(Var::Global(slot))),
+            _ => Err(malformed(format!("unknown variable tag {}", tag))),
+        }
+    }
+
+    fn inst(&mut self) -> Result<Inst, BytecodeError> {
+        let opcode = self.byte()?;
+
+        let inst = match opcode {
+            CALL_VALUE => Inst::CallValue,
+            RETURN => Inst::Return,
+            POP => Inst::Pop,
+            NO_MATCH => Inst::NoMatch,
+            CONSTANT => match self.constant_at()? {
+                Constant::Int(value) => Inst::Int(*value),
+                Constant::BigInt(value) => Inst::BigInt(value.clone()),
+                Constant::Float(value) => Inst::Float(*value),
+                Constant::String(value) => Inst::String(value.clone()),
+            },
+            CALL_NAMED => Inst::CallNamed { name: self.string()?, argc: self.uint()? },
+            LOAD_NAMED => Inst::LoadNamed(self.string()?),
+            PROPERTY => Inst::Property { name: self.string()?, method: self.var()? },
+            UPDATE => Inst::Update(self.list(|it| it.string())?),
+            DESTRUCTURE => {
+                let otherwise = self.uint()?;
+                Inst::Destructure { pattern: self.pattern()?, otherwise }
+            }
+            _ => {
+                let operand = self.uint()?;
+
+                match opcode {
+                    LOAD_LOCAL => Inst::LoadLocal(operand),
+                    STORE_LOCAL => Inst::StoreLocal(operand),
+                    LOAD_UPVALUE => Inst::LoadUpvalue(operand),
+                    STORE_UPVALUE => Inst::StoreUpvalue(operand),
+                    LOAD_GLOBAL => Inst::LoadGlobal(operand),
+                    STORE_GLOBAL => Inst::StoreGlobal(operand),
+                    CALL => Inst::Call(operand),
+                    TAIL_CALL => Inst::TailCall(operand),
+                    LIST => Inst::List(operand),
+                    TUPLE => Inst::Tuple(operand),
+                    FUNCTION => Inst::Function(operand),
+                    JUMP => Inst::Jump(operand),
+                    JUMP_IF_FALSE => Inst::JumpIfFalse(operand),
+                    CONCAT => Inst::Concat(operand),
+                    _ => return Err(malformed(format!("unknown opcode {}", opcode))),
+                }
+            }
+        };
+
+        Ok(inst)
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, BytecodeError> {
+        let tag = self.byte()?;
+        let span = self.span()?;
+
+        let pattern = match tag {
+            WILDCARD => Pattern::Wildcard { span },
+            BINDING => Pattern::Binding { name: self.string()?, span },
+            LITERAL => match self.constant_at()? {
+                Constant::Int(value) => Pattern::Int { value: *value, span },
+                Constant::BigInt(value) => Pattern::BigInt { value: value.clone(), span },
+                Constant::Float(value) => Pattern::Float { value: *value, span },
+                Constant::String(value) => Pattern::String { value: value.clone(), span },
+            },
+            TUPLE_PATTERN => Pattern::Tuple { items: self.list(|it| it.pattern())?, span },
+            LIST_PATTERN => {
+                let items = self.list(|it| it.pattern())?;
+                let rest = match self.byte()? {
+                    0 => None,
+                    _ => Some(Box::new(self.pattern()?)),
+                };
+                Pattern::List { items, rest, span }
+            }
+            VARIANT => {
+                let name = self.string()?;
+                Pattern::Variant { name, args: self.list(|it| it.pattern())?, span }
+            }
+            _ => return Err(malformed(format!("unknown pattern tag {}", tag))),
+        };
+
+        Ok(pattern)
+    }
+}
+
+fn malformed(message: impl Into<String>) -> BytecodeError {
+    BytecodeError::Malformed(message.into())
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::Io(error) => write!(f, "{}", error),
+            BytecodeError::NotBytecode => write!(f, "not a compiled program"),
+            BytecodeError::UnsupportedVersion(version) => {
+                write!(f, "compiled program of version {}, this version reads version {}", version, VERSION)
+            }
+            BytecodeError::Malformed(message) => write!(f, "corrupt compiled program: {}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run::Value;
+    use crate::runtime::{Runtime, RuntimeError};
+    use crate::testing::Pipeline;
+
+    use super::*;
+
+    fn compile(code: &str) -> (CompiledProgram, SourceMap) {
+        let mut pipeline = Pipeline::new();
+        let program = pipeline.compile(code).expect("CompileError");
+        (program, pipeline.sources)
+    }
+
+    fn round_trip(code: &str) -> (Vec<u8>, CompiledProgram, SourceMap) {
+        let (program, sources) = compile(code);
+        let mut bytes = vec![];
+        program.write_to(&mut bytes, &sources).unwrap();
+
+        let mut sources = SourceMap::new();
+        let program = CompiledProgram::read_from(&mut bytes.as_slice(), &mut sources).unwrap();
+        (bytes, program, sources)
+    }
+
+    fn run(program: CompiledProgram) -> Result<Value, RuntimeError> {
+        Pipeline::new().runtime.run(program)
+    }
+
+    #[test]
+    fn programs_run_the_same() {
+        let code = "\
+type List = Cons(value, next) | Null
+big = 123456789012345678901234567890
+sum = { list | match list {
+    Cons(value, next) -> value + sum next
+    Null -> 0
+} }
+describe = { value | match value {
+    [first, ...rest] -> \"list\"
+    (1.5, \"a\") -> \"tuple\"
+    -5 -> \"negative\"
+    _ -> \"other\"
+} }
+user = Cons 1, Null
+[(sum (Cons 1, (Cons 2, Null))), big * 2, (describe [1]), (describe (1.5, \"a\")), (describe -5), user with { value = 3 }, \"${big}\", Null < (Cons 1, Null)]";
+
+        let (program, _) = compile(code);
+        let expected = Runtime::new().display_value(&run(program).unwrap());
+        let (_, program, _) = round_trip(code);
+        let found = Runtime::new().display_value(&run(program).unwrap());
+
+        assert_eq!(expected, found);
+        assert!(found.contains("246913578024691357802469135780"), "{}", found);
+    }
+
+    #[test]
+    fn spans_and_files() {
+        let (_, program, sources) = round_trip("f = { x |\n  x / 0\n}\nf 1");
+        assert_eq!("main.dl", sources.file(0).name);
+
+        let error = run(program).unwrap_err();
+        let frames: Vec<(&str, u32, u32)> = error.trace().iter()
+            .map(|it| (it.function.as_str(), it.span.start.line, it.span.start.column))
+            .collect();
+        assert_eq!(vec![("f", 2, 3), ("<main>", 4, 1)], frames);
+    }
+
+    #[test]
+    fn shared_tables() {
+        let (bytes, program, _) = round_trip("type A = X(name) | Y(name)\n[\"name\", \"name\", 1, 1, (X 1), (Y 2)]");
+
+        // Strings and constants are written once
+        let text = String::from_utf8_lossy(&bytes);
+        assert_eq!(1, text.matches("name").count(), "{:?}", text);
+
+        // Variants of a type keep sharing it
+        let classes = &program.root_function.instance_classes;
+        assert!(Rc::ptr_eq(&classes["X"].typedef, &classes["Y"].typedef));
+
+        // The same program always gives the same bytes
+        let (again, _, _) = round_trip("type A = X(name) | Y(name)\n[\"name\", \"name\", 1, 1, (X 1), (Y 2)]");
+        assert_eq!(bytes, again);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let read = |bytes: &[u8]| CompiledProgram::read_from(&mut &bytes[..], &mut SourceMap::new()).unwrap_err();
+
+        assert!(matches!(read(b"println 1"), BytecodeError::NotBytecode));
+        assert!(matches!(read(b"\0DLC\x02\0"), BytecodeError::UnsupportedVersion(2)));
+
+        let (program, sources) = compile("f = { x | x + 1 }\nf 2");
+        let mut bytes = vec![];
+        program.write_to(&mut bytes, &sources).unwrap();
+
+        for len in 0..bytes.len() {
+            assert!(matches!(read(&bytes[..len]), BytecodeError::NotBytecode | BytecodeError::Malformed(_)), "{}", len);
+        }
+
+        bytes.push(0);
+        assert_eq!("corrupt compiled program: unexpected data after the root function", read(&bytes).to_string());
+    }
+
+    #[test]
+    fn invalid_operands() {
+        // Writes the program of `code` after `corrupt` changed its root function and reads it back
+        let read = |code: &str, corrupt: &dyn Fn(&mut CompiledFunction)| {
+            let (mut program, sources) = compile(code);
+            corrupt(&mut program.root_function);
+
+            let mut bytes = vec![];
+            program.write_to(&mut bytes, &sources).unwrap();
+            CompiledProgram::read_from(&mut bytes.as_slice(), &mut SourceMap::new()).unwrap_err().to_string()
+        };
+        let push = |inst: Inst| move |func: &mut CompiledFunction| func.push(inst.clone(), func.span);
+
+        assert_eq!("corrupt compiled program: local slot 0 out of range", read("1", &push(Inst::LoadLocal(0))));
+        assert_eq!("corrupt compiled program: local slot 3 out of range", read("1", &push(Inst::StoreLocal(3))));
+        assert_eq!("corrupt compiled program: upvalue 0 out of range", read("1", &push(Inst::LoadUpvalue(0))));
+        assert_eq!("corrupt compiled program: global slot 1 out of range", read("x = 1", &push(Inst::StoreGlobal(1))));
+        assert_eq!("corrupt compiled program: unknown function 7", read("f = { x | x }", &push(Inst::Function(7))));
+        assert_eq!("corrupt compiled program: jump target 9 out of range", read("1", &push(Inst::Jump(9))));
+
+        let destructure = Inst::Destructure { pattern: Pattern::Wildcard { span: Default::default() }, otherwise: 9 };
+        assert_eq!("corrupt compiled program: jump target 9 out of range", read("1", &push(destructure)));
+
+        let method = Inst::Property { name: "name".to_string(), method: Some(Var::Global(4)) };
+        assert_eq!("corrupt compiled program: global slot 4 out of range", read("x = 1", &push(method)));
+
+        // Captures point to the variables of the function that creates the closure
+        let error = read("f = { x | { x } }", &|func| {
+            let f = func.functions.values_mut().next().unwrap();
+            let lambda = Rc::make_mut(f).functions.values_mut().next().unwrap();
+            Rc::make_mut(lambda).captures = vec![Var::Local(5)];
+        });
+        assert_eq!("corrupt compiled program: local slot 5 out of range", error);
+
+        let error = read("1", &|func| func.lines.add(func.code.len() + 2, SourceSpan::default()));
+        assert_eq!("corrupt compiled program: line entry for instruction 3 out of order or range", error);
+    }
+}
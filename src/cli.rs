@@ -0,0 +1,379 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::ast::Program;
+use crate::builtins::register_builtins;
+use crate::bytecode::is_bytecode;
+use crate::compiler::Compiler;
+use crate::diagnostics::{render_all_in, Diagnostic};
+use crate::modules::{ModuleError, ModuleLoader};
+use crate::repl::Repl;
+use crate::run::CompiledProgram;
+use crate::runtime::Runtime;
+use crate::source::{FileId, SourceError, SourceMap};
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_RUNTIME_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_IO_ERROR: i32 = 3;
+pub const EXIT_PARSE_ERROR: i32 = 4;
+pub const EXIT_COMPILE_ERROR: i32 = 5;
+
+/// Environment variable with the directories where imported modules are searched
+const SEARCH_PATH_VAR: &str = "DEMO_LANG_PATH";
+
+const USAGE: &str = "\
+Usage: demo_lang [<command> <file>]
+
+Commands:
+    repl                    Start an interactive session, the default without arguments
+    run <file>              Run a script
+    check <file>            Parse and compile a script without running it
+    dump-ast <file>         Print the syntax tree of a script
+    dump-bytecode <file>    Print the compiled bytecode of a script
+    compile <file>          Compile a script and its imports to a `.dlc` file next to it
+    help                    Print this message
+
+Use `-` as <file> to read the script from stdin, `compile -` writes to stdout. Imported modules are
+searched next to the importing file, then in the directories listed in `DEMO_LANG_PATH`. `run`, `check`
+and `dump-bytecode` also take `.dlc` files, which run without parsing nor compiling.";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    Run,
+    Check,
+    DumpAst,
+    DumpBytecode,
+    Compile,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Invocation {
+    Help,
+    Repl,
+    Script(Command, String),
+}
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Io(String),
+    /// Already rendered diagnostics
+    Parse(String),
+    /// Already rendered diagnostics
+    Compile(String),
+    /// Already rendered diagnostic
+    Runtime(String),
+}
+
+/// Entry point of the command line driver, returns the process exit code
+pub fn main(args: Vec<String>) -> i32 {
+    let (command, path) = match parse_args(&args) {
+        Ok(Invocation::Script(command, path)) => (command, path),
+        Ok(Invocation::Repl) => {
+            Repl::new().run();
+            return EXIT_OK;
+        }
+        Ok(Invocation::Help) => {
+            println!("{}", USAGE);
+            return EXIT_OK;
+        }
+        Err(error) => return report(error),
+    };
+
+    match execute(command, &path) {
+        Ok(()) => EXIT_OK,
+        Err(error) => report(error),
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Invocation, CliError> {
+    let mut iter = args.iter();
+
+    let command = match iter.next().map(|it| it.as_str()) {
+        Some("run") => Command::Run,
+        Some("check") => Command::Check,
+        Some("dump-ast") => Command::DumpAst,
+        Some("dump-bytecode") => Command::DumpBytecode,
+        Some("compile") => Command::Compile,
+        Some("repl") | None => {
+            if let Some(extra) = iter.next() {
+                return Err(CliError::Usage(format!("unexpected argument `{}`", extra)));
+            }
+            return Ok(Invocation::Repl);
+        }
+        Some("help") | Some("-h") | Some("--help") => return Ok(Invocation::Help),
+        Some(other) => return Err(CliError::Usage(format!("unknown command `{}`", other))),
+    };
+
+    let path = match iter.next() {
+        Some(path) => path.clone(),
+        None => return Err(CliError::Usage("missing input file".to_string())),
+    };
+
+    if let Some(extra) = iter.next() {
+        return Err(CliError::Usage(format!("unexpected argument `{}`", extra)));
+    }
+
+    Ok(Invocation::Script(command, path))
+}
+
+fn execute(command: Command, path: &str) -> Result<(), CliError> {
+    // Checked first, so a script named `*.dlc` is not overwritten by its own bytecode
+    if command == Command::Compile && path != "-" && compiled_path(path) == Path::new(path) {
+        return Err(CliError::Usage(format!("`{}` would be replaced by its bytecode, rename it to compile it", path)));
+    }
+
+    if path == "-" {
+        execute_from(command, path, &mut io::stdin().lock())
+    } else {
+        let mut file = File::open(path).map_err(|error| CliError::Io(SourceError { name: path.to_string(), error }.to_string()))?;
+        execute_from(command, path, &mut file)
+    }
+}
+
+/// Runs a command on a script or a `.dlc` file read once from `input`, which may be a pipe that can't
+/// be read again
+fn execute_from(command: Command, path: &str, input: &mut dyn Read) -> Result<(), CliError> {
+    let name = if path == "-" { "<stdin>" } else { path };
+
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)
+        .map_err(|error| CliError::Io(SourceError { name: name.to_string(), error }.to_string()))?;
+
+    if is_bytecode(&bytes) {
+        return execute_compiled(command, name, &bytes);
+    }
+
+    let mut loader = ModuleLoader::new();
+    if let Some(dirs) = env::var_os(SEARCH_PATH_VAR) {
+        env::split_paths(&dirs).for_each(|dir| loader.add_search_path(dir));
+    }
+
+    let file = loader.sources.add(name, String::from_utf8_lossy(&bytes));
+    let program = link(&mut loader, file)?;
+    let sources = &loader.sources;
+
+    if command == Command::DumpAst {
+        println!("{:#?}", program);
+        return Ok(());
+    }
+
+    let mut runtime = Runtime::new();
+    register_builtins(&mut runtime);
+
+    let compiled_program = compile(&runtime, program, sources)?;
+
+    match command {
+        Command::DumpBytecode => {
+            println!("{:#?}", compiled_program);
+        }
+        Command::Run => {
+            runtime.run(compiled_program).map_err(|error| {
+                CliError::Runtime(Diagnostic::from(&error).render_in(sources))
+            })?;
+        }
+        Command::Compile => {
+            write_compiled(&compiled_program, path, sources)?;
+        }
+        Command::Check | Command::DumpAst => {}
+    }
+
+    Ok(())
+}
+
+/// Writes the program to `<file>.dlc`, or to stdout when the script comes from stdin
+fn write_compiled(program: &CompiledProgram, path: &str, sources: &SourceMap) -> Result<(), CliError> {
+    if path == "-" {
+        return program.write_to(&mut io::stdout().lock(), sources).map_err(|error| CliError::Io(error.to_string()));
+    }
+
+    let output = compiled_path(path);
+    let result = File::create(&output).and_then(|mut file| program.write_to(&mut file, sources));
+    result.map_err(|error| CliError::Io(format!("unable to write `{}`: {}", output.display(), error)))
+}
+
+fn compiled_path(path: &str) -> PathBuf {
+    Path::new(path).with_extension("dlc")
+}
+
+/// Runs a command on a program written by `compile`
+fn execute_compiled(command: Command, path: &str, bytes: &[u8]) -> Result<(), CliError> {
+    let mut sources = SourceMap::new();
+    let compiled_program = CompiledProgram::read_from(&mut &bytes[..], &mut sources)
+        .map_err(|error| CliError::Io(format!("unable to read `{}`: {}", path, error)))?;
+
+    match command {
+        Command::DumpBytecode => {
+            println!("{:#?}", compiled_program);
+        }
+        Command::Run => {
+            let mut runtime = Runtime::new();
+            register_builtins(&mut runtime);
+
+            runtime.run(compiled_program).map_err(|error| {
+                CliError::Runtime(Diagnostic::from(&error).render_in(&sources))
+            })?;
+        }
+        Command::DumpAst | Command::Compile => {
+            return Err(CliError::Usage(format!("`{}` is already compiled", path)));
+        }
+        Command::Check => {}
+    }
+
+    Ok(())
+}
+
+/// Parses the script and the modules it imports, the code of the modules goes first
+fn link(loader: &mut ModuleLoader, file: FileId) -> Result<Program, CliError> {
+    let linked = loader.load_main(file).map_err(|errors| {
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+        let rendered = render_all_in(&diagnostics, &loader.sources);
+
+        if errors.iter().any(|it| matches!(it, ModuleError::Parse { .. })) {
+            CliError::Parse(rendered)
+        } else {
+            CliError::Compile(rendered)
+        }
+    })?;
+
+    let mut statements = linked.modules;
+    statements.extend(linked.program.statements);
+    Ok(Program { statements })
+}
+
+fn compile(runtime: &Runtime, program: Program, sources: &SourceMap) -> Result<CompiledProgram, CliError> {
+    let mut compiler = Compiler::new();
+    compiler.declare_builtins(runtime);
+
+    let compiled = compiler.compile(program).map_err(|errors| {
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+        CliError::Compile(render_all_in(&diagnostics, sources))
+    })?;
+
+    let warnings: Vec<Diagnostic> = compiler.take_warnings().iter().map(Diagnostic::from).collect();
+    eprint!("{}", render_all_in(&warnings, sources));
+
+    Ok(compiled)
+}
+
+fn report(error: CliError) -> i32 {
+    match error {
+        CliError::Usage(msg) => {
+            eprintln!("error: {}\n\n{}", msg, USAGE);
+            EXIT_USAGE
+        }
+        CliError::Io(msg) => {
+            eprintln!("error: {}", msg);
+            EXIT_IO_ERROR
+        }
+        CliError::Parse(msg) => {
+            eprint!("{}", msg);
+            EXIT_PARSE_ERROR
+        }
+        CliError::Compile(msg) => {
+            eprint!("{}", msg);
+            EXIT_COMPILE_ERROR
+        }
+        CliError::Runtime(msg) => {
+            eprint!("{}", msg);
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::Pipeline;
+
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|it| it.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_commands() {
+        let invocation = parse_args(&args(&["run", "main.dl"])).unwrap();
+        assert_eq!(Invocation::Script(Command::Run, "main.dl".to_string()), invocation);
+
+        let invocation = parse_args(&args(&["dump-bytecode", "-"])).unwrap();
+        assert_eq!(Invocation::Script(Command::DumpBytecode, "-".to_string()), invocation);
+
+        let invocation = parse_args(&args(&["compile", "main.dl"])).unwrap();
+        assert_eq!(Invocation::Script(Command::Compile, "main.dl".to_string()), invocation);
+
+        assert_eq!(Invocation::Help, parse_args(&args(&["help"])).unwrap());
+        assert_eq!(Invocation::Repl, parse_args(&args(&[])).unwrap());
+        assert_eq!(Invocation::Repl, parse_args(&args(&["repl"])).unwrap());
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert!(matches!(parse_args(&args(&["repl", "a.dl"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["run"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["jump", "a.dl"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["run", "a.dl", "b.dl"])), Err(CliError::Usage(_))));
+    }
+
+    #[test]
+    fn missing_file() {
+        assert_eq!(EXIT_IO_ERROR, main(args(&["check", "does_not_exist.dl"])));
+    }
+
+    #[test]
+    fn compiled_scripts() {
+        let path = std::env::temp_dir().join(format!("demo_lang_cli_compiled_{}.dl", std::process::id()));
+        let compiled = path.with_extension("dlc");
+        let _files = crate::source::TempFiles(vec![path.clone(), compiled.clone()]);
+        std::fs::write(&path, "x = 1\nx / 0").unwrap();
+        let path = path.to_string_lossy().to_string();
+        let compiled_path = compiled.to_string_lossy().to_string();
+
+        assert_eq!(EXIT_OK, main(args(&["compile", &path])));
+        assert!(is_bytecode(&std::fs::read(&compiled_path).unwrap()));
+        assert!(!is_bytecode(&std::fs::read(&path).unwrap()));
+
+        assert_eq!(EXIT_OK, main(args(&["check", &compiled_path])));
+        assert_eq!(EXIT_RUNTIME_ERROR, main(args(&["run", &compiled_path])));
+        assert_eq!(EXIT_USAGE, main(args(&["dump-ast", &compiled_path])));
+    }
+
+    #[test]
+    fn scripts_named_like_bytecode_are_not_overwritten() {
+        let path = std::env::temp_dir().join(format!("demo_lang_cli_source_{}.dlc", std::process::id()));
+        let _files = crate::source::TempFiles(vec![path.clone()]);
+        std::fs::write(&path, "x = 1").unwrap();
+
+        assert_eq!(EXIT_USAGE, main(args(&["compile", &path.to_string_lossy()])));
+        assert_eq!("x = 1", std::fs::read_to_string(&path).unwrap());
+    }
+
+    /// Hands out a few bytes per read and can't rewind, like a pipe
+    struct Pipe<'a>(&'a [u8]);
+
+    impl Read for Pipe<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn scripts_from_pipes() {
+        assert!(execute_from(Command::Check, "-", &mut Pipe(b"println \"hello\"\n")).is_ok());
+        assert!(matches!(execute_from(Command::Check, "-", &mut Pipe(b"println tln")), Err(CliError::Compile(_))));
+
+        let mut pipeline = Pipeline::new();
+        let compiled = pipeline.compile("x = 1\nx").unwrap();
+
+        let mut bytes = vec![];
+        compiled.write_to(&mut bytes, &pipeline.sources).unwrap();
+        assert!(execute_from(Command::Check, "-", &mut Pipe(&bytes)).is_ok());
+        assert!(matches!(execute_from(Command::DumpAst, "-", &mut Pipe(&bytes)), Err(CliError::Usage(_))));
+    }
+}
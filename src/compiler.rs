@@ -1,41 +1,170 @@
-use crate::ast::{Expression, Operator, Program, Statement, UnaryOperator};
-use crate::run::{CompiledFunction, CompiledProgram, Inst, InstanceClass};
+use crate::analysis::{Analyzer, Binding, Globals, VariantInfo};
+use crate::ast::{Expression, MatchArm, Operator, Program, Statement, UnaryOperator};
+use crate::run::{CompiledFunction, CompiledProgram, Inst, InstanceClass, Var};
+use crate::runtime::Runtime;
+use crate::source::SourceSpan;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
-pub enum CompileError {}
+pub enum CompileError {
+    UndefinedName { name: String, span: SourceSpan },
+    UsedBeforeAssignment { name: String, span: SourceSpan },
+    /// A variant with the same name was already defined by the type `typedef`
+    DuplicateVariant { name: String, typedef: String, span: SourceSpan, previous: SourceSpan },
+    ConstructorArity { name: String, expected: usize, found: usize, span: SourceSpan },
+    LambdaArity { name: String, expected: usize, found: usize, span: SourceSpan },
+    ReturnOutsideLambda { span: SourceSpan },
+    /// No type has a property named `name`, and there is no function to call with that name
+    UnknownProperty { name: String, span: SourceSpan },
+    /// A `with` sets the property `name` more than once, `previous` is the first one
+    DuplicateProperty { name: String, span: SourceSpan, previous: SourceSpan },
+    /// Imports are replaced by the code of the modules before compiling, see `ModuleLoader`
+    UnresolvedImport { path: String, span: SourceSpan },
+}
+
+/// Mistake that doesn't stop the program from compiling
+#[derive(Debug, Clone)]
+pub enum CompileWarning {
+    /// Some values match no arm, `missing` lists patterns that would match them
+    NonExhaustiveMatch { missing: Vec<String>, span: SourceSpan },
+    /// The previous arms match every value this arm could match
+    UnreachableArm { span: SourceSpan },
+}
 
+#[derive(Clone)]
 pub struct Compiler {
-    next_id: usize
+    next_id: usize,
+    globals: Globals,
+    /// Slot of every global variable of the programs compiled so far
+    global_slots: HashMap<String, usize>,
+    global_names: Vec<String>,
+    /// Functions being compiled, the first one is the root of the program
+    scopes: Vec<FunctionScope>,
+    /// Warnings of the programs compiled since the last call to `take_warnings`
+    warnings: Vec<CompileWarning>,
+    /// Variables created by each lambda of the program being compiled, decided by the `Analyzer`
+    lambda_locals: HashMap<SourceSpan, HashSet<String>>,
+}
+
+/// Names of a function being compiled, used to find the variables its lambdas must capture
+#[derive(Default, Clone)]
+struct FunctionScope {
+    /// Arguments and every variable assigned in the function body
+    locals: HashSet<String>,
+    /// Arguments and variables assigned so far
+    assigned: HashSet<String>,
+    /// Slot of each local variable, given the first time the variable is used
+    slots: HashMap<String, usize>,
+    /// Names of the local variables by slot
+    names: Vec<String>,
+    /// Variables of enclosing lambdas used by this function or its nested lambdas
+    upvalues: Vec<String>,
+    /// Where each upvalue is found in the enclosing function
+    captures: Vec<Var>,
+    /// Slots of the pattern variables of the `match` arms being compiled, innermost arm last
+    arms: Vec<HashMap<String, usize>>,
 }
 
 impl Compiler {
     pub fn new() -> Self {
-        Compiler { next_id: 0 }
+        Compiler {
+            next_id: 0,
+            globals: Default::default(),
+            global_slots: Default::default(),
+            global_names: vec![],
+            scopes: vec![],
+            warnings: vec![],
+            lambda_locals: Default::default(),
+        }
+    }
+
+    /// Makes the builtin functions and types of the runtime known to the compiler
+    pub fn declare_builtins(&mut self, runtime: &Runtime) {
+        for (name, args) in runtime.builtin_functions() {
+            self.globals.names.insert(name.to_string(), Binding::Lambda(args));
+            self.globals.builtins.insert(name.to_string());
+        }
+
+        for class in runtime.builtin_classes() {
+            let signature = Rc::new(
+                class.typedef.variants.iter().map(|it| (it.name.to_string(), it.properties.len())).collect()
+            );
+
+            self.globals.variants.insert(class.variant.to_string(), VariantInfo {
+                typedef: class.typedef.name.to_string(),
+                properties: class.properties.clone(),
+                span: class.typedef.span,
+                signature,
+            });
+        }
+    }
+
+    /// Compiles a program, warnings are kept until `take_warnings` is called
+    pub fn compile(&mut self, program: Program) -> Result<CompiledProgram, Vec<CompileError>> {
+        let analysis = Analyzer::new(&self.globals).analyze(&program)?;
+        self.globals = analysis.globals;
+        self.warnings.extend(analysis.warnings);
+        self.lambda_locals = analysis.lambda_locals;
+        self.generate(program).map_err(|e| vec![e])
+    }
+
+    /// Checks if `name` is a global variable of the programs compiled so far, builtins and types are not
+    pub fn is_global(&self, name: &str) -> bool {
+        self.global_slots.contains_key(name)
     }
 
-    pub fn compile(&mut self, program: Program) -> Result<CompiledProgram, CompileError> {
-        let mut root = CompiledFunction {
-            args: 0,
-            code: vec![],
-            functions: Default::default(),
-            instance_classes: Default::default(),
+    pub fn take_warnings(&mut self) -> Vec<CompileWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn generate(&mut self, program: Program) -> Result<CompiledProgram, CompileError> {
+        let span = match (program.statements.first(), program.statements.last()) {
+            (Some(first), Some(last)) => first.span().merge(last.span()),
+            _ => SourceSpan::default(),
         };
+        let mut root = CompiledFunction::new("<main>", span, 0);
 
-        for stm in program.statements {
-            self.compile_statement(&mut root, stm)?;
+        // Variables of the root function are globals, lambdas find them without capturing them
+        let mut scope = FunctionScope::default();
+        for stm in &program.statements {
+            if let Statement::Variable(var) = stm {
+                scope.locals.insert(var.name.to_string());
+                self.global(&var.name);
+            }
         }
+        self.scopes.push(scope);
+
+        let result = program.statements.into_iter()
+            .try_for_each(|stm| self.compile_statement(&mut root, stm));
+
+        // Only pattern variables are locals of the root function
+        root.locals = self.scopes.pop().unwrap().names;
+        result?;
 
         Ok(CompiledProgram {
             root_function: root,
+            globals: self.global_names.clone(),
         })
     }
 
     fn compile_statement(&mut self, node: &mut CompiledFunction, stm: Statement) -> Result<(), CompileError> {
         match stm {
             Statement::Variable(var) => {
-                self.compile_expression(node, var.value)?;
-                node.code.push(Inst::Set(var.name));
+                if let Expression::Lambda { args, code, span } = var.value {
+                    self.compile_lambda(node, &var.name, args, code, span)?;
+                } else {
+                    self.compile_expression(node, var.value)?;
+                }
+                let scope = self.scopes.last_mut().unwrap();
+                let is_local = scope.locals.contains(&var.name);
+                let target = if is_local {
+                    scope.assigned.insert(var.name.to_string());
+                    self.local(&var.name)
+                } else {
+                    self.resolve_outer(&var.name).expect("assigned variables are local or visible")
+                };
+                node.push(store(target), var.span);
             }
             Statement::Expression(e) => {
                 self.compile_expression(node, e)?;
@@ -54,38 +183,49 @@ impl Compiler {
                     node.instance_classes.insert(variant.name.to_string(), class);
                 }
             }
+            Statement::Import(_) | Statement::Error(_) => {
+                // Already reported by the analyzer or the parser, nothing to compile
+            }
         }
 
         Ok(())
     }
 
     fn compile_expression(&mut self, node: &mut CompiledFunction, expr: Expression) -> Result<(), CompileError> {
+        let span = expr.span();
+
         match expr {
-            Expression::UnaryOperator { operator, expr } => {
+            Expression::UnaryOperator { operator, expr, .. } => {
                 self.compile_expression(node, *expr)?;
                 let op = match operator {
                     UnaryOperator::Plus => "unary_plus",
                     UnaryOperator::Minus => "unary_minus",
                     UnaryOperator::Not => "unary_not",
                 };
-                node.code.push(Inst::Call(op.to_string()));
+                node.push(Inst::CallNamed { name: op.to_string(), argc: 1 }, span);
             }
-            Expression::Int { value } => {
-                node.code.push(Inst::Int(value));
+            Expression::Int { value, .. } => {
+                node.push(Inst::Int(value), span);
             }
-            Expression::Float { value } => {
-                node.code.push(Inst::Float(value));
+            Expression::BigInt { value, .. } => {
+                node.push(Inst::BigInt(value), span);
             }
-            Expression::String { value } => {
-                node.code.push(Inst::String(value));
+            Expression::Float { value, .. } => {
+                node.push(Inst::Float(value), span);
             }
-            Expression::FunCall { name, args } => {
-                for expr in args {
-                    self.compile_expression(node, expr)?;
+            Expression::String { value, .. } => {
+                node.push(Inst::String(value), span);
+            }
+            Expression::FunCall { name, args, .. } => {
+                self.compile_call(node, name, args, span, false)?;
+            }
+            Expression::Reference { name, .. } => {
+                match self.resolve(&name) {
+                    Some(var) => node.push(load(var), span),
+                    None => node.push(Inst::LoadNamed(name), span),
                 }
-                node.code.push(Inst::Call(name));
             }
-            Expression::Operator { operator, left, right } => {
+            Expression::Operator { operator, left, right, .. } => {
                 self.compile_expression(node, *left)?;
                 self.compile_expression(node, *right)?;
                 let name = match operator {
@@ -106,51 +246,251 @@ impl Compiler {
                     Operator::Equals => "==",
                     Operator::NotEquals => "!=",
                 };
-                node.code.push(Inst::Call(name.to_string()));
+                node.push(Inst::CallNamed { name: name.to_string(), argc: 2 }, span);
             }
-            Expression::List { items } => {
+            Expression::List { items, .. } => {
                 let len = items.len();
                 for expr in items {
                     self.compile_expression(node, expr)?;
                 }
-                node.code.push(Inst::List(len));
+                node.push(Inst::List(len), span);
             }
-            Expression::Tuple { values } => {
+            Expression::Tuple { values, .. } => {
                 let len = values.len();
                 for expr in values {
                     self.compile_expression(node, expr)?;
                 }
-                node.code.push(Inst::Tuple(len));
-            }
-            Expression::Lambda { args, code } => {
-                let mut lambda = CompiledFunction {
-                    args: args.len(),
-                    code: vec![],
-                    functions: Default::default(),
-                    instance_classes: Default::default(),
+                node.push(Inst::Tuple(len), span);
+            }
+            Expression::Lambda { args, code, .. } => {
+                self.compile_lambda(node, "<lambda>", args, code, span)?;
+            }
+            Expression::Return { value, .. } => {
+                let is_tail_call = match *value {
+                    Expression::FunCall { name, args, span: call_span } => self.compile_call(node, name, args, call_span, true)?,
+                    value => {
+                        self.compile_expression(node, value)?;
+                        false
+                    }
                 };
 
-                for arg in args.into_iter().rev() {
-                    lambda.code.push(Inst::Set(arg));
+                if !is_tail_call {
+                    node.push(Inst::Return, span);
                 }
-
-                for stm in code {
-                    self.compile_statement(&mut lambda, stm)?;
+            }
+            Expression::Match { value, arms, .. } => {
+                self.compile_match(node, *value, arms, span)?;
+            }
+            Expression::Property { value, name, .. } => {
+                self.compile_expression(node, *value)?;
+                // It may be a call to a function of an enclosing lambda
+                let method = self.resolve(&name);
+                node.push(Inst::Property { name, method }, span);
+            }
+            Expression::Update { value, properties, .. } => {
+                self.compile_expression(node, *value)?;
+                let mut names = vec![];
+                for property in properties {
+                    self.compile_expression(node, property.value)?;
+                    names.push(property.name);
+                }
+                node.push(Inst::Update(names), span);
+            }
+            Expression::Interpolated { parts, .. } => {
+                let count = parts.len();
+                for part in parts {
+                    self.compile_expression(node, part)?;
                 }
+                node.push(Inst::Concat(count), span);
+            }
+        }
+
+        Ok(())
+    }
 
-                let id = self.next_id();
-                node.functions.insert(id, lambda);
-                node.code.push(Inst::Function(id));
+    /// Calls a variable, a builtin or a constructor. With `tail` calls to lambdas reuse the frame of the
+    /// running function and return from it, returns true if that's the case.
+    fn compile_call(&mut self, node: &mut CompiledFunction, name: String, args: Vec<Expression>, span: SourceSpan, tail: bool) -> Result<bool, CompileError> {
+        let argc = args.len();
+        for expr in args {
+            self.compile_expression(node, expr)?;
+        }
+
+        let var = match self.resolve(&name) {
+            Some(var) => var,
+            None => {
+                node.push(Inst::CallNamed { name, argc }, span);
+                return Ok(false);
             }
-            Expression::Return { value } => {
-                self.compile_expression(node, *value)?;
-                node.code.push(Inst::Return);
+        };
+
+        node.push(load(var), span);
+        let (inst, is_tail_call) = match argc {
+            // Variables that are not functions are read like this
+            0 => (Inst::CallValue, false),
+            _ if tail => (Inst::TailCall(argc), true),
+            _ => (Inst::Call(argc), false),
+        };
+        node.push(inst, span);
+
+        Ok(is_tail_call)
+    }
+
+    /// Each arm tests the value, left on the stack until an arm applies, and jumps to the next arm if
+    /// the pattern or the guard fail
+    fn compile_match(&mut self, node: &mut CompiledFunction, value: Expression, arms: Vec<MatchArm>, span: SourceSpan) -> Result<(), CompileError> {
+        self.compile_expression(node, value)?;
+        let mut exits = vec![];
+
+        for arm in arms {
+            // Pattern variables are locals that only the arm sees, even in the root function, and
+            // shadow any variable with the same name
+            let bindings: Vec<String> = arm.pattern.bindings().iter().map(|it| it.to_string()).collect();
+            let scope = self.scopes.last_mut().unwrap();
+            let slots: Vec<usize> = bindings.iter().map(|it| scope.new_slot(it)).collect();
+            scope.arms.push(bindings.into_iter().zip(slots.iter().copied()).collect());
+
+            let test = node.code.len();
+            let pattern_span = arm.pattern.span();
+            node.push(Inst::Destructure { pattern: arm.pattern, otherwise: 0 }, pattern_span);
+            for slot in slots.iter().rev() {
+                node.push(Inst::StoreLocal(*slot), pattern_span);
             }
+
+            let guard = match arm.guard {
+                Some(guard) => {
+                    let guard_span = guard.span();
+                    self.compile_expression(node, guard)?;
+                    node.push(Inst::JumpIfFalse(0), guard_span);
+                    Some(node.code.len() - 1)
+                }
+                None => None,
+            };
+
+            node.push(Inst::Pop, arm.span);
+            self.compile_expression(node, arm.body)?;
+            self.scopes.last_mut().unwrap().arms.pop();
+            exits.push(node.code.len());
+            node.push(Inst::Jump(0), arm.span);
+
+            let next = node.code.len();
+            node.patch_jump(test, next);
+            if let Some(guard) = guard {
+                node.patch_jump(guard, next);
+            }
+        }
+
+        node.push(Inst::NoMatch, span);
+
+        let end = node.code.len();
+        for exit in exits {
+            node.patch_jump(exit, end);
+        }
+
+        Ok(())
+    }
+
+    fn compile_lambda(&mut self, node: &mut CompiledFunction, name: &str, args: Vec<String>, code: Vec<Statement>, span: SourceSpan) -> Result<(), CompileError> {
+        let mut lambda = CompiledFunction::new(name, span, args.len());
+        let mut scope = FunctionScope::default();
+
+        for arg in &args {
+            scope.locals.insert(arg.to_string());
+            scope.assigned.insert(arg.to_string());
+            scope.slot(arg);
         }
 
+        // Other assignments change a variable of an enclosing function
+        if let Some(locals) = self.lambda_locals.get(&span) {
+            scope.locals.extend(locals.iter().cloned());
+        }
+
+        self.scopes.push(scope);
+
+        let result = code.into_iter().try_for_each(|stm| self.compile_statement(&mut lambda, stm));
+
+        let scope = self.scopes.pop().unwrap();
+        result?;
+
+        lambda.locals = scope.names;
+        lambda.upvalues = scope.upvalues;
+        lambda.captures = scope.captures;
+
+        let id = self.next_id();
+        node.functions.insert(id, Rc::new(lambda));
+        node.push(Inst::Function(id), span);
         Ok(())
     }
 
+    /// Where the current function reads `name`, `None` for builtins and types
+    fn resolve(&mut self, name: &str) -> Option<Var> {
+        if let Some(slot) = self.scopes.last().unwrap().arm_slot(name) {
+            return Some(Var::Local(slot));
+        }
+
+        if self.scopes.last().unwrap().assigned.contains(name) {
+            return Some(self.local(name));
+        }
+
+        // Local variables not assigned yet are looked up further out
+        self.resolve_outer(name)
+    }
+
+    /// Finds `name` in an enclosing lambda, capturing it, or in the globals
+    fn resolve_outer(&mut self, name: &str) -> Option<Var> {
+        match self.upvalue(self.scopes.len() - 1, name) {
+            Some(index) => Some(Var::Upvalue(index)),
+            None => self.global_slots.get(name).map(|it| Var::Global(*it)),
+        }
+    }
+
+    /// Variable `name` of the current function, the variables of the root function are globals
+    fn local(&mut self, name: &str) -> Var {
+        if self.scopes.len() == 1 {
+            Var::Global(self.global(name))
+        } else {
+            Var::Local(self.scopes.last_mut().unwrap().slot(name))
+        }
+    }
+
+    fn global(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.global_slots.get(name) {
+            return *slot;
+        }
+
+        let slot = self.global_names.len();
+        self.global_slots.insert(name.to_string(), slot);
+        self.global_names.push(name.to_string());
+        slot
+    }
+
+    /// Index of the upvalue of the function at `depth` that captures the variable `name` of an
+    /// enclosing lambda, every function in between captures it too. `None` if no lambda has it.
+    fn upvalue(&mut self, depth: usize, name: &str) -> Option<usize> {
+        // Globals are not captured
+        if depth == 0 {
+            return None;
+        }
+
+        if let Some(index) = self.scopes[depth].upvalues.iter().position(|it| it == name) {
+            return Some(index);
+        }
+
+        let parent = depth - 1;
+        let from = if let Some(slot) = self.scopes[parent].arm_slot(name) {
+            Var::Local(slot)
+        } else if parent > 0 && self.scopes[parent].locals.contains(name) {
+            Var::Local(self.scopes[parent].slot(name))
+        } else {
+            Var::Upvalue(self.upvalue(parent, name)?)
+        };
+
+        let scope = &mut self.scopes[depth];
+        scope.upvalues.push(name.to_string());
+        scope.captures.push(from);
+        Some(scope.upvalues.len() - 1)
+    }
+
     fn next_id(&mut self) -> usize {
         let id = self.next_id;
         self.next_id += 1;
@@ -158,3 +498,250 @@ impl Compiler {
     }
 }
 
+impl FunctionScope {
+    fn slot(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+
+        let slot = self.new_slot(name);
+        self.slots.insert(name.to_string(), slot);
+        slot
+    }
+
+    /// A slot that no other variable uses, even if they have the same name
+    fn new_slot(&mut self, name: &str) -> usize {
+        self.names.push(name.to_string());
+        self.names.len() - 1
+    }
+
+    /// Slot of the pattern variable `name` of the innermost arm that has it
+    fn arm_slot(&self, name: &str) -> Option<usize> {
+        self.arms.iter().rev().find_map(|it| it.get(name).copied())
+    }
+}
+
+fn load(var: Var) -> Inst {
+    match var {
+        Var::Local(slot) => Inst::LoadLocal(slot),
+        Var::Upvalue(index) => Inst::LoadUpvalue(index),
+        Var::Global(slot) => Inst::LoadGlobal(slot),
+    }
+}
+
+fn store(var: Var) -> Inst {
+    match var {
+        Var::Local(slot) => Inst::StoreLocal(slot),
+        Var::Upvalue(index) => Inst::StoreUpvalue(index),
+        Var::Global(slot) => Inst::StoreGlobal(slot),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::Pipeline;
+
+    use super::*;
+
+    fn compile(code: &str) -> Result<CompiledProgram, Vec<CompileError>> {
+        Pipeline::new().compile(code)
+    }
+
+    fn errors(code: &str) -> Vec<CompileError> {
+        compile(code).expect_err("Expected a CompileError")
+    }
+
+    fn warnings(code: &str) -> Vec<CompileWarning> {
+        let mut pipeline = Pipeline::new();
+        pipeline.compile(code).expect("CompileError");
+        pipeline.compiler.take_warnings()
+    }
+
+    fn missing(code: &str) -> Vec<String> {
+        match warnings(code).as_slice() {
+            [CompileWarning::NonExhaustiveMatch { missing, .. }] => missing.clone(),
+            other => panic!("Expected a NonExhaustiveMatch, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn undefined_names() {
+        let errors = errors("a = 1\nb = a\nc = d\nf = { x | y }");
+        assert_eq!(2, errors.len());
+        assert!(matches!(&errors[0], CompileError::UndefinedName { name, span } if name == "d" && span.start.line == 3));
+        assert!(matches!(&errors[1], CompileError::UndefinedName { name, span } if name == "y" && span.start.line == 4));
+
+        let errors = self::errors("b = a\na = 1");
+        assert!(matches!(&errors[0], CompileError::UsedBeforeAssignment { name, .. } if name == "a"));
+    }
+
+    #[test]
+    fn lambdas_see_later_definitions() {
+        assert!(compile("f = { n | g n }\ng = { n | f n }").is_ok());
+        assert!(compile("fib = { n | fib n }").is_ok());
+        assert!(compile("x = 1\nf = { a |\n  y = x\n  x = 2\n}").is_ok());
+    }
+
+    #[test]
+    fn duplicate_variants() {
+        let errors = errors("type A = X | Y\ntype B = Y | Z");
+        assert_eq!(1, errors.len());
+        assert!(matches!(&errors[0], CompileError::DuplicateVariant { name, typedef, span, previous }
+            if name == "Y" && typedef == "A" && span.start.line == 2 && previous.start.line == 1));
+    }
+
+    #[test]
+    fn constructor_arity() {
+        assert!(compile("type Pair = Pair(a, b)\np = Pair 1, 2").is_ok());
+
+        let errors = errors("type Pair = Pair(a, b)\np = Pair 1");
+        assert!(matches!(&errors[0], CompileError::ConstructorArity { expected: 2, found: 1, .. }));
+    }
+
+    #[test]
+    fn lambda_arity() {
+        let errors = errors("f = { a, b | a }\nf 1, 2, 3");
+        assert!(matches!(&errors[0], CompileError::LambdaArity { expected: 2, found: 3, .. }));
+
+        // Reassigned variables may hold any function
+        assert!(compile("f = { a, b | a }\nf = { a | a }\nf 1").is_ok());
+        assert!(compile("f = { a, b | a }\ng = { f = { a | a } }\nf 1").is_ok());
+    }
+
+    #[test]
+    fn builtin_arity() {
+        let errors = errors("print 1, 2");
+        assert!(matches!(&errors[0], CompileError::LambdaArity { name, expected: 1, found: 2, .. } if name == "print"));
+    }
+
+    #[test]
+    fn return_outside_lambda() {
+        let errors = errors("return 1");
+        assert!(matches!(&errors[0], CompileError::ReturnOutsideLambda { .. }));
+        assert!(compile("f = { a | return a }").is_ok());
+    }
+
+    #[test]
+    fn globals_of_previous_programs() {
+        let mut pipeline = Pipeline::new();
+
+        pipeline.compile("type Color = Red | Green\nf = { a | a }").unwrap();
+        assert!(pipeline.compile("x = f Red").is_ok());
+        assert!(pipeline.compile("type Color = Red | Green | Blue").is_ok());
+
+        let errors = pipeline.compile("y = f 1, 2").unwrap_err();
+        assert!(matches!(&errors[0], CompileError::LambdaArity { .. }));
+    }
+
+    #[test]
+    fn upvalues() {
+        let compiled = compile("x = 1\nf = { a |\n  b = 2\n  { c | [a, b, c, x] }\n}").unwrap();
+        let f = compiled.root_function.functions.values().next().unwrap();
+        let inner = f.functions.values().next().unwrap();
+
+        // Globals are not captured
+        assert!(f.upvalues.is_empty());
+        assert_eq!(vec!["a".to_string(), "b".to_string()], inner.upvalues);
+
+        let compiled = compile("f = { a |\n  { b | { c | a } }\n}").unwrap();
+        let f = compiled.root_function.functions.values().next().unwrap();
+        let middle = f.functions.values().next().unwrap();
+        let inner = middle.functions.values().next().unwrap();
+
+        assert_eq!(vec!["a".to_string()], middle.upvalues);
+        assert_eq!(vec!["a".to_string()], inner.upvalues);
+    }
+
+    #[test]
+    fn variable_slots() {
+        let compiled = compile("x = 1\nf = { a, b |\n  c = a\n  { d | [b, c, d, x] }\n}").unwrap();
+        assert_eq!(vec!["x".to_string(), "f".to_string()], compiled.globals);
+
+        let f = compiled.root_function.functions.values().next().unwrap();
+        let inner = f.functions.values().next().unwrap();
+        assert_eq!(vec!["a".to_string(), "b".to_string(), "c".to_string()], f.locals);
+        assert!(matches!(f.code[..2], [Inst::LoadLocal(0), Inst::CallValue]), "{:?}", f.code);
+        assert!(matches!(f.code[2], Inst::StoreLocal(2)), "{:?}", f.code);
+
+        assert_eq!(vec![Var::Local(1), Var::Local(2)], inner.captures);
+        assert!(matches!(inner.code[..8], [
+            Inst::LoadUpvalue(0), Inst::CallValue, Inst::LoadUpvalue(1), Inst::CallValue,
+            Inst::LoadLocal(0), Inst::CallValue, Inst::LoadGlobal(0), Inst::CallValue,
+        ]), "{:?}", inner.code);
+    }
+
+    #[test]
+    fn tail_calls() {
+        let compiled = compile("type Box = Box(value)\nf = { n | return f n }\ng = { n |\n  return Box n\n}\nh = { n | f n }").unwrap();
+        let function = |name: &str| compiled.root_function.functions.values().find(|it| it.name == name).unwrap();
+
+        assert!(matches!(function("f").code[..], [.., Inst::TailCall(1)]), "{:?}", function("f").code);
+        // Constructors and calls that aren't returned use the frame stack as usual
+        assert!(!function("g").code.iter().any(|it| matches!(it, Inst::TailCall(_))), "{:?}", function("g").code);
+        assert!(matches!(function("h").code[..], [.., Inst::Call(1)]), "{:?}", function("h").code);
+    }
+
+    #[test]
+    fn match_bindings_are_scoped() {
+        let errors = errors("match 1 {\n  x -> x\n}\nx");
+        assert_eq!(1, errors.len());
+        assert!(matches!(&errors[0], CompileError::UndefinedName { name, span } if name == "x" && span.start.line == 4));
+
+        let compiled = compile("y = match 1 {\n  x -> x\n}").unwrap();
+        assert_eq!(vec!["y".to_string()], compiled.globals);
+        assert_eq!(vec!["x".to_string()], compiled.root_function.locals);
+
+        // Variables of the function used in an arm before they are assigned
+        let errors = self::errors("f = {\n  match 1 {\n    _ -> z\n  }\n  z = 2\n}");
+        assert!(matches!(&errors[0], CompileError::UsedBeforeAssignment { name, .. } if name == "z"));
+    }
+
+    #[test]
+    fn non_exhaustive_matches() {
+        let option = "type Option = Some(value) | Null\n";
+
+        assert_eq!(vec!["Null"], missing(&format!("{}match Some 1 {{\n  Some(x) -> x\n}}", option)));
+        assert_eq!(vec!["Some(_)"], missing(&format!("{}match Null {{\n  Some(x) if x > 0 -> x\n  Null -> 0\n}}", option)));
+        assert_eq!(vec!["(Some(_), Null)"], missing(&format!(
+            "{}match (Null, Null) {{\n  (Some(a), Some(b)) -> 1\n  (Null, _) -> 2\n}}", option
+        )));
+        assert_eq!(vec!["[_, _, ..._]"], missing("match [] {\n  [] -> 0\n  [x] -> 1\n}"));
+        assert_eq!(vec!["_"], missing("match 1 {\n  0 -> 1\n  1 -> 2\n}"));
+
+        assert!(warnings(&format!("{}match Null {{\n  Some(x) -> x\n  Null -> 0\n}}", option)).is_empty());
+        assert!(warnings("match [] {\n  [] -> 0\n  [x, ...rest] -> 1\n}").is_empty());
+        assert!(warnings("match (1, 2) {\n  (a, b) -> a\n}").is_empty());
+    }
+
+    #[test]
+    fn unreachable_arms() {
+        let warnings = warnings("type Shape = Circle(r) | Point\nmatch Point {\n  _ -> 0\n  Point -> 1\n}");
+        assert_eq!(1, warnings.len());
+        assert!(matches!(&warnings[0], CompileWarning::UnreachableArm { span } if span.start.line == 4));
+
+        let warnings = self::warnings("match 1 {\n  1 -> 0\n  1 -> 1\n  _ -> 2\n}");
+        assert!(matches!(warnings.as_slice(), [CompileWarning::UnreachableArm { span }] if span.start.line == 3));
+
+        assert_eq!(1, self::warnings("match [1] {\n  [x, ...rest] -> 0\n  [x] -> 1\n  [] -> 2\n}").len());
+    }
+
+    #[test]
+    fn unknown_properties() {
+        let errors = errors("type User = User(name, email)\nu = User 1, 2\na = u.phone\nb = u with { phone = 1 }");
+        assert_eq!(2, errors.len());
+        assert!(matches!(&errors[0], CompileError::UnknownProperty { name, span } if name == "phone" && span.start.line == 3));
+        assert!(matches!(&errors[1], CompileError::UnknownProperty { name, span } if name == "phone" && span.start.line == 4));
+
+        assert!(compile("type User = User(name, email)\nu = User 1, 2\nn = u.name\nl = [u].len").is_err());
+        assert!(compile("type User = User(name, email)\nf = { x | x }\nu = User 1, 2\nn = u.name\nm = u.f").is_ok());
+    }
+
+    #[test]
+    fn duplicate_properties() {
+        let errors = errors("type User = User(name, email)\nu = User 1, 2\nv = u with {\n  name = 1\n  name = 2\n}");
+        assert_eq!(1, errors.len());
+        assert!(matches!(&errors[0], CompileError::DuplicateProperty { name, span, previous }
+            if name == "name" && span.start.line == 5 && previous.start.line == 4));
+    }
+}
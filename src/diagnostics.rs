@@ -0,0 +1,716 @@
+use std::fmt::Write;
+
+use crate::compiler::{CompileError, CompileWarning};
+use crate::modules::ModuleError;
+use crate::parser::ParseError;
+use crate::runtime::{RuntimeError, TraceFrame};
+use crate::source::{FileId, SourceMap, SourceSpan};
+use crate::tokenizer::{LexError, Token, TokenSpan};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// Error or warning ready to be shown to the user, optionally pointing at a location in the source code
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    /// File of `span`
+    pub file: FileId,
+    pub span: Option<TokenSpan>,
+    pub label: Option<String>,
+    pub notes: Vec<String>,
+    /// Notes about other code, followed by where it is
+    pub notes_at: Vec<(String, SourceSpan)>,
+    pub help: Option<String>,
+    /// Call stack of a runtime error, innermost call first
+    pub trace: Vec<TraceFrame>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic {
+            level: Level::Error,
+            message: message.into(),
+            file: 0,
+            span: None,
+            label: None,
+            notes: vec![],
+            notes_at: vec![],
+            help: None,
+            trace: vec![],
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Diagnostic { level: Level::Warning, ..Diagnostic::error(message) }
+    }
+
+    pub fn with_span(mut self, span: TokenSpan) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_file(mut self, file: FileId) -> Self {
+        self.file = file;
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_note_at(mut self, note: impl Into<String>, span: SourceSpan) -> Self {
+        self.notes_at.push((note.into(), span));
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Renders the diagnostic rustc-style, `source` must be the full text of the file named `file_name`
+    ///
+    /// ```text
+    /// error: expected `)` but found `b`
+    ///  --> main.dl:1:14
+    ///   |
+    /// 1 | type A = B(a b)
+    ///   |              ^
+    /// ```
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        self.render_with(file_name, source, &|_| file_name.to_string())
+    }
+
+    /// Renders the diagnostic taking the code of its file, and of the files of its stack trace, from `sources`
+    pub fn render_in(&self, sources: &SourceMap) -> String {
+        let file = sources.file(self.file);
+        self.render_with(&file.name, &file.text, &|id| sources.file(id).name.to_string())
+    }
+
+    fn render_with(&self, file_name: &str, source: &str, name_of: &dyn Fn(FileId) -> String) -> String {
+        let mut out = String::new();
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+
+        writeln!(out, "{}: {}", level, self.message).unwrap();
+
+        let mut gutter = 1;
+
+        if let Some((start, end)) = self.span {
+            let line_num = start.line.to_string();
+            gutter = line_num.len() + 1;
+            let pad = " ".repeat(gutter);
+
+            writeln!(out, "{}--> {}:{}:{}", " ".repeat(gutter - 1), file_name, start.line, start.column).unwrap();
+
+            // Lines start at 1, generated code has spans on line 0
+            let line = start.line.checked_sub(1).and_then(|index| source.lines().nth(index as usize));
+
+            if let Some(line) = line {
+                let line_len = line.chars().count() as u32;
+                let first = start.column.max(1);
+                let last = if end.line == start.line { end.column } else { line_len + 1 };
+                let width = last.saturating_sub(first).max(1) as usize;
+
+                // Keep tabs so the caret lines up with the code above it
+                let indent: String = line.chars()
+                    .take(first.saturating_sub(1) as usize)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+
+                writeln!(out, "{}|", pad).unwrap();
+                writeln!(out, "{} | {}", line_num, line).unwrap();
+                write!(out, "{}| {}{}", pad, indent, "^".repeat(width)).unwrap();
+
+                if let Some(label) = &self.label {
+                    write!(out, " {}", label).unwrap();
+                }
+                out.push('\n');
+            }
+        }
+
+        let pad = " ".repeat(gutter);
+
+        for note in &self.notes {
+            writeln!(out, "{}= note: {}", pad, note).unwrap();
+        }
+
+        for (note, span) in &self.notes_at {
+            let at = span.start;
+            writeln!(out, "{}= note: {} at {}:{}:{}", pad, note, name_of(span.file), at.line, at.column).unwrap();
+        }
+
+        if let Some(help) = &self.help {
+            writeln!(out, "{}= help: {}", pad, help).unwrap();
+        }
+
+        if !self.trace.is_empty() {
+            writeln!(out, "{}= stack trace:", pad).unwrap();
+        }
+
+        let lines = self.trace.iter().map(|frame| {
+            let at = frame.span.start;
+            let at_file = name_of(frame.span.file);
+
+            if frame.function == "<lambda>" {
+                let def = frame.defined_at.start;
+                let def_file = name_of(frame.defined_at.file);
+                format!("at <lambda {}:{}:{}> ({}:{}:{})", def_file, def.line, def.column, at_file, at.line, at.column)
+            } else {
+                format!("at {} ({}:{}:{})", frame.function, at_file, at.line, at.column)
+            }
+        }).collect::<Vec<_>>();
+
+        // Deep recursion repeats the same frames, or the same few frames when it goes through several
+        // functions or builtins like `if`, those are written once
+        let mut index = 0;
+        while index < lines.len() {
+            let (length, repeated) = repeating_cycle(&lines[index..]);
+
+            for line in &lines[index..index + length] {
+                writeln!(out, "{}    {}", pad, line).unwrap();
+            }
+
+            if repeated > 1 && length == 1 {
+                writeln!(out, "{}    ... repeated {} more times", pad, repeated - 1).unwrap();
+            } else if repeated > 1 {
+                writeln!(out, "{}    ... the {} frames above repeated {} more times", pad, length, repeated - 1).unwrap();
+            }
+            index += length * repeated;
+        }
+
+        out
+    }
+}
+
+/// Longest run of frames `repeating_cycle` looks for
+const MAX_CYCLE: usize = 16;
+
+/// Length of the run of lines at the start of `lines` that repeats back to back, and how many times
+/// it's there. The cycle that covers the most lines wins, `(1, 1)` if nothing repeats.
+fn repeating_cycle(lines: &[String]) -> (usize, usize) {
+    let mut best = (1, 1);
+
+    for length in 1..=MAX_CYCLE.min(lines.len() / 2) {
+        let cycle = &lines[..length];
+        let repeated = lines.chunks_exact(length).take_while(|it| *it == cycle).count();
+
+        if repeated > 1 && length * repeated > best.0 * best.1 {
+            best = (length, repeated);
+        }
+    }
+
+    best
+}
+
+/// Renders several diagnostics of the same file, separated by empty lines
+pub fn render_all<'a>(diagnostics: impl IntoIterator<Item=&'a Diagnostic>, file_name: &str, source: &str) -> String {
+    diagnostics.into_iter()
+        .map(|it| it.render(file_name, source))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders several diagnostics of any of the files of `sources`, separated by empty lines
+pub fn render_all_in<'a>(diagnostics: impl IntoIterator<Item=&'a Diagnostic>, sources: &SourceMap) -> String {
+    diagnostics.into_iter()
+        .map(|it| it.render_in(sources))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(error: &ParseError) -> Self {
+        // The parser stopped at text the tokenizer could not read, that's the real problem
+        if let ParseError::Expected { found: Token::Error(error, span), .. } |
+               ParseError::ExpectedId { found: Token::Error(error, span), .. } |
+               ParseError::UnexpectedToken(Token::Error(error, span), _) = error {
+            return Diagnostic::from(error).with_span(*span);
+        }
+
+        match error {
+            ParseError::Expected { expected, found, span } => {
+                Diagnostic::error(format!("expected {} but found {}", quote(expected), quote(found)))
+                    .with_span(*span)
+                    .with_label(format!("expected {}", quote(expected)))
+            }
+            ParseError::ExpectedId { found, span } => {
+                Diagnostic::error(format!("expected a name but found {}", quote(found)))
+                    .with_span(*span)
+                    .with_help("names start with a letter or `_`")
+            }
+            ParseError::UnexpectedToken(found, span) => {
+                Diagnostic::error(format!("unexpected {}", quote(found)))
+                    .with_span(*span)
+            }
+            ParseError::Eof { open, span } => {
+                Diagnostic::error(format!("unexpected end of file, {} was never closed", quote(open)))
+                    .with_span(*span)
+                    .with_label("unclosed delimiter")
+                    .with_help(format!("add {} to close it", quote(&closing_delimiter(open))))
+            }
+        }
+    }
+}
+
+impl From<&LexError> for Diagnostic {
+    fn from(error: &LexError) -> Self {
+        let diagnostic = Diagnostic::error(error.to_string());
+
+        match error {
+            LexError::UnexpectedChar(_) => diagnostic,
+            LexError::UnterminatedString => {
+                diagnostic.with_label("the string starts here").with_help("add `\"` to close it")
+            }
+            LexError::UnterminatedComment => {
+                diagnostic.with_label("the comment starts here").with_help("add `*/` to close it")
+            }
+            LexError::InvalidEscape(_) => {
+                diagnostic.with_label("not a valid escape")
+                    .with_note("valid escapes are `\\n`, `\\t`, `\\r`, `\\0`, `\\\\`, `\\\"`, `\\'`, `\\$` and `\\u{...}`")
+            }
+            LexError::InvalidNumber(_) => diagnostic,
+            LexError::LeadingZero(_) => diagnostic.with_help("use the prefix `0o` for octal numbers"),
+        }
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Self {
+        let (diagnostic, span) = match error {
+            CompileError::UndefinedName { name, span } => {
+                let diagnostic = Diagnostic::error(format!("undefined name `{}`", name))
+                    .with_label("not found in this scope");
+                (diagnostic, span)
+            }
+            CompileError::UsedBeforeAssignment { name, span } => {
+                let diagnostic = Diagnostic::error(format!("`{}` is used before it's assigned", name))
+                    .with_label("not assigned yet")
+                    .with_help(format!("move the assignment of `{}` before this line", name));
+                (diagnostic, span)
+            }
+            CompileError::DuplicateVariant { name, typedef, span, previous } => {
+                let diagnostic = Diagnostic::error(format!("variant `{}` is defined multiple times", name))
+                    .with_label("redefined here");
+
+                // Builtin types have no code
+                let diagnostic = if *previous == SourceSpan::default() {
+                    diagnostic.with_note(format!("`{}` is a variant of the builtin type `{}`", name, typedef))
+                } else {
+                    diagnostic.with_note_at(format!("previously defined by type `{}`", typedef), *previous)
+                };
+                (diagnostic, span)
+            }
+            CompileError::ConstructorArity { name, expected, found, span } => {
+                let diagnostic = Diagnostic::error(format!(
+                    "constructor `{}` takes {} but {} {} supplied", name, plural(*expected, "argument"), plural(*found, "argument"), was_were(*found)
+                )).with_label(format!("expected {}", plural(*expected, "argument")));
+                (diagnostic, span)
+            }
+            CompileError::LambdaArity { name, expected, found, span } => {
+                let mut diagnostic = Diagnostic::error(format!(
+                    "function `{}` takes {} but {} {} supplied", name, plural(*expected, "argument"), plural(*found, "argument"), was_were(*found)
+                )).with_label(format!("expected {}", plural(*expected, "argument")));
+                if *found == 0 {
+                    diagnostic = diagnostic.with_help(format!("use `@{}` to pass the function without calling it", name));
+                }
+                (diagnostic, span)
+            }
+            CompileError::ReturnOutsideLambda { span } => {
+                let diagnostic = Diagnostic::error("`return` outside of a lambda")
+                    .with_label("cannot return from here");
+                (diagnostic, span)
+            }
+            CompileError::UnknownProperty { name, span } => {
+                let diagnostic = Diagnostic::error(format!("unknown property `{}`", name))
+                    .with_label("no type has this property");
+                (diagnostic, span)
+            }
+            CompileError::DuplicateProperty { name, span, previous } => {
+                let diagnostic = Diagnostic::error(format!("property `{}` is updated multiple times", name))
+                    .with_label("updated again here")
+                    .with_note_at("first updated here", *previous);
+                (diagnostic, span)
+            }
+            CompileError::UnresolvedImport { path, span } => {
+                let diagnostic = Diagnostic::error(format!("module `{}` was not loaded", path))
+                    .with_label("imports are only allowed at the top level of a file");
+                (diagnostic, span)
+            }
+        };
+
+        diagnostic.with_file(span.file).with_span((span.start, span.end))
+    }
+}
+
+impl From<&CompileWarning> for Diagnostic {
+    fn from(warning: &CompileWarning) -> Self {
+        let (diagnostic, span) = match warning {
+            CompileWarning::NonExhaustiveMatch { missing, span } => {
+                let patterns: Vec<String> = missing.iter().map(|it| format!("`{}`", it)).collect();
+                let diagnostic = Diagnostic::warning("match is not exhaustive")
+                    .with_label(format!("{} not covered", patterns.join(", ")))
+                    .with_help("add arms for the missing patterns or a `_` arm");
+                (diagnostic, span)
+            }
+            CompileWarning::UnreachableArm { span } => {
+                let diagnostic = Diagnostic::warning("unreachable match arm")
+                    .with_label("the previous arms already match these values");
+                (diagnostic, span)
+            }
+        };
+
+        diagnostic.with_file(span.file).with_span((span.start, span.end))
+    }
+}
+
+impl From<&ModuleError> for Diagnostic {
+    fn from(error: &ModuleError) -> Self {
+        let (diagnostic, span) = match error {
+            ModuleError::Parse { file, error } => return Diagnostic::from(error).with_file(*file),
+            ModuleError::NotFound { path, span } => {
+                let diagnostic = Diagnostic::error(format!("cannot find module `{}`", path))
+                    .with_label("imported here")
+                    .with_note("modules are searched next to the importing file and in the directories of `DEMO_LANG_PATH`");
+                (diagnostic, span)
+            }
+            ModuleError::Io { error, span } => {
+                let diagnostic = Diagnostic::error(error.to_string()).with_label("imported here");
+                (diagnostic, span)
+            }
+            ModuleError::Cycle { modules, span } => {
+                let diagnostic = Diagnostic::error(format!("import cycle: {}", modules.join(" -> ")))
+                    .with_label("this import closes the cycle")
+                    .with_help("move the code both modules need to a new module");
+                (diagnostic, span)
+            }
+            ModuleError::UnknownExport { module, name, span } => {
+                let diagnostic = Diagnostic::error(format!("module `{}` has no export `{}`", module, name))
+                    .with_label("not defined at the top level of the module");
+                (diagnostic, span)
+            }
+            ModuleError::AssignsImport { name, span, import } => {
+                let diagnostic = Diagnostic::error(format!("cannot assign the imported name `{}`", name))
+                    .with_label("assigned here")
+                    .with_note_at("imported here", *import)
+                    .with_help("change it from its module, or use another name");
+                (diagnostic, span)
+            }
+            ModuleError::ModuleAsValue { name, span } => {
+                let diagnostic = Diagnostic::error(format!("module `{}` is not a value", name))
+                    .with_label("used as a value here")
+                    .with_help(format!("use `{}.name` to access the names of the module", name));
+                (diagnostic, span)
+            }
+        };
+
+        diagnostic.with_file(span.file).with_span((span.start, span.end))
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(error: &RuntimeError) -> Self {
+        let mut diagnostic = Diagnostic::error(error.to_string());
+        diagnostic.trace = error.trace().to_vec();
+
+        if let Some(frame) = diagnostic.trace.first() {
+            diagnostic.file = frame.span.file;
+            diagnostic.span = Some((frame.span.start, frame.span.end));
+        }
+
+        if let RuntimeError::ArityMismatch { got: 0, .. } = error.kind() {
+            diagnostic.help = Some("use `@name` to pass a function without calling it".to_string());
+        }
+
+        diagnostic
+    }
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 { format!("1 {}", word) } else { format!("{} {}s", count, word) }
+}
+
+fn was_were(count: usize) -> &'static str {
+    if count == 1 { "was" } else { "were" }
+}
+
+fn quote(tk: &Token) -> String {
+    match tk {
+        Token::Eof => tk.to_string(),
+        _ => format!("`{}`", tk),
+    }
+}
+
+fn closing_delimiter(open: &Token) -> Token {
+    match open {
+        Token::LeftParen => Token::RightParen,
+        Token::LeftBracket => Token::RightBracket,
+        _ => Token::RightBrace,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::source::{SourceReader, SourceSpan, Span};
+    use crate::tokenizer::Tokenizer;
+
+    use super::*;
+
+    fn render_error(code: &str) -> String {
+        let reader = SourceReader::new(code);
+        let mut parser = Parser::new(Tokenizer::new(reader));
+        let errors = parser.parse_program().expect_err("Expected a ParseError");
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+
+        render_all(&diagnostics, "main.dl", code)
+    }
+
+    #[test]
+    fn expected_token() {
+        let out = render_error("type A = B(a b)");
+        assert_eq!(out, "\
+error: expected `)` but found `b`
+ --> main.dl:1:14
+  |
+1 | type A = B(a b)
+  |              ^ expected `)`
+");
+    }
+
+    #[test]
+    fn unclosed_delimiter() {
+        let out = render_error("x = 1\nf = { a |\n  print a\n");
+        assert_eq!(out, "\
+error: unexpected end of file, `{` was never closed
+ --> main.dl:2:5
+  |
+2 | f = { a |
+  |     ^ unclosed delimiter
+  = help: add `}` to close it
+");
+    }
+
+    #[test]
+    fn unclosed_interpolation() {
+        let out = render_error("x = 1\nprintln \"a ${x} b ${x\n");
+        assert_eq!(out, "\
+error: unexpected end of file, `{` was never closed
+ --> main.dl:2:15
+  |
+2 | println \"a ${x} b ${x
+  |               ^^^^^^ unclosed delimiter
+  = help: add `}` to close it
+");
+
+        let out = render_error("println \"a ${");
+        assert!(out.starts_with("error: unexpected end of file, `{` was never closed\n --> main.dl:1:9\n"), "{}", out);
+    }
+
+    #[test]
+    fn non_ascii_columns() {
+        let out = render_error("café = (\"ñ\" ]");
+        assert_eq!(out, "\
+error: unexpected `]`
+ --> main.dl:1:13
+  |
+1 | café = (\"ñ\" ]
+  |             ^
+");
+    }
+
+    #[test]
+    fn lexical_errors() {
+        let out = render_error("x = \"abc\\q\"\ny = 017");
+        assert_eq!(out, "\
+error: invalid escape sequence `\\q`
+ --> main.dl:1:9
+  |
+1 | x = \"abc\\q\"
+  |         ^^ not a valid escape
+  = note: valid escapes are `\\n`, `\\t`, `\\r`, `\\0`, `\\\\`, `\\\"`, `\\'`, `\\$` and `\\u{...}`
+
+error: leading zeros are not allowed in `017`
+ --> main.dl:2:5
+  |
+2 | y = 017
+  |     ^^^
+  = help: use the prefix `0o` for octal numbers
+");
+    }
+
+    #[test]
+    fn multiple_errors() {
+        let out = render_error("print (1 2 ]\nx = 3\ntype = A");
+        assert_eq!(out, "\
+error: unexpected `]`
+ --> main.dl:1:12
+  |
+1 | print (1 2 ]
+  |            ^
+
+error: expected a name but found `=`
+ --> main.dl:3:6
+  |
+3 | type = A
+  |      ^
+  = help: names start with a letter or `_`
+");
+    }
+
+    #[test]
+    fn compile_errors() {
+        let code = "f = { a, b | a }\nf 1\ng = missing";
+        let reader = SourceReader::new(code);
+        let program = Parser::new(Tokenizer::new(reader)).parse_program().unwrap();
+        let errors = Compiler::new().compile(program).expect_err("Expected a CompileError");
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+
+        assert_eq!(render_all(&diagnostics, "main.dl", code), "\
+error: function `f` takes 2 arguments but 1 argument was supplied
+ --> main.dl:2:1
+  |
+2 | f 1
+  | ^^^ expected 2 arguments
+
+error: undefined name `missing`
+ --> main.dl:3:5
+  |
+3 | g = missing
+  |     ^^^^^^^ not found in this scope
+");
+    }
+
+    #[test]
+    fn duplicate_variants() {
+        let render = |code: &str| {
+            let errors = crate::testing::Pipeline::new().compile(code).expect_err("Expected a CompileError");
+            render_all(&errors.iter().map(Diagnostic::from).collect::<Vec<_>>(), "main.dl", code)
+        };
+
+        assert_eq!(render("type A = X | Y\ntype B = Y"), "\
+error: variant `Y` is defined multiple times
+ --> main.dl:2:10
+  |
+2 | type B = Y
+  |          ^ redefined here
+  = note: previously defined by type `A` at main.dl:1:14
+");
+
+        assert_eq!(render("type B = True | No"), "\
+error: variant `True` is defined multiple times
+ --> main.dl:1:10
+  |
+1 | type B = True | No
+  |          ^^^^ redefined here
+  = note: `True` is a variant of the builtin type `Boolean`
+");
+    }
+
+    #[test]
+    fn arity_messages() {
+        let message = |error: CompileError| Diagnostic::from(&error).message;
+        let span = SourceSpan::default();
+
+        assert_eq!("function `f` takes 1 argument but 3 arguments were supplied",
+                   message(CompileError::LambdaArity { name: "f".to_string(), expected: 1, found: 3, span }));
+        assert_eq!("constructor `A` takes 2 arguments but 0 arguments were supplied",
+                   message(CompileError::ConstructorArity { name: "A".to_string(), expected: 2, found: 0, span }));
+
+        let error = RuntimeError::ArityMismatch { name: "g".to_string(), expected: 0, got: 1 };
+        assert_eq!("function `g` takes 0 arguments but 1 argument was supplied", error.to_string());
+    }
+
+    #[test]
+    fn caret_spans_whole_token() {
+        let out = render_error("type 123 = A");
+        assert!(out.contains("1 | type 123 = A\n  |      ^^^\n"), "{}", out);
+    }
+
+    #[test]
+    fn spans_without_code() {
+        let start = Span { line: 0, column: 0 };
+        let diagnostic = Diagnostic::error("no code").with_span((start, start));
+
+        assert_eq!("error: no code\n --> main.dl:0:0\n", diagnostic.render("main.dl", "a = 1"));
+    }
+
+    #[test]
+    fn repeated_trace_frames() {
+        let frame = |function: &str, line| {
+            let span = SourceSpan::new(0, Span { line, column: 1 }, Span { line, column: 2 });
+            TraceFrame { function: function.to_string(), defined_at: span, span }
+        };
+        let mut diagnostic = Diagnostic::error("stack overflow");
+        diagnostic.trace = vec![frame("down", 2), frame("down", 2), frame("down", 2), frame("<main>", 3)];
+
+        let out = diagnostic.render("main.dl", "");
+        assert_eq!(out, "\
+error: stack overflow
+ = stack trace:
+     at down (main.dl:2:1)
+     ... repeated 2 more times
+     at <main> (main.dl:3:1)
+");
+    }
+
+    #[test]
+    fn repeated_trace_cycles() {
+        let frame = |function: &str, line| {
+            let span = SourceSpan::new(0, Span { line, column: 1 }, Span { line, column: 2 });
+            TraceFrame { function: function.to_string(), defined_at: span, span }
+        };
+
+        // `down` recursing through the lambda it gives to `if`
+        let mut diagnostic = Diagnostic::error("stack overflow");
+        diagnostic.trace = vec![frame("<lambda>", 1)];
+        for _ in 0..5000 {
+            diagnostic.trace.extend(vec![frame("down", 1), frame("<lambda>", 1)]);
+        }
+        diagnostic.trace.pop();
+        diagnostic.trace.push(frame("<main>", 2));
+
+        let out = diagnostic.render("main.dl", "");
+        assert_eq!(out, "\
+error: stack overflow
+ = stack trace:
+     at <lambda main.dl:1:1> (main.dl:1:1)
+     at down (main.dl:1:1)
+     ... the 2 frames above repeated 4999 more times
+     at <main> (main.dl:2:1)
+");
+
+        // Mutual recursion, with a different call on the way in
+        diagnostic.trace = vec![frame("even", 3), frame("odd", 2)];
+        for _ in 0..3 {
+            diagnostic.trace.extend(vec![frame("even", 1), frame("odd", 2)]);
+        }
+        diagnostic.trace.push(frame("<main>", 4));
+
+        let out = diagnostic.render("main.dl", "");
+        assert_eq!(out, "\
+error: stack overflow
+ = stack trace:
+     at even (main.dl:3:1)
+     at odd (main.dl:2:1)
+     at even (main.dl:1:1)
+     ... the 2 frames above repeated 2 more times
+     at odd (main.dl:2:1)
+     at <main> (main.dl:4:1)
+");
+    }
+}
@@ -0,0 +1,283 @@
+use std::rc::Rc;
+
+use crate::ast::Pattern;
+
+/// Variants of a type with their number of properties, in declaration order
+pub type Signature = Rc<Vec<(String, usize)>>;
+
+/// Pattern reduced to constructors and wildcards, lists are seen as chains of `Cons` ending in `Nil`
+#[derive(Debug, Clone)]
+enum Pat {
+    Wild,
+    Ctor(Ctor, Vec<Pat>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Ctor {
+    Variant(String, Signature),
+    Tuple(usize),
+    Nil,
+    Cons,
+    /// Ints, floats and strings, there are always more values than arms
+    Literal(String),
+}
+
+/// Result of checking the arms of a `match`
+#[derive(Debug, Default)]
+pub struct MatchReport {
+    /// Indices of the arms that can never match because the previous ones cover them
+    pub unreachable: Vec<usize>,
+    /// Examples of values no arm matches, empty if the match is exhaustive
+    pub missing: Vec<String>,
+}
+
+/// Max number of missing patterns listed
+const MAX_MISSING: usize = 5;
+
+/// Checks the arms of a match, given their patterns and whether they have a guard. Arms with a guard
+/// may not match, so they don't cover the values of their pattern. `signature` finds the type of a
+/// variant by its name, unknown variants are treated as wildcards because they were already reported.
+pub fn check_match(arms: &[(&Pattern, bool)], signature: &dyn Fn(&str) -> Option<Signature>) -> MatchReport {
+    let mut report = MatchReport::default();
+    let mut matrix: Vec<Vec<Pat>> = vec![];
+
+    for (index, (pattern, guarded)) in arms.iter().enumerate() {
+        let row = vec![lower(pattern, signature)];
+
+        if !useful(&matrix, &row) {
+            report.unreachable.push(index);
+        }
+
+        if !guarded {
+            matrix.push(row);
+        }
+    }
+
+    let mut missing = missing(&matrix, 1);
+    missing.truncate(MAX_MISSING);
+    report.missing = missing.iter().map(|it| show(&it[0])).collect();
+    report
+}
+
+fn lower(pattern: &Pattern, signature: &dyn Fn(&str) -> Option<Signature>) -> Pat {
+    match pattern {
+        Pattern::Wildcard { .. } | Pattern::Binding { .. } => Pat::Wild,
+        Pattern::Int { value, .. } => Pat::Ctor(Ctor::Literal(value.to_string()), vec![]),
+        Pattern::BigInt { value, .. } => Pat::Ctor(Ctor::Literal(value.to_string()), vec![]),
+        Pattern::Float { value, .. } => Pat::Ctor(Ctor::Literal(format!("{:?}", value)), vec![]),
+        Pattern::String { value, .. } => Pat::Ctor(Ctor::Literal(format!("{:?}", value)), vec![]),
+        Pattern::Tuple { items, .. } => {
+            Pat::Ctor(Ctor::Tuple(items.len()), items.iter().map(|it| lower(it, signature)).collect())
+        }
+        Pattern::List { items, rest, .. } => {
+            let tail = match rest {
+                Some(rest) => lower(rest, signature),
+                None => Pat::Ctor(Ctor::Nil, vec![]),
+            };
+
+            items.iter().rev().fold(tail, |tail, item| {
+                Pat::Ctor(Ctor::Cons, vec![lower(item, signature), tail])
+            })
+        }
+        Pattern::Variant { name, args, .. } => {
+            match signature(name) {
+                Some(sig) if sig.iter().any(|(it, arity)| it == name && *arity == args.len()) => {
+                    Pat::Ctor(Ctor::Variant(name.to_string(), sig), args.iter().map(|it| lower(it, signature)).collect())
+                }
+                _ => Pat::Wild,
+            }
+        }
+    }
+}
+
+impl Ctor {
+    fn arity(&self) -> usize {
+        match self {
+            Ctor::Variant(name, sig) => sig.iter().find(|(it, _)| it == name).map(|(_, arity)| *arity).unwrap_or(0),
+            Ctor::Tuple(len) => *len,
+            Ctor::Cons => 2,
+            Ctor::Nil | Ctor::Literal(_) => 0,
+        }
+    }
+}
+
+/// Constructors used in the first column of the matrix, without repetitions
+fn head_ctors(matrix: &[Vec<Pat>]) -> Vec<Ctor> {
+    let mut ctors: Vec<Ctor> = vec![];
+
+    for row in matrix {
+        if let Pat::Ctor(ctor, _) = &row[0] {
+            if !ctors.contains(ctor) {
+                ctors.push(ctor.clone());
+            }
+        }
+    }
+
+    ctors
+}
+
+/// Every constructor of the type of `ctors`, if all of them belong to the same type and it has a
+/// finite number of constructors
+fn signature_of(ctors: &[Ctor]) -> Option<Vec<Ctor>> {
+    let all = match ctors.first()? {
+        Ctor::Variant(_, sig) => {
+            sig.iter().map(|(name, _)| Ctor::Variant(name.to_string(), sig.clone())).collect()
+        }
+        Ctor::Tuple(len) => vec![Ctor::Tuple(*len)],
+        Ctor::Nil | Ctor::Cons => vec![Ctor::Nil, Ctor::Cons],
+        Ctor::Literal(_) => return None,
+    };
+
+    if ctors.iter().all(|it| all.contains(it)) {
+        Some(all)
+    } else {
+        None
+    }
+}
+
+/// Rows that match `ctor`, with its arguments expanded in place of the first column
+fn specialize(matrix: &[Vec<Pat>], ctor: &Ctor) -> Vec<Vec<Pat>> {
+    let arity = ctor.arity();
+
+    matrix.iter()
+        .filter_map(|row| {
+            let mut new_row = match &row[0] {
+                Pat::Ctor(it, args) if it == ctor => args.clone(),
+                Pat::Ctor(..) => return None,
+                Pat::Wild => vec![Pat::Wild; arity],
+            };
+            new_row.extend_from_slice(&row[1..]);
+            Some(new_row)
+        })
+        .collect()
+}
+
+/// Rows starting with a wildcard, without the first column
+fn default_rows(matrix: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    matrix.iter()
+        .filter(|row| matches!(row[0], Pat::Wild))
+        .map(|row| row[1..].to_vec())
+        .collect()
+}
+
+/// Checks if some value matches `row` but none of the rows of the matrix
+fn useful(matrix: &[Vec<Pat>], row: &[Pat]) -> bool {
+    let (first, rest) = match row.split_first() {
+        Some(it) => it,
+        None => return matrix.is_empty(),
+    };
+
+    match first {
+        Pat::Ctor(ctor, args) => {
+            let mut new_row = args.clone();
+            new_row.extend_from_slice(rest);
+            useful(&specialize(matrix, ctor), &new_row)
+        }
+        Pat::Wild => {
+            let ctors = head_ctors(matrix);
+
+            match signature_of(&ctors).filter(|all| all.iter().all(|it| ctors.contains(it))) {
+                Some(all) => all.iter().any(|ctor| {
+                    let mut new_row = vec![Pat::Wild; ctor.arity()];
+                    new_row.extend_from_slice(rest);
+                    useful(&specialize(matrix, ctor), &new_row)
+                }),
+                None => useful(&default_rows(matrix), rest),
+            }
+        }
+    }
+}
+
+/// Rows of `width` patterns that match values no row of the matrix matches
+fn missing(matrix: &[Vec<Pat>], width: usize) -> Vec<Vec<Pat>> {
+    if width == 0 {
+        return if matrix.is_empty() { vec![vec![]] } else { vec![] };
+    }
+
+    let ctors = head_ctors(matrix);
+    let signature = signature_of(&ctors);
+
+    if let Some(all) = signature.as_ref().filter(|all| all.iter().all(|it| ctors.contains(it))) {
+        let mut result = vec![];
+
+        for ctor in all {
+            let arity = ctor.arity();
+
+            for mut witness in missing(&specialize(matrix, ctor), arity + width - 1) {
+                let rest = witness.split_off(arity);
+                let mut row = vec![Pat::Ctor(ctor.clone(), witness)];
+                row.extend(rest);
+                result.push(row);
+
+                if result.len() > MAX_MISSING {
+                    return result;
+                }
+            }
+        }
+
+        return result;
+    }
+
+    let rest = missing(&default_rows(matrix), width - 1);
+    if rest.is_empty() {
+        return vec![];
+    }
+
+    // List the constructors not used yet, or `_` if there are too many to list
+    let firsts: Vec<Pat> = match signature {
+        Some(all) => all.into_iter()
+            .filter(|it| !ctors.contains(it))
+            .map(|it| {
+                let args = vec![Pat::Wild; it.arity()];
+                Pat::Ctor(it, args)
+            })
+            .collect(),
+        None => vec![Pat::Wild],
+    };
+
+    let mut result = vec![];
+    for first in firsts {
+        for witness in &rest {
+            let mut row = vec![first.clone()];
+            row.extend(witness.iter().cloned());
+            result.push(row);
+        }
+    }
+
+    result
+}
+
+/// Shows a pattern with the syntax of the language
+fn show(pat: &Pat) -> String {
+    match pat {
+        Pat::Wild => "_".to_string(),
+        Pat::Ctor(Ctor::Variant(name, _), args) if args.is_empty() => name.to_string(),
+        Pat::Ctor(Ctor::Variant(name, _), args) => format!("{}({})", name, show_all(args)),
+        Pat::Ctor(Ctor::Tuple(_), args) => format!("({})", show_all(args)),
+        Pat::Ctor(Ctor::Literal(text), _) => text.to_string(),
+        Pat::Ctor(Ctor::Nil, _) | Pat::Ctor(Ctor::Cons, _) => {
+            let mut items = vec![];
+            let mut current = pat;
+
+            loop {
+                match current {
+                    Pat::Ctor(Ctor::Cons, args) => {
+                        items.push(show(&args[0]));
+                        current = &args[1];
+                    }
+                    Pat::Ctor(Ctor::Nil, _) => break,
+                    other => {
+                        items.push(format!("...{}", show(other)));
+                        break;
+                    }
+                }
+            }
+
+            format!("[{}]", items.join(", "))
+        }
+    }
+}
+
+fn show_all(pats: &[Pat]) -> String {
+    pats.iter().map(show).collect::<Vec<_>>().join(", ")
+}
@@ -2,62 +2,46 @@
 // cargo watch -c -q -s 'cargo rustc -- -Awarnings -Zno-codegen && cargo test'
 // https://www.lysator.liu.se/c/ANSI-C-grammar-l.html#comment
 
-use crate::parser::Parser;
-use crate::source::{CodeSource, SourceReader};
-use crate::tokenizer::{Tokenizer};
-use crate::compiler::Compiler;
-use crate::runtime::Runtime;
-use crate::builtins::register_builtins;
-
 mod source;
 mod tokenizer;
 mod parser;
 mod ast;
+mod analysis;
+mod exhaustiveness;
 mod compiler;
+mod modules;
 mod run;
+mod bytecode;
 mod runtime;
 mod builtins;
+mod operators;
+mod cli;
+mod diagnostics;
+mod repl;
+#[cfg(test)]
+mod testing;
 
 fn main() {
-    let source = CodeSource::file("pruebas.txt");
-    let reader = SourceReader::new(source);
-    let tokenizer = Tokenizer::new(reader);
-    let mut parser = Parser::new(tokenizer);
-    let program = parser.parse_program().expect("Unable to parse program");
-
-    let mut compiler = Compiler::new();
-    let compiled_program = compiler.compile(program).expect("Unable to compile program");
-
-    println!("{:#?}", compiled_program);
-
-
-    let mut runtime = Runtime::new();
-    register_builtins(&mut runtime);
-
-    let result = runtime.run(compiled_program);
-
-    println!("{:#?}", result);
+    let args = std::env::args().skip(1).collect();
+    std::process::exit(cli::main(args));
 }
 
 
-#[test]
-fn hello_world() {
-    let source = CodeSource::str("print \"hello world\"");
-    let reader = SourceReader::new(source);
-    let tokenizer = Tokenizer::new(reader);
-    let mut parser = Parser::new(tokenizer);
-    let program = parser.parse_program().expect("Unable to parse program");
+#[cfg(test)]
+mod tests {
+    use crate::testing::Pipeline;
 
-    let mut compiler = Compiler::new();
-    let compiled_program = compiler.compile(program).expect("Unable to compile program");
+    #[test]
+    fn hello_world() {
+        let source = "println \"hello world\"";
+        let mut pipeline = Pipeline::new();
+        let compiled_program = pipeline.compile(source).expect("Unable to compile program");
 
-    println!("{:#?}", compiled_program);
+        println!("{:#?}", compiled_program);
 
 
-    let mut runtime = Runtime::new();
-    register_builtins(&mut runtime);
+        let result = pipeline.runtime.run(compiled_program);
 
-    let result = runtime.run(compiled_program);
-
-    println!("{:#?}", result);
-}
\ No newline at end of file
+        println!("{:#?}", result);
+    }
+}
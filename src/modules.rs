@@ -0,0 +1,688 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::ast::{Expression, Import, ImportNames, Pattern, Program, Statement};
+use crate::parser::{ParseError, Parser};
+use crate::source::{FileId, SourceError, SourceMap, SourceSpan};
+use crate::tokenizer::Tokenizer;
+
+#[derive(Debug)]
+pub enum ModuleError {
+    /// No file with this path next to the importing file nor in the search path
+    NotFound { path: String, span: SourceSpan },
+    /// The file exists but could not be read
+    Io { error: SourceError, span: SourceSpan },
+    Parse { file: FileId, error: ParseError },
+    /// Modules that import each other, from the first module of the cycle back to it
+    Cycle { modules: Vec<String>, span: SourceSpan },
+    UnknownExport { module: String, name: String, span: SourceSpan },
+    /// The alias of a module used as a value instead of as `alias.name`
+    ModuleAsValue { name: String, span: SourceSpan },
+    /// A name imported with `import { name }` is assigned or defined again, `import` is where it was imported
+    AssignsImport { name: String, span: SourceSpan, import: SourceSpan },
+}
+
+/// A program with its imports resolved
+#[derive(Debug)]
+pub struct LinkedProgram {
+    /// Code of the modules imported for the first time, in the order it must run
+    pub modules: Vec<Statement>,
+    pub program: Program,
+}
+
+/// Names defined at the top level of a module
+#[derive(Debug)]
+struct Module {
+    /// Path of the file, as shown in diagnostics
+    path: String,
+    /// Name of each variable and variant of the module in the program, where it's qualified by the
+    /// name of the module, like `utils.double`
+    exports: HashMap<String, String>,
+    /// Variants of each type of the module
+    types: HashMap<String, Vec<String>>,
+}
+
+/// Names visible at the top level of a module
+#[derive(Debug, Default)]
+struct ModuleScope {
+    /// Name in the program of the names defined or imported by the module
+    names: HashMap<String, String>,
+    /// Modules imported with `import "path" as alias`
+    aliases: HashMap<String, Rc<Module>>,
+    /// Names imported with `import { name }`, where they were imported. They belong to their module,
+    /// so they can't be assigned nor defined again.
+    imported: HashMap<String, SourceSpan>,
+}
+
+/// Loads the modules imported by a program and links them into a single program. Each module is
+/// loaded and run once, its top level names are renamed to `module.name` so they don't clash with
+/// the names of other modules.
+pub struct ModuleLoader {
+    pub sources: SourceMap,
+    /// Directories where imports are searched when they are not next to the importing file
+    search_path: Vec<PathBuf>,
+    /// Loaded modules by their canonical path
+    modules: HashMap<PathBuf, Rc<Module>>,
+    /// Modules being loaded, each one imported by the previous one, to detect cycles
+    loading: Vec<(PathBuf, String)>,
+    /// Names used to qualify the names of the modules
+    prefixes: HashSet<String>,
+    /// Names visible at the top level of the linked programs, kept for the next ones like globals
+    root: ModuleScope,
+    /// Code of the modules loaded by the current call to `link`
+    code: Vec<Statement>,
+    /// Modules loaded by the current call to `link`, forgotten if it fails because their code never runs
+    loaded: Vec<PathBuf>,
+    errors: Vec<ModuleError>,
+}
+
+impl ModuleLoader {
+    pub fn new() -> Self {
+        ModuleLoader {
+            sources: SourceMap::new(),
+            search_path: vec![],
+            modules: Default::default(),
+            loading: vec![],
+            prefixes: Default::default(),
+            root: Default::default(),
+            code: vec![],
+            loaded: vec![],
+            errors: vec![],
+        }
+    }
+
+    pub fn add_search_path(&mut self, dir: impl Into<PathBuf>) {
+        self.search_path.push(dir.into());
+    }
+
+    /// Parses a file of `sources` and links it, its imports are relative to the directory of the file
+    pub fn load_main(&mut self, file: FileId) -> Result<LinkedProgram, Vec<ModuleError>> {
+        let program = self.parse(file).map_err(|_| mem::take(&mut self.errors))?;
+        let path = PathBuf::from(&self.sources.file(file).name);
+
+        // Modules that import the main file are part of a cycle
+        let key = fs::canonicalize(&path).ok();
+        if let Some(key) = &key {
+            self.loading.push((key.clone(), path.to_string_lossy().to_string()));
+        }
+
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let result = self.link(program, &dir);
+
+        if key.is_some() {
+            self.loading.pop();
+        }
+
+        result
+    }
+
+    /// Resolves the imports of a program, its top level names are not renamed. Names defined or
+    /// imported by previous programs stay visible, as they do in the REPL.
+    pub fn link(&mut self, program: Program, dir: &Path) -> Result<LinkedProgram, Vec<ModuleError>> {
+        let mut scope = mem::take(&mut self.root);
+        let statements = self.link_module(program.statements, dir, &mut scope, None);
+        self.root = scope;
+
+        let modules = mem::take(&mut self.code);
+        let loaded = mem::take(&mut self.loaded);
+
+        if !self.errors.is_empty() {
+            for key in loaded {
+                self.modules.remove(&key);
+            }
+            return Err(mem::take(&mut self.errors));
+        }
+
+        Ok(LinkedProgram { modules, program: Program { statements } })
+    }
+
+    /// Forgets every module and name, modules imported again are loaded and run again
+    pub fn reset(&mut self) {
+        self.modules.clear();
+        self.prefixes.clear();
+        self.root = ModuleScope::default();
+    }
+
+    fn parse(&mut self, file: FileId) -> Result<Program, ()> {
+        let tokenizer = Tokenizer::new(self.sources.reader(file));
+
+        Parser::with_file(tokenizer, file).parse_program().map_err(|errors| {
+            self.errors.extend(errors.into_iter().map(|error| ModuleError::Parse { file, error }));
+        })
+    }
+
+    /// Resolves the imports of the top level of a module and renames its names, `prefix` qualifies
+    /// the names the module defines
+    fn link_module(&mut self, statements: Vec<Statement>, dir: &Path, scope: &mut ModuleScope, prefix: Option<&str>) -> Vec<Statement> {
+        let mut code = vec![];
+
+        for stm in statements {
+            match stm {
+                Statement::Import(import) => self.import(import, dir, scope),
+                stm => code.push(stm),
+            }
+        }
+
+        let qualify = |name: &str| match prefix {
+            Some(prefix) => format!("{}.{}", prefix, name),
+            None => name.to_string(),
+        };
+
+        for stm in &mut code {
+            match stm {
+                Statement::Variable(var) => {
+                    if let Some(import) = scope.imported.get(&var.name) {
+                        self.errors.push(ModuleError::AssignsImport { name: var.name.to_string(), span: var.span, import: *import });
+                        continue;
+                    }
+                    scope.names.insert(var.name.to_string(), qualify(&var.name));
+                }
+                Statement::TypeDef(def) => {
+                    def.name = qualify(&def.name);
+                    for variant in &def.variants {
+                        if let Some(import) = scope.imported.get(&variant.name) {
+                            self.errors.push(ModuleError::AssignsImport { name: variant.name.to_string(), span: variant.span, import: *import });
+                            continue;
+                        }
+                        scope.names.insert(variant.name.to_string(), qualify(&variant.name));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut renamer = Renamer { scope, locals: vec![], errors: &mut self.errors };
+        renamer.statements(&mut code);
+        code
+    }
+
+    fn import(&mut self, import: Import, dir: &Path, scope: &mut ModuleScope) {
+        let module = match self.load(&import.path, dir, import.span) {
+            Some(module) => module,
+            None => return,
+        };
+
+        match import.names {
+            ImportNames::Alias(alias) => {
+                scope.aliases.insert(alias, module);
+            }
+            ImportNames::Names(names) => {
+                for (name, span) in names {
+                    let mut found = false;
+
+                    if let Some(global) = module.exports.get(&name) {
+                        scope.names.insert(name.to_string(), global.to_string());
+                        scope.imported.insert(name.to_string(), span);
+                        found = true;
+                    }
+
+                    // Importing a type imports its variants
+                    if let Some(variants) = module.types.get(&name) {
+                        for variant in variants {
+                            scope.names.insert(variant.to_string(), module.exports[variant].to_string());
+                            scope.imported.insert(variant.to_string(), span);
+                        }
+                        found = true;
+                    }
+
+                    if !found {
+                        self.errors.push(ModuleError::UnknownExport { module: module.path.to_string(), name, span });
+                    }
+                }
+            }
+        }
+    }
+
+    fn load(&mut self, path: &str, dir: &Path, span: SourceSpan) -> Option<Rc<Module>> {
+        let resolved = match self.resolve(path, dir) {
+            Some(resolved) => resolved,
+            None => {
+                self.errors.push(ModuleError::NotFound { path: path.to_string(), span });
+                return None;
+            }
+        };
+
+        let key = fs::canonicalize(&resolved).unwrap_or_else(|_| resolved.clone());
+        let name = resolved.to_string_lossy().to_string();
+
+        if let Some(index) = self.loading.iter().position(|(it, _)| it == &key) {
+            let mut modules: Vec<String> = self.loading[index..].iter().map(|(_, name)| name.to_string()).collect();
+            modules.push(name);
+            self.errors.push(ModuleError::Cycle { modules, span });
+            return None;
+        }
+
+        if let Some(module) = self.modules.get(&key) {
+            return Some(module.clone());
+        }
+
+        let file = match self.sources.load(&name) {
+            Ok(file) => file,
+            Err(error) => {
+                self.errors.push(ModuleError::Io { error, span });
+                return None;
+            }
+        };
+
+        let program = self.parse(file).ok()?;
+        let prefix = self.prefix(&resolved);
+
+        let mut exports = HashMap::new();
+        let mut types = HashMap::new();
+        for stm in &program.statements {
+            match stm {
+                Statement::Variable(var) => {
+                    exports.insert(var.name.to_string(), format!("{}.{}", prefix, var.name));
+                }
+                Statement::TypeDef(def) => {
+                    let variants: Vec<String> = def.variants.iter().map(|it| it.name.to_string()).collect();
+                    for variant in &variants {
+                        exports.insert(variant.to_string(), format!("{}.{}", prefix, variant));
+                    }
+                    types.insert(def.name.to_string(), variants);
+                }
+                _ => {}
+            }
+        }
+
+        self.loading.push((key.clone(), name.to_string()));
+        let mut scope = ModuleScope::default();
+        let dir = resolved.parent().map(Path::to_path_buf).unwrap_or_default();
+        let code = self.link_module(program.statements, &dir, &mut scope, Some(&prefix));
+        self.loading.pop();
+
+        // Imported modules run before the modules that import them
+        self.code.extend(code);
+
+        let module = Rc::new(Module { path: name, exports, types });
+        self.modules.insert(key.clone(), module.clone());
+        self.loaded.push(key);
+
+        Some(module)
+    }
+
+    /// Finds an imported file, first next to the importing file, then in the search path
+    fn resolve(&self, path: &str, dir: &Path) -> Option<PathBuf> {
+        std::iter::once(dir)
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|it| it.join(path))
+            .find(|it| it.is_file())
+    }
+
+    /// Name used to qualify the names of a module, the name of its file unless another module has it
+    fn prefix(&mut self, path: &Path) -> String {
+        let stem = path.file_stem().map(|it| it.to_string_lossy().to_string()).unwrap_or_default();
+        let mut prefix = stem.to_string();
+        let mut count = 1;
+
+        while self.prefixes.contains(&prefix) {
+            count += 1;
+            prefix = format!("{}{}", stem, count);
+        }
+
+        self.prefixes.insert(prefix.to_string());
+        prefix
+    }
+}
+
+/// Replaces the names of a module by their names in the program
+struct Renamer<'a> {
+    scope: &'a ModuleScope,
+    /// Variables of the enclosing lambdas and match arms, they hide the names of the module
+    locals: Vec<HashSet<String>>,
+    errors: &'a mut Vec<ModuleError>,
+}
+
+impl Renamer<'_> {
+    fn is_local(&self, name: &str) -> bool {
+        self.locals.iter().any(|it| it.contains(name))
+    }
+
+    fn rename(&self, name: &mut String) {
+        if self.is_local(name) {
+            return;
+        }
+
+        if let Some(global) = self.scope.names.get(name.as_str()) {
+            *name = global.to_string();
+        }
+    }
+
+    /// The module of an expression that is just the alias of a module
+    fn alias(&self, expr: &Expression) -> Option<Rc<Module>> {
+        match expr {
+            Expression::FunCall { name, args, .. } if args.is_empty() && !self.is_local(name) => {
+                self.scope.aliases.get(name).cloned()
+            }
+            _ => None,
+        }
+    }
+
+    /// Name in the program of an export of `module`, `name` itself if there is no such export
+    fn export(&mut self, module: &Module, name: &str, span: SourceSpan) -> String {
+        match module.exports.get(name) {
+            Some(global) => global.to_string(),
+            None => {
+                self.errors.push(ModuleError::UnknownExport { module: module.path.to_string(), name: name.to_string(), span });
+                name.to_string()
+            }
+        }
+    }
+
+    fn statements(&mut self, code: &mut [Statement]) {
+        for stm in code {
+            match stm {
+                Statement::Variable(var) => {
+                    self.expression(&mut var.value);
+                    self.rename(&mut var.name);
+                }
+                Statement::Expression(expr) => self.expression(expr),
+                Statement::TypeDef(def) => {
+                    for variant in &mut def.variants {
+                        self.rename(&mut variant.name);
+                    }
+                }
+                // Imports inside lambdas are reported by the compiler
+                Statement::Import(_) | Statement::Error(_) => {}
+            }
+        }
+    }
+
+    fn lambda(&mut self, args: &[String], code: &mut [Statement]) {
+        let mut locals: HashSet<String> = args.iter().cloned().collect();
+
+        // Assigning a variable of the module or of an enclosing lambda changes it instead of creating a new one
+        for stm in code.iter() {
+            if let Statement::Variable(var) = stm {
+                if self.is_local(&var.name) || locals.contains(&var.name) {
+                    continue;
+                }
+
+                if let Some(import) = self.scope.imported.get(&var.name) {
+                    self.errors.push(ModuleError::AssignsImport { name: var.name.to_string(), span: var.span, import: *import });
+                } else if !self.scope.names.contains_key(&var.name) {
+                    locals.insert(var.name.to_string());
+                }
+            }
+        }
+
+        self.locals.push(locals);
+        self.statements(code);
+        self.locals.pop();
+    }
+
+    fn expression(&mut self, expr: &mut Expression) {
+        match expr {
+            Expression::FunCall { name, args, span } => {
+                // `alias.name args` is parsed as `name alias, args`, the only call where the first
+                // argument starts where the call does
+                let module = args.first()
+                    .filter(|first| first.span().start == span.start)
+                    .and_then(|first| self.alias(first));
+
+                if let Some(module) = module {
+                    *name = self.export(&module, name, *span);
+                    args.remove(0);
+                } else if !self.is_local(name) && self.scope.aliases.contains_key(name.as_str()) {
+                    self.errors.push(ModuleError::ModuleAsValue { name: name.to_string(), span: *span });
+                } else {
+                    self.rename(name);
+                }
+
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+            Expression::Reference { name, span } => {
+                if !self.is_local(name) && self.scope.aliases.contains_key(name.as_str()) {
+                    self.errors.push(ModuleError::ModuleAsValue { name: name.to_string(), span: *span });
+                } else {
+                    self.rename(name);
+                }
+            }
+            Expression::Property { value, name, span } => {
+                if let Some(module) = self.alias(value) {
+                    let name = self.export(&module, name, *span);
+                    *expr = Expression::FunCall { name, args: vec![], span: *span };
+                    return;
+                }
+
+                // `@alias.name` references an export
+                if let Expression::Reference { name: alias, .. } = &**value {
+                    if let Some(module) = self.scope.aliases.get(alias.as_str()).filter(|_| !self.is_local(alias)).cloned() {
+                        let name = self.export(&module, name, *span);
+                        *expr = Expression::Reference { name, span: *span };
+                        return;
+                    }
+                }
+
+                self.expression(value);
+                // `value.name` may be a call to a function of the module
+                self.rename(name);
+            }
+            Expression::Operator { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::UnaryOperator { expr, .. } => self.expression(expr),
+            Expression::List { items: values, .. } |
+            Expression::Tuple { values, .. } |
+            Expression::Interpolated { parts: values, .. } => {
+                for value in values {
+                    self.expression(value);
+                }
+            }
+            Expression::Lambda { args, code, .. } => self.lambda(args, code),
+            Expression::Return { value, .. } => self.expression(value),
+            Expression::Match { value, arms, .. } => {
+                self.expression(value);
+
+                for arm in arms {
+                    self.pattern(&mut arm.pattern);
+                    self.locals.push(arm.pattern.bindings().into_iter().map(str::to_string).collect());
+
+                    if let Some(guard) = &mut arm.guard {
+                        self.expression(guard);
+                    }
+                    self.expression(&mut arm.body);
+                    self.locals.pop();
+                }
+            }
+            Expression::Update { value, properties, .. } => {
+                self.expression(value);
+                for property in properties {
+                    self.expression(&mut property.value);
+                }
+            }
+            Expression::Int { .. } | Expression::BigInt { .. } | Expression::Float { .. } | Expression::String { .. } => {}
+        }
+    }
+
+    fn pattern(&mut self, pattern: &mut Pattern) {
+        match pattern {
+            Pattern::Variant { name, args, span } => {
+                // Variables don't hide variants, so the name is never local
+                let qualified = name.split_once('.')
+                    .and_then(|(alias, variant)| Some((self.scope.aliases.get(alias)?.clone(), variant.to_string())));
+
+                if let Some((module, variant)) = qualified {
+                    *name = self.export(&module, &variant, *span);
+                } else if let Some(global) = self.scope.names.get(name.as_str()) {
+                    *name = global.to_string();
+                }
+
+                for arg in args {
+                    self.pattern(arg);
+                }
+            }
+            Pattern::Tuple { items, .. } => {
+                for item in items {
+                    self.pattern(item);
+                }
+            }
+            Pattern::List { items, rest, .. } => {
+                for item in items {
+                    self.pattern(item);
+                }
+                if let Some(rest) = rest {
+                    self.pattern(rest);
+                }
+            }
+            Pattern::Wildcard { .. } | Pattern::Binding { .. } | Pattern::Int { .. } | Pattern::BigInt { .. } |
+            Pattern::Float { .. } | Pattern::String { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run::Value;
+    use crate::source::TempFiles;
+    use crate::testing::Pipeline;
+
+    use super::*;
+
+    /// Writes the files to a new directory, the first one is the main file. The directory is removed
+    /// when the returned guard is dropped.
+    fn write_files(name: &str, files: &[(&str, &str)]) -> (PathBuf, TempFiles) {
+        let dir = std::env::temp_dir().join(format!("demo_lang_modules_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        for (path, code) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, code).unwrap();
+        }
+        (dir.clone(), TempFiles(vec![dir]))
+    }
+
+    fn link(name: &str, files: &[(&str, &str)]) -> Result<LinkedProgram, Vec<ModuleError>> {
+        link_with(name, files).1
+    }
+
+    /// Like `link`, also returns the loader
+    fn link_with(name: &str, files: &[(&str, &str)]) -> (ModuleLoader, Result<LinkedProgram, Vec<ModuleError>>) {
+        let (dir, _files) = write_files(name, files);
+        let mut loader = ModuleLoader::new();
+        loader.add_search_path(dir.join("lib"));
+
+        let file = loader.sources.load(&dir.join(files[0].0).to_string_lossy()).unwrap();
+        let result = loader.load_main(file);
+        (loader, result)
+    }
+
+    fn run(name: &str, files: &[(&str, &str)]) -> Value {
+        let linked = link(name, files).expect("ModuleError");
+        let mut statements = linked.modules;
+        statements.extend(linked.program.statements);
+
+        Pipeline::new().run_program(Program { statements }).expect("RuntimeError")
+    }
+
+    #[test]
+    fn alias_import() {
+        let value = run("alias", &[
+            ("main.dl", "import \"utils.dl\" as utils\nx = utils.double 4\napply = { f, n | f n }\n(apply @utils.double, x) + utils.count + utils.base"),
+            ("utils.dl", "base = 10\ncount = 0\ndouble = { n | n * 2 }"),
+        ]);
+        assert!(matches!(value, Value::Int(26)), "{:?}", value);
+    }
+
+    #[test]
+    fn names_import() {
+        let value = run("names", &[
+            ("main.dl", "import { Maybe, unwrap } from \"maybe.dl\"\nunwrap (Some 3), 0"),
+            ("maybe.dl", "type Maybe = Some(value) | Nothing\nunwrap = { m, default | match m { Some(v) -> v\n Nothing -> default } }"),
+        ]);
+        assert!(matches!(value, Value::Int(3)), "{:?}", value);
+    }
+
+    #[test]
+    fn qualified_patterns() {
+        let value = run("patterns", &[
+            ("main.dl", "import \"maybe.dl\" as maybe\nmatch maybe.Some(5) { maybe.Some(v) -> v\n maybe.Nothing -> 0 }"),
+            ("maybe.dl", "type Maybe = Some(value) | Nothing"),
+        ]);
+        assert!(matches!(value, Value::Int(5)), "{:?}", value);
+    }
+
+    #[test]
+    fn modules_are_loaded_once() {
+        let (loader, linked) = link_with("once", &[
+            ("main.dl", "import \"a.dl\" as a\nimport \"b.dl\" as b\nimport \"a.dl\" as again\na.x"),
+            ("a.dl", "x = 1"),
+            ("b.dl", "import { x } from \"a.dl\"\ny = x"),
+        ]);
+        let linked = linked.expect("ModuleError");
+
+        // `a.dl` is read and cached once, and its top level code is linked once
+        let names: Vec<&str> = loader.sources.files().iter().map(|it| it.name.as_str()).collect();
+        assert_eq!(1, names.iter().filter(|it| it.ends_with("a.dl")).count(), "{:?}", names);
+        assert_eq!(2, loader.modules.len());
+
+        let definitions = linked.modules.iter()
+            .filter(|it| matches!(it, Statement::Variable(variable) if variable.name == "a.x"))
+            .count();
+        assert_eq!(1, definitions);
+    }
+
+    #[test]
+    fn search_path() {
+        let value = run("search", &[
+            ("main.dl", "import { square } from \"math.dl\"\nsquare 3"),
+            ("lib/math.dl", "square = { n | n * n }"),
+        ]);
+        assert!(matches!(value, Value::Int(9)), "{:?}", value);
+    }
+
+    #[test]
+    fn errors() {
+        let errors = link("cycle", &[
+            ("main.dl", "import \"a.dl\" as a"),
+            ("a.dl", "import \"b.dl\" as b"),
+            ("b.dl", "import \"a.dl\" as a"),
+        ]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::Cycle { modules, .. }] if modules.len() == 3), "{:?}", errors);
+
+        let errors = link("not_found", &[("main.dl", "import \"missing.dl\" as m")]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::NotFound { path, .. }] if path == "missing.dl"), "{:?}", errors);
+
+        let errors = link("unknown", &[
+            ("main.dl", "import { nope } from \"a.dl\""),
+            ("a.dl", "x = 1"),
+        ]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::UnknownExport { name, .. }] if name == "nope"), "{:?}", errors);
+
+        let errors = link("as_value", &[
+            ("main.dl", "import \"a.dl\" as a\nprintln a"),
+            ("a.dl", "x = 1"),
+        ]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::ModuleAsValue { name, .. }] if name == "a"), "{:?}", errors);
+    }
+
+    #[test]
+    fn imported_names_are_not_assigned() {
+        let utils = ("utils.dl", "counter = 0\nbump = { counter = counter + 1 }\ntype Maybe = Some(value) | Nothing");
+
+        let errors = link("assign_import", &[("main.dl", "import { counter, bump } from \"utils.dl\"\ncounter = 5"), utils]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::AssignsImport { name, span, import }]
+            if name == "counter" && span.start.line == 2 && import.start.line == 1), "{:?}", errors);
+
+        // Also when the definition comes first, or inside a lambda
+        let errors = link("define_import", &[("main.dl", "counter = 1\nimport { counter } from \"utils.dl\""), utils]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::AssignsImport { name, .. }] if name == "counter"), "{:?}", errors);
+
+        let errors = link("lambda_import", &[("main.dl", "import { counter } from \"utils.dl\"\nreset = { counter = 0 }"), utils]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::AssignsImport { name, .. }] if name == "counter"), "{:?}", errors);
+
+        let errors = link("variant_import", &[("main.dl", "import { Maybe } from \"utils.dl\"\ntype Option = Some(v) | None"), utils]).unwrap_err();
+        assert!(matches!(&errors[..], [ModuleError::AssignsImport { name, .. }] if name == "Some"), "{:?}", errors);
+
+        // Arguments and pattern variables may still reuse the name
+        let value = run("shadow_import", &[("main.dl", "import { counter, bump } from \"utils.dl\"\nbump\nf = { counter | counter = counter * 10\n  counter }\n(f 2) + counter"), utils]);
+        assert!(matches!(value, Value::Int(21)), "{:?}", value);
+    }
+}
@@ -0,0 +1,415 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+
+use crate::run::Value;
+use crate::runtime::{Overflow, Runtime, RuntimeError};
+
+/// Registers the functions the compiler calls for binary operators.
+///
+/// Arithmetic on two `Int`s stays an `Int` and fails on division by zero, results that don't fit in
+/// 64 bits become big integers or fail depending on `Runtime::overflow`. If any side is a `Float` both
+/// are converted to `Float` and follow IEEE 754. `+` also concatenates `String`s and
+/// `List`s. Comparisons work on numbers, strings, lists and tuples (element by element) and
+/// instances of the same type (by variant, then by properties). Equality works on any pair of
+/// values: `Int`s, big integers and `Float`s compare by their numeric value, so `1 == 1.0`, and any
+/// other mix of types is unequal.
+pub fn register_operators(runtime: &mut Runtime) {
+    runtime.register_func("+", 2, |rt, args| arithmetic(rt, "+", args));
+    runtime.register_func("-", 2, |rt, args| arithmetic(rt, "-", args));
+    runtime.register_func("*", 2, |rt, args| arithmetic(rt, "*", args));
+    runtime.register_func("/", 2, |rt, args| arithmetic(rt, "/", args));
+    runtime.register_func("%", 2, |rt, args| arithmetic(rt, "%", args));
+
+    runtime.register_func("<", 2, |rt, args| compare(rt, "<", args, Ordering::is_lt));
+    runtime.register_func(">", 2, |rt, args| compare(rt, ">", args, Ordering::is_gt));
+    runtime.register_func("<=", 2, |rt, args| compare(rt, "<=", args, Ordering::is_le));
+    runtime.register_func(">=", 2, |rt, args| compare(rt, ">=", args, Ordering::is_ge));
+
+    runtime.register_func("==", 2, |rt, args| {
+        let (left, right) = operands(args);
+        Ok(rt.boolean(values_equal(&left, &right)))
+    });
+
+    runtime.register_func("!=", 2, |rt, args| {
+        let (left, right) = operands(args);
+        Ok(rt.boolean(!values_equal(&left, &right)))
+    });
+
+    runtime.register_func("&", 2, |rt, args| bitwise(rt, "&", args));
+    runtime.register_func("|", 2, |rt, args| bitwise(rt, "|", args));
+    runtime.register_func("^", 2, |rt, args| bitwise(rt, "^", args));
+
+    runtime.register_func("&&", 2, |rt, args| {
+        let (left, right) = operands(args);
+        logical(rt, "&&", left, right)
+    });
+
+    runtime.register_func("||", 2, |rt, args| {
+        let (left, right) = operands(args);
+        logical(rt, "||", left, right)
+    });
+}
+
+/// Structural equality, `Int`s and `Float`s are compared by value and functions by identity
+pub fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Unit, Value::Unit) => true,
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::Int(_) | Value::BigInt(_), Value::Int(_) | Value::BigInt(_)) => as_bigint(left) == as_bigint(right),
+        (Value::Int(_) | Value::BigInt(_) | Value::Float(_), Value::Int(_) | Value::BigInt(_) | Value::Float(_)) => {
+            compare_numbers(left, right) == Some(Ordering::Equal)
+        }
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::List(a), Value::List(b)) | (Value::Tuple(a), Value::Tuple(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| values_equal(a, b))
+        }
+        (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+        (Value::Instance(a), Value::Instance(b)) => {
+            a.class == b.class && a.properties.iter().zip(&b.properties).all(|(a, b)| values_equal(a, b))
+        }
+        _ => false,
+    }
+}
+
+/// Orders two values, `None` if they can't be ordered but it's not an error, like `NaN`
+pub fn compare_values(rt: &Runtime, op: &str, left: &Value, right: &Value) -> Result<Option<Ordering>, RuntimeError> {
+    let ordering = match (left, right) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Int(_) | Value::BigInt(_), Value::Int(_) | Value::BigInt(_)) => Some(as_bigint(left).cmp(&as_bigint(right))),
+        (Value::Int(_) | Value::BigInt(_) | Value::Float(_), Value::Int(_) | Value::BigInt(_) | Value::Float(_)) => {
+            compare_numbers(left, right)
+        }
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::List(a), Value::List(b)) => compare_all(rt, op, a, b)?,
+        (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => compare_all(rt, op, a, b)?,
+        (Value::Instance(a), Value::Instance(b)) => {
+            let (class_a, class_b) = match (rt.get_class_by_id(a.class), rt.get_class_by_id(b.class)) {
+                (Some(class_a), Some(class_b)) if Rc::ptr_eq(&class_a.typedef, &class_b.typedef) => (class_a, class_b),
+                _ => return Err(invalid_operands(rt, op, left, right)),
+            };
+
+            let variants = &class_a.typedef.variants;
+            let index_a = variants.iter().position(|it| it.name == class_a.variant);
+            let index_b = variants.iter().position(|it| it.name == class_b.variant);
+
+            match index_a.cmp(&index_b) {
+                Ordering::Equal => compare_all(rt, op, &a.properties, &b.properties)?,
+                other => Some(other),
+            }
+        }
+        _ => return Err(invalid_operands(rt, op, left, right)),
+    };
+
+    Ok(ordering)
+}
+
+/// Exact order of two numbers where at least one is a `Float`, integers are not rounded to the nearest
+/// `Float`, so `9007199254740993 > 9007199254740992.0`
+fn compare_numbers(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+        (Value::Float(_), _) => compare_numbers(right, left).map(Ordering::reverse),
+        (_, Value::Float(b)) => {
+            let int = as_bigint(left);
+
+            if b.is_nan() {
+                None
+            } else if b.is_infinite() {
+                Some(if *b > 0.0 { Ordering::Less } else { Ordering::Greater })
+            } else if b.fract() == 0.0 {
+                Some(int.cmp(&BigInt::from_f64(*b)?))
+            } else if int <= BigInt::from_f64(b.floor())? {
+                Some(Ordering::Less)
+            } else {
+                Some(Ordering::Greater)
+            }
+        }
+        _ => Some(as_bigint(left).cmp(&as_bigint(right))),
+    }
+}
+
+/// Lexicographic order of two sequences of values
+fn compare_all(rt: &Runtime, op: &str, left: &[Value], right: &[Value]) -> Result<Option<Ordering>, RuntimeError> {
+    for (a, b) in left.iter().zip(right) {
+        match compare_values(rt, op, a, b)? {
+            Some(Ordering::Equal) => {}
+            other => return Ok(other),
+        }
+    }
+
+    Ok(Some(left.len().cmp(&right.len())))
+}
+
+fn arithmetic(rt: &mut Runtime, op: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (left, right) = operands(args);
+
+    match (&left, &right) {
+        (Value::Int(a), Value::Int(b)) => {
+            match int_arithmetic(op, *a, *b)? {
+                Some(result) => Ok(Value::Int(result)),
+                None => int_result(rt, op, big_arithmetic(op, BigInt::from(*a), BigInt::from(*b))?),
+            }
+        }
+        (Value::Int(_) | Value::BigInt(_), Value::Int(_) | Value::BigInt(_)) => {
+            int_result(rt, op, big_arithmetic(op, as_bigint(&left), as_bigint(&right))?)
+        }
+        (Value::Int(_) | Value::BigInt(_) | Value::Float(_), Value::Int(_) | Value::BigInt(_) | Value::Float(_)) => {
+            Ok(Value::Float(float_arithmetic(op, as_f64(&left), as_f64(&right))))
+        }
+        (Value::String(a), Value::String(b)) if op == "+" => Ok(Value::String(format!("{}{}", a, b))),
+        (Value::List(a), Value::List(b)) if op == "+" => Ok(Value::List(a.iter().chain(b).cloned().collect())),
+        _ => Err(invalid_operands(rt, op, &left, &right)),
+    }
+}
+
+/// `None` if the result doesn't fit in an `i64`
+fn int_arithmetic(op: &str, a: i64, b: i64) -> Result<Option<i64>, RuntimeError> {
+    if (op == "/" || op == "%") && b == 0 {
+        return Err(RuntimeError::DivisionByZero);
+    }
+
+    let result = match op {
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" => a.checked_div(b),
+        _ => a.checked_rem(b),
+    };
+
+    Ok(result)
+}
+
+/// Same as `int_arithmetic` without size limits, `/` and `%` also truncate towards zero
+fn big_arithmetic(op: &str, a: BigInt, b: BigInt) -> Result<BigInt, RuntimeError> {
+    if (op == "/" || op == "%") && b.is_zero() {
+        return Err(RuntimeError::DivisionByZero);
+    }
+
+    let result = match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        _ => a % b,
+    };
+
+    Ok(result)
+}
+
+/// Value of an integer computed by `op`, an `Int` if it fits, otherwise a big integer or an error
+/// depending on the overflow mode of the runtime
+pub fn int_result(rt: &Runtime, op: &str, value: BigInt) -> Result<Value, RuntimeError> {
+    match value.to_i64() {
+        Some(value) => Ok(Value::Int(value)),
+        None if rt.overflow() == Overflow::Promote => Ok(Value::BigInt(value)),
+        None => Err(RuntimeError::IntegerOverflow { operator: op.to_string() }),
+    }
+}
+
+fn float_arithmetic(op: &str, a: f64, b: f64) -> f64 {
+    match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        _ => a % b,
+    }
+}
+
+fn compare(rt: &mut Runtime, op: &str, args: Vec<Value>, test: fn(Ordering) -> bool) -> Result<Value, RuntimeError> {
+    let (left, right) = operands(args);
+    let ordering = compare_values(rt, op, &left, &right)?;
+
+    Ok(rt.boolean(ordering.map(test).unwrap_or(false)))
+}
+
+/// Bitwise operation on `Int`s, or the logical one on booleans
+fn bitwise(rt: &mut Runtime, op: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (left, right) = operands(args);
+
+    match (&left, &right) {
+        (Value::Int(a), Value::Int(b)) => {
+            let result = match op {
+                "&" => a & b,
+                "|" => a | b,
+                _ => a ^ b,
+            };
+            Ok(Value::Int(result))
+        }
+        (Value::Int(_) | Value::BigInt(_), Value::Int(_) | Value::BigInt(_)) => {
+            let (a, b) = (as_bigint(&left), as_bigint(&right));
+            let result = match op {
+                "&" => a & b,
+                "|" => a | b,
+                _ => a ^ b,
+            };
+            int_result(rt, op, result)
+        }
+        _ => logical(rt, op, left, right),
+    }
+}
+
+/// Both sides are always evaluated, there is no short-circuit
+fn logical(rt: &mut Runtime, op: &str, left: Value, right: Value) -> Result<Value, RuntimeError> {
+    let (a, b) = match (rt.as_boolean(&left), rt.as_boolean(&right)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err(invalid_operands(rt, op, &left, &right)),
+    };
+
+    let result = match op {
+        "&&" | "&" => a && b,
+        "||" | "|" => a || b,
+        _ => a != b,
+    };
+
+    Ok(rt.boolean(result))
+}
+
+fn operands(args: Vec<Value>) -> (Value, Value) {
+    let mut iter = args.into_iter();
+    (iter.next().unwrap(), iter.next().unwrap())
+}
+
+fn as_f64(value: &Value) -> f64 {
+    match value {
+        Value::Int(value) => *value as f64,
+        Value::BigInt(value) => value.to_f64().unwrap_or(f64::NAN),
+        Value::Float(value) => *value,
+        _ => f64::NAN,
+    }
+}
+
+fn as_bigint(value: &Value) -> BigInt {
+    match value {
+        Value::Int(value) => BigInt::from(*value),
+        Value::BigInt(value) => value.clone(),
+        _ => BigInt::zero(),
+    }
+}
+
+fn invalid_operands(rt: &Runtime, op: &str, left: &Value, right: &Value) -> RuntimeError {
+    RuntimeError::InvalidOperands {
+        operator: op.to_string(),
+        left: rt.type_name(left),
+        right: rt.type_name(right),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{run as eval, Pipeline};
+
+    use super::*;
+
+    fn eval_bool(code: &str) -> bool {
+        Pipeline::new().run_bool(code)
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert!(matches!(eval("test = 10\ntest + 20 / 3"), Ok(Value::Int(16))));
+        assert!(matches!(eval("7 % 3 - 2 * 3"), Ok(Value::Int(-5))));
+        assert!(matches!(eval("1 + 0.5"), Ok(Value::Float(value)) if value == 1.5));
+        assert!(matches!(eval("1.0 / 0"), Ok(Value::Float(value)) if value.is_infinite()));
+        assert!(matches!(eval("\"ab\" + \"cd\""), Ok(Value::String(value)) if value == "abcd"));
+        assert!(matches!(eval("[1] + [2, 3]"), Ok(Value::List(values)) if values.len() == 3));
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        assert!(matches!(eval("1 / 0").unwrap_err().kind(), RuntimeError::DivisionByZero));
+
+        let error = eval("1 + \"a\"").unwrap_err();
+        assert_eq!("cannot apply `+` to `Int` and `String`", error.to_string());
+
+        let error = eval("[1] - [1]").unwrap_err();
+        assert_eq!("cannot apply `-` to `List` and `List`", error.to_string());
+    }
+
+    #[test]
+    fn big_integers() {
+        assert!(matches!(eval("2147483647 + 1"), Ok(Value::Int(2147483648))));
+        assert!(matches!(eval("9223372036854775807 + 1"), Ok(Value::BigInt(value)) if value.to_string() == "9223372036854775808"));
+        assert!(matches!(eval("-9223372036854775807 - 1"), Ok(Value::Int(i64::MIN))));
+        assert!(matches!(eval("(9223372036854775807 + 1) - 1"), Ok(Value::Int(i64::MAX))));
+        assert!(matches!(eval("-(-9223372036854775807 - 1)"), Ok(Value::BigInt(_))));
+        assert!(matches!(eval("(-9223372036854775807 - 1) / -1"), Ok(Value::BigInt(_))));
+        assert!(matches!(eval("0xFFFFFFFFFFFFFFFF & 0xFF"), Ok(Value::Int(255))));
+        assert!(matches!(eval("18446744073709551616 * 2 / 4 % 7"), Ok(Value::Int(1))));
+        assert!(matches!(eval("18446744073709551616 / 0").unwrap_err().kind(), RuntimeError::DivisionByZero));
+
+        assert!(eval_bool("9223372036854775808 > 9223372036854775807"));
+        assert!(eval_bool("9223372036854775808 == 9223372036854775807 + 1"));
+        assert!(eval_bool("9223372036854775808 == 9223372036854775808.0"));
+        assert!(eval_bool("-18446744073709551616 < 0.5"));
+
+        // 2^53 + 1 has no exact `Float`, converting it would round it to 2^53
+        assert!(!eval_bool("9007199254740993 == 9007199254740992.0"));
+        assert!(eval_bool("9007199254740993 > 9007199254740992.0"));
+        assert!(eval_bool("9007199254740992.0 < 9007199254740993"));
+        assert!(eval_bool("9007199254740992 == 9007199254740992.0"));
+        assert!(eval_bool("2 > 1.5 && -2 < -1.5 && 1 < 1.0 / 0"));
+
+        let mut pipeline = Pipeline::new();
+        let value = pipeline.run("99999999999999999999 * 99999999999999999999").unwrap();
+        assert_eq!("9999999999999999999800000000000000000001", pipeline.runtime.display_value(&value));
+        assert_eq!("Int", pipeline.runtime.type_name(&value));
+    }
+
+    #[test]
+    fn overflow_errors() {
+        let eval_checked = |code: &str| {
+            let mut pipeline = Pipeline::new();
+            pipeline.runtime.set_overflow(Overflow::Error);
+            pipeline.run(code)
+        };
+
+        assert!(matches!(eval_checked("9223372036854775807 + 1").unwrap_err().kind(), RuntimeError::IntegerOverflow { .. }));
+        assert!(matches!(eval_checked("-(-9223372036854775807 - 1)").unwrap_err().kind(), RuntimeError::IntegerOverflow { .. }));
+        assert!(matches!(eval_checked("3037000500 * 3037000500").unwrap_err().kind(), RuntimeError::IntegerOverflow { .. }));
+        assert!(matches!(eval_checked("9223372036854775808 - 1"), Ok(Value::Int(i64::MAX))));
+
+        let error = eval_checked("1 + 9223372036854775807").unwrap_err();
+        assert_eq!("integer overflow in `+`", error.to_string());
+    }
+
+    #[test]
+    fn comparison() {
+        assert!(eval_bool("1 < 2"));
+        assert!(eval_bool("2 >= 1.5"));
+        assert!(eval_bool("\"abc\" < \"abd\""));
+        assert!(eval_bool("[1, 2] < [1, 2, 0]"));
+        assert!(eval_bool("(1, \"b\") > (1, \"a\")"));
+        assert!(eval_bool("type Size = Small(n) | Big(n)\n(Small 10) < (Big 1)"));
+        assert!(eval_bool("type Size = Small(n) | Big(n)\n(Big 1) < (Big 2)"));
+
+        let error = eval("(1, 2) < (1, 2, 3)").unwrap_err();
+        assert_eq!("cannot apply `<` to `(Int, Int)` and `(Int, Int, Int)`", error.to_string());
+        assert!(matches!(eval("[1] < [\"a\"]").unwrap_err().kind(), RuntimeError::InvalidOperands { .. }));
+    }
+
+    #[test]
+    fn equality() {
+        assert!(eval_bool("1 == 1.0"));
+        assert!(eval_bool("[1, (2, \"a\")] == [1, (2, \"a\")]"));
+        assert!(eval_bool("1 != \"1\""));
+        assert!(eval_bool("type Color = Red | Green\nRed != Green"));
+        assert!(!eval_bool("[1, 2] == [1]"));
+    }
+
+    #[test]
+    fn logic() {
+        assert!(eval_bool("True && !False"));
+        assert!(eval_bool("False || 1 < 2"));
+        assert!(eval_bool("True ^ False"));
+        assert!(matches!(eval("6 & 3 | 8"), Ok(Value::Int(10))));
+        assert!(matches!(eval("!0"), Ok(Value::Int(-1))));
+
+        let error = eval("True && 1").unwrap_err();
+        assert_eq!("cannot apply `&&` to `Boolean` and `Int`", error.to_string());
+        assert!(matches!(eval("!\"a\"").unwrap_err().kind(), RuntimeError::InvalidOperand { .. }));
+    }
+}
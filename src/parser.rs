@@ -1,7 +1,10 @@
 use std::collections::VecDeque;
 
-use crate::ast::{Expression, Operator, Program, Statement, TypeDef, TypeDefVariant, UnaryOperator, Variable};
-use crate::source::Span;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use crate::ast::{Expression, Import, ImportNames, MatchArm, Operator, Pattern, Program, PropertyUpdate, Statement, TypeDef, TypeDefVariant, UnaryOperator, Variable};
+use crate::source::{FileId, SourceSpan, Span};
 use crate::tokenizer::{Token, Tokenizer, TokenSpan};
 
 #[derive(Debug, Clone)]
@@ -9,22 +12,58 @@ pub enum ParseError {
     Expected { expected: Token, found: Token, span: TokenSpan },
     ExpectedId { found: Token, span: TokenSpan },
     UnexpectedToken(Token, TokenSpan),
-    EOF,
+    Eof { open: Token, span: TokenSpan },
 }
 
 pub struct Parser {
     tk: Tokenizer,
     lookahead: VecDeque<(Token, TokenSpan)>,
     eof: Token,
+    file: FileId,
+    last_span: TokenSpan,
+    consumed: usize,
+    errors: Vec<ParseError>,
+    /// Set while parsing the value of a `match`, so the `{` of the arms is not taken as a lambda argument
+    no_lambda_args: bool,
 }
 
 impl Parser {
     pub fn new(tk: Tokenizer) -> Self {
-        Self { tk, lookahead: VecDeque::new(), eof: Token::Eof }
+        Self::with_file(tk, 0)
+    }
+
+    /// Creates a parser that tags the spans of the syntax tree with the given file
+    pub fn with_file(tk: Tokenizer, file: FileId) -> Self {
+        let start = Span { line: 1, column: 1 };
+
+        Self {
+            tk,
+            lookahead: VecDeque::new(),
+            eof: Token::Eof,
+            file,
+            last_span: (start, start),
+            consumed: 0,
+            errors: vec![],
+            no_lambda_args: false,
+        }
+    }
+
+    /// Parses the whole input, failing with every syntax error found
+    pub fn parse_program(&mut self) -> Result<Program, Vec<ParseError>> {
+        let (program, errors) = self.parse_program_partial();
+
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
     }
 
-    pub fn parse_program(&mut self) -> Result<Program, ParseError> {
-        parse_program(self)
+    /// Parses the whole input recovering from syntax errors, statements that failed to parse are
+    /// replaced by `Statement::Error` so the rest of the program can still be analyzed
+    pub fn parse_program_partial(&mut self) -> (Program, Vec<ParseError>) {
+        let program = parse_program(self);
+        (program, std::mem::take(&mut self.errors))
     }
 
     fn current(&mut self) -> &Token {
@@ -32,7 +71,7 @@ impl Parser {
             self.lookahead.push_back(self.tk.next());
         }
 
-        self.lookahead.get(0)
+        self.lookahead.front()
             .map(|it| &it.0)
             .unwrap_or(&self.eof)
     }
@@ -42,8 +81,12 @@ impl Parser {
             self.lookahead.push_back(self.tk.next());
         }
 
-        self.lookahead.pop_front()
-            .unwrap_or((Token::Eof, (Span { line: 0, column: 0 }, Span { line: 0, column: 0 })))
+        let (tk, span) = self.lookahead.pop_front()
+            .unwrap_or((Token::Eof, (Span { line: 0, column: 0 }, Span { line: 0, column: 0 })));
+
+        self.last_span = span;
+        self.consumed += 1;
+        (tk, span)
     }
 
     fn current_pos(&mut self) -> TokenSpan {
@@ -51,11 +94,19 @@ impl Parser {
             self.lookahead.push_back(self.tk.next());
         }
 
-        self.lookahead.get(0)
-            .map(|it| it.1.clone())
+        self.lookahead.front()
+            .map(|it| it.1)
             .unwrap_or((Span { line: 0, column: 0 }, Span { line: 0, column: 0 }))
     }
 
+    fn pos_at(&mut self, offset: usize) -> TokenSpan {
+        while self.lookahead.len() <= offset {
+            self.lookahead.push_back(self.tk.next());
+        }
+
+        self.lookahead[offset].1
+    }
+
     fn at(&mut self, offset: i32) -> &Token {
         let index = offset as usize;
 
@@ -69,9 +120,22 @@ impl Parser {
     }
 
     fn next(&mut self) {
-        if !self.lookahead.is_empty() {
-            self.lookahead.pop_front();
-        }
+        self.pop();
+    }
+
+    /// Start of the current token
+    fn start(&mut self) -> Span {
+        self.current_pos().0
+    }
+
+    /// Span from `start` to the end of the last consumed token
+    fn span_from(&self, start: Span) -> SourceSpan {
+        SourceSpan::new(self.file, start, self.last_span.1)
+    }
+
+    /// Checks if the current token starts in a different line than the end of the previous one
+    fn on_new_line(&mut self) -> bool {
+        self.current_pos().0.line > self.last_span.1.line
     }
 
     fn expect(&mut self, tk: Token) -> Result<(), ParseError> {
@@ -107,15 +171,69 @@ impl Parser {
     }
 }
 
-pub fn parse_program(p: &mut Parser) -> Result<Program, ParseError> {
+pub fn parse_program(p: &mut Parser) -> Program {
     let mut statements = vec![];
 
     while p.current() != &Token::Eof {
-        statements.push(parse_statement(p)?);
+        statements.push(parse_statement_or_recover(p, None));
         p.skip(Token::Semicolon);
     }
 
-    Ok(Program { statements })
+    Program { statements }
+}
+
+/// Parses a statement, in case of error the error is recorded and the parser skips tokens until the
+/// start of the next statement, `closing` is the delimiter that ends the enclosing block, if any
+fn parse_statement_or_recover(p: &mut Parser, closing: Option<&Token>) -> Statement {
+    let start = p.consumed;
+    let start_pos = p.start();
+
+    match parse_statement(p) {
+        Ok(stm) => stm,
+        Err(error) => {
+            p.errors.push(error);
+
+            // Make progress even if the statement failed at its first token
+            if p.consumed == start && p.current() != &Token::Eof && Some(p.current()) != closing {
+                p.next();
+            }
+
+            synchronize(p, closing);
+            Statement::Error(p.span_from(start_pos))
+        }
+    }
+}
+
+/// Panic-mode recovery: skips tokens until a `;`, a token at the start of a new line or the closing
+/// delimiter of the enclosing block, ignoring anything nested inside other delimiters
+fn synchronize(p: &mut Parser, closing: Option<&Token>) {
+    let mut depth = 0;
+
+    loop {
+        if p.current() == &Token::Eof {
+            return;
+        }
+
+        if depth == 0 {
+            if p.current() == &Token::Semicolon {
+                p.next();
+                return;
+            }
+
+            if Some(p.current()) == closing || p.on_new_line() {
+                return;
+            }
+        }
+
+        match p.current() {
+            Token::LeftParen | Token::LeftBrace | Token::LeftBracket => depth += 1,
+            // Unbalanced closing delimiters that don't end the current block are just skipped
+            Token::RightParen | Token::RightBrace | Token::RightBracket if depth > 0 => depth -= 1,
+            _ => {}
+        }
+
+        p.next();
+    }
 }
 
 pub fn parse_statement(p: &mut Parser) -> Result<Statement, ParseError> {
@@ -125,26 +243,32 @@ pub fn parse_statement(p: &mut Parser) -> Result<Statement, ParseError> {
 
     if let Token::Identifier(_) = p.at(0) {
         if let Token::Assign = p.at(1) {
-            return parse_variable(p).map(|i| Statement::Variable(i));
+            return parse_variable(p).map(Statement::Variable);
         }
     }
 
     if let Token::Typedef = p.at(0) {
-        return parse_typedef(p).map(|i| Statement::TypeDef(i));
+        return parse_typedef(p).map(Statement::TypeDef);
     }
 
-    parse_expression(p).map(|i| Statement::Expression(i))
+    if let Token::Import = p.at(0) {
+        return parse_import(p).map(Statement::Import);
+    }
+
+    parse_expression(p).map(Statement::Expression)
 }
 
 pub fn parse_variable(p: &mut Parser) -> Result<Variable, ParseError> {
+    let start = p.start();
     let name = p.expect_id()?;
     p.expect(Token::Assign)?;
     let value = parse_expression(p)?;
 
-    Ok(Variable { name, value })
+    Ok(Variable { name, value, span: p.span_from(start) })
 }
 
 pub fn parse_typedef(p: &mut Parser) -> Result<TypeDef, ParseError> {
+    let start = p.start();
     p.expect(Token::Typedef)?;
     let name = p.expect_id()?;
     p.expect(Token::Assign)?;
@@ -160,10 +284,59 @@ pub fn parse_typedef(p: &mut Parser) -> Result<TypeDef, ParseError> {
         p.expect(Token::Pipe)?;
     }
 
-    Ok(TypeDef { name, variants })
+    Ok(TypeDef { name, variants, span: p.span_from(start) })
+}
+
+/// `import "path" as name` or `import { a, b } from "path"`, `as` and `from` are not keywords
+pub fn parse_import(p: &mut Parser) -> Result<Import, ParseError> {
+    let start = p.start();
+    p.expect(Token::Import)?;
+
+    if p.skip(Token::LeftBrace) {
+        let mut names = vec![];
+
+        while p.current() != &Token::RightBrace {
+            let name_start = p.start();
+            let name = p.expect_id()?;
+            names.push((name, p.span_from(name_start)));
+
+            if !p.skip(Token::Comma) {
+                break;
+            }
+        }
+        p.expect(Token::RightBrace)?;
+        expect_word(p, "from")?;
+        let path = expect_path(p)?;
+
+        return Ok(Import { path, names: ImportNames::Names(names), span: p.span_from(start) });
+    }
+
+    let path = expect_path(p)?;
+    expect_word(p, "as")?;
+    let alias = p.expect_id()?;
+
+    Ok(Import { path, names: ImportNames::Alias(alias), span: p.span_from(start) })
+}
+
+fn expect_word(p: &mut Parser, word: &str) -> Result<(), ParseError> {
+    match p.current() {
+        Token::Identifier(name) if name == word => {
+            p.next();
+            Ok(())
+        }
+        _ => p.expect(Token::Identifier(word.to_string())),
+    }
+}
+
+fn expect_path(p: &mut Parser) -> Result<String, ParseError> {
+    match p.pop() {
+        (Token::StringLiteral(path), _) => Ok(path),
+        (found, span) => Err(ParseError::Expected { expected: Token::StringLiteral("path".to_string()), found, span }),
+    }
 }
 
 pub fn parse_typedef_variant(p: &mut Parser) -> Result<TypeDefVariant, ParseError> {
+    let start = p.start();
     let name = p.expect_id()?;
     let mut properties = vec![];
 
@@ -190,7 +363,7 @@ pub fn parse_typedef_variant(p: &mut Parser) -> Result<TypeDefVariant, ParseErro
         }
     }
 
-    Ok(TypeDefVariant { name, properties })
+    Ok(TypeDefVariant { name, properties, span: p.span_from(start) })
 }
 
 pub fn parse_expression(p: &mut Parser) -> Result<Expression, ParseError> {
@@ -198,6 +371,7 @@ pub fn parse_expression(p: &mut Parser) -> Result<Expression, ParseError> {
 }
 
 pub fn parse_expression_6(p: &mut Parser) -> Result<Expression, ParseError> {
+    let start = p.start();
     let mut expr = parse_expression_5(p)?;
     loop {
         let op = match p.current() {
@@ -214,6 +388,7 @@ pub fn parse_expression_6(p: &mut Parser) -> Result<Expression, ParseError> {
             operator: op,
             left: Box::new(expr),
             right: Box::new(right),
+            span: p.span_from(start),
         };
     }
 
@@ -221,6 +396,7 @@ pub fn parse_expression_6(p: &mut Parser) -> Result<Expression, ParseError> {
 }
 
 pub fn parse_expression_5(p: &mut Parser) -> Result<Expression, ParseError> {
+    let start = p.start();
     let mut expr = parse_expression_4(p)?;
     loop {
         let op = match p.current() {
@@ -236,6 +412,7 @@ pub fn parse_expression_5(p: &mut Parser) -> Result<Expression, ParseError> {
             operator: op,
             left: Box::new(expr),
             right: Box::new(right),
+            span: p.span_from(start),
         };
     }
 
@@ -243,6 +420,7 @@ pub fn parse_expression_5(p: &mut Parser) -> Result<Expression, ParseError> {
 }
 
 pub fn parse_expression_4(p: &mut Parser) -> Result<Expression, ParseError> {
+    let start = p.start();
     let mut expr = parse_expression_3(p)?;
     loop {
         let op = match p.current() {
@@ -260,6 +438,7 @@ pub fn parse_expression_4(p: &mut Parser) -> Result<Expression, ParseError> {
             operator: op,
             left: Box::new(expr),
             right: Box::new(right),
+            span: p.span_from(start),
         };
     }
 
@@ -267,6 +446,7 @@ pub fn parse_expression_4(p: &mut Parser) -> Result<Expression, ParseError> {
 }
 
 pub fn parse_expression_3(p: &mut Parser) -> Result<Expression, ParseError> {
+    let start = p.start();
     let mut expr = parse_expression_2(p)?;
     loop {
         let op = match p.current() {
@@ -275,6 +455,9 @@ pub fn parse_expression_3(p: &mut Parser) -> Result<Expression, ParseError> {
             _ => { break; }
         };
 
+        // A line starting with `-1` is a new statement or match arm, not a subtraction
+        if p.on_new_line() && sign_glued_to_next(p) { break; }
+
         p.next();
         let right = parse_expression_2(p)?;
 
@@ -282,6 +465,7 @@ pub fn parse_expression_3(p: &mut Parser) -> Result<Expression, ParseError> {
             operator: op,
             left: Box::new(expr),
             right: Box::new(right),
+            span: p.span_from(start),
         };
     }
 
@@ -289,6 +473,7 @@ pub fn parse_expression_3(p: &mut Parser) -> Result<Expression, ParseError> {
 }
 
 pub fn parse_expression_2(p: &mut Parser) -> Result<Expression, ParseError> {
+    let start = p.start();
     let mut expr = parse_expression_1(p)?;
     loop {
         let op = match p.current() {
@@ -305,6 +490,7 @@ pub fn parse_expression_2(p: &mut Parser) -> Result<Expression, ParseError> {
             operator: op,
             left: Box::new(expr),
             right: Box::new(right),
+            span: p.span_from(start),
         };
     }
 
@@ -312,6 +498,7 @@ pub fn parse_expression_2(p: &mut Parser) -> Result<Expression, ParseError> {
 }
 
 pub fn parse_expression_1(p: &mut Parser) -> Result<Expression, ParseError> {
+    let start = p.start();
     let mut expr = parse_expression_0(p)?;
     loop {
         let op = match p.current() {
@@ -327,6 +514,7 @@ pub fn parse_expression_1(p: &mut Parser) -> Result<Expression, ParseError> {
             operator: op,
             left: Box::new(expr),
             right: Box::new(right),
+            span: p.span_from(start),
         };
     }
 
@@ -334,8 +522,15 @@ pub fn parse_expression_1(p: &mut Parser) -> Result<Expression, ParseError> {
 }
 
 pub fn parse_expression_0(p: &mut Parser) -> Result<Expression, ParseError> {
+    let start = p.start();
     let mut expr = parse_expression_base(p)?;
     loop {
+        if p.skip(Token::With) {
+            let properties = parse_property_updates(p)?;
+            expr = Expression::Update { value: Box::new(expr), properties, span: p.span_from(start) };
+            continue;
+        }
+
         if !p.skip(Token::Dot) {
             break;
         }
@@ -343,56 +538,163 @@ pub fn parse_expression_0(p: &mut Parser) -> Result<Expression, ParseError> {
         let mut args = vec![expr];
         let name = p.expect_id()?;
 
-        while p.current() != &Token::Dot && p.current() != &Token::Eof && expression_first(p) {
+        while p.current() != &Token::Dot && p.current() != &Token::Eof && argument_first(p) {
+            // A new line starts a new statement
+            if p.on_new_line() { break; }
             args.push(parse_expression(p)?);
         }
 
-        expr = Expression::FunCall { name, args };
+        expr = if args.len() == 1 {
+            Expression::Property { value: Box::new(args.pop().unwrap()), name, span: p.span_from(start) }
+        } else {
+            Expression::FunCall { name, args, span: p.span_from(start) }
+        };
     }
 
     Ok(expr)
 }
 
+/// `{ name = value, other = value }` after `with`, commas are optional between lines
+fn parse_property_updates(p: &mut Parser) -> Result<Vec<PropertyUpdate>, ParseError> {
+    let previous = std::mem::replace(&mut p.no_lambda_args, false);
+    let properties = parse_property_updates_inner(p);
+    p.no_lambda_args = previous;
+    properties
+}
+
+fn parse_property_updates_inner(p: &mut Parser) -> Result<Vec<PropertyUpdate>, ParseError> {
+    let open = p.current_pos();
+    p.expect(Token::LeftBrace)?;
+    let mut properties = vec![];
+
+    while p.current() != &Token::RightBrace {
+        if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftBrace, span: open }); }
+
+        let start = p.start();
+        let name = p.expect_id()?;
+        p.expect(Token::Assign)?;
+        let value = parse_expression(p)?;
+
+        properties.push(PropertyUpdate { name, value, span: p.span_from(start) });
+        p.skip(Token::Comma);
+    }
+    p.next();
+
+    Ok(properties)
+}
+
 pub fn parse_expression_base(p: &mut Parser) -> Result<Expression, ParseError> {
+    // Inside delimiters a `{` after a name is a lambda argument again, even in the value of a `match`
+    let previous = p.no_lambda_args;
+    if matches!(p.current(), Token::LeftBrace | Token::LeftParen | Token::LeftBracket | Token::StringStart(_)) {
+        p.no_lambda_args = false;
+    }
+
+    let expr = parse_expression_atom(p);
+    p.no_lambda_args = previous;
+    expr
+}
+
+fn parse_expression_atom(p: &mut Parser) -> Result<Expression, ParseError> {
     let (token, span) = p.pop();
+    let start = span.0;
 
     let expr = match token {
         Token::Minus => {
-            let expr = parse_expression(p)?;
-            Expression::UnaryOperator { operator: UnaryOperator::Minus, expr: Box::new(expr) }
+            let expr = parse_expression_0(p)?;
+            Expression::UnaryOperator { operator: UnaryOperator::Minus, expr: Box::new(expr), span: p.span_from(start) }
         }
         Token::Plus => {
-            let expr = parse_expression(p)?;
-            Expression::UnaryOperator { operator: UnaryOperator::Plus, expr: Box::new(expr) }
+            let expr = parse_expression_0(p)?;
+            Expression::UnaryOperator { operator: UnaryOperator::Plus, expr: Box::new(expr), span: p.span_from(start) }
         }
         Token::Not => {
-            let expr = parse_expression(p)?;
-            Expression::UnaryOperator { operator: UnaryOperator::Not, expr: Box::new(expr) }
+            let expr = parse_expression_0(p)?;
+            Expression::UnaryOperator { operator: UnaryOperator::Not, expr: Box::new(expr), span: p.span_from(start) }
         }
         Token::IntLiteral(text) => {
-            Expression::Int { value: text.parse::<i32>().unwrap() }
+            let value = int_value(&text);
+            match value.to_i64() {
+                Some(value) => Expression::Int { value, span: p.span_from(start) },
+                None => Expression::BigInt { value, span: p.span_from(start) },
+            }
         }
         Token::FloatLiteral(text) => {
-            Expression::Float { value: text.parse::<f32>().unwrap() }
+            Expression::Float { value: float_value(&text), span: p.span_from(start) }
         }
         Token::StringLiteral(text) => {
-            Expression::String { value: text }
+            Expression::String { value: text, span: p.span_from(start) }
+        }
+        Token::StringStart(text) => {
+            let mut parts = vec![Expression::String { value: text, span: p.span_from(start) }];
+            // The token that ends with the `${` being parsed
+            let mut open = span;
+
+            loop {
+                if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftBrace, span: open }); }
+                parts.push(parse_expression(p)?);
+
+                let (token, span) = p.pop();
+                match token {
+                    Token::StringMiddle(text) => {
+                        parts.push(Expression::String { value: text, span: SourceSpan::new(p.file, span.0, span.1) });
+                        open = span;
+                    }
+                    Token::StringEnd(text) => {
+                        parts.push(Expression::String { value: text, span: SourceSpan::new(p.file, span.0, span.1) });
+                        break;
+                    }
+                    Token::Eof => return Err(ParseError::Eof { open: Token::LeftBrace, span: open }),
+                    found => return Err(ParseError::Expected { expected: Token::RightBrace, found, span }),
+                }
+            }
+
+            parts.retain(|it| !matches!(it, Expression::String { value, .. } if value.is_empty()));
+            Expression::Interpolated { parts, span: p.span_from(start) }
         }
         Token::Identifier(name) => {
             let mut args = vec![];
 
-            while p.current() != &Token::Dot && p.current() != &Token::Eof && expression_first(p) {
+            while p.current() != &Token::Dot && p.current() != &Token::Eof {
+                // Arguments start in the same line as the name, or in the next one after a comma
+                if args.is_empty() && (p.on_new_line() || !argument_first(p)) { break; }
+                if !expression_first(p) { break; }
                 args.push(parse_expression(p)?);
                 if !p.skip(Token::Comma) {
                     break;
                 }
             }
 
-            Expression::FunCall { name, args }
-            // let expr = parse_expression(e)?;
+            Expression::FunCall { name, args, span: p.span_from(start) }
+        }
+        Token::At => {
+            let name = p.expect_id()?;
+            Expression::Reference { name, span: p.span_from(start) }
         }
         Token::Return => {
-            Expression::Return { value: Box::new(parse_expression(p)?) }
+            let value = parse_expression(p)?;
+            Expression::Return { value: Box::new(value), span: p.span_from(start) }
+        }
+        Token::Match => {
+            p.no_lambda_args = true;
+            let value = parse_expression(p);
+            // The arms are inside braces, `parse_expression_base` restores the previous value after them
+            p.no_lambda_args = false;
+            let value = value?;
+
+            let open = p.current_pos();
+            p.expect(Token::LeftBrace)?;
+            let mut arms = vec![];
+
+            while p.current() != &Token::RightBrace {
+                if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftBrace, span: open }); }
+
+                arms.push(parse_match_arm(p)?);
+                p.skip(Token::Comma);
+            }
+            p.next();
+
+            Expression::Match { value: Box::new(value), arms, span: p.span_from(start) }
         }
         Token::LeftBrace => { // {
             // Lambda
@@ -427,9 +729,9 @@ pub fn parse_expression_base(p: &mut Parser) -> Result<Expression, ParseError> {
             }
 
             while p.current() != &Token::RightBrace {
-                if p.current() == &Token::Eof { return Err(ParseError::EOF); }
+                if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftBrace, span }); }
 
-                let stm = parse_statement(p)?;
+                let stm = parse_statement_or_recover(p, Some(&Token::RightBrace));
                 code.push(stm);
 
                 if p.current() == &Token::Comma {
@@ -437,14 +739,14 @@ pub fn parse_expression_base(p: &mut Parser) -> Result<Expression, ParseError> {
                 }
             }
             p.next();
-            Expression::Lambda { args, code }
+            Expression::Lambda { args, code, span: p.span_from(start) }
         }
         Token::LeftParen => { // (
             // Tuple
             let mut values = vec![];
 
             while p.current() != &Token::RightParen {
-                if p.current() == &Token::Eof { return Err(ParseError::EOF); }
+                if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftParen, span }); }
 
                 let expr = parse_expression(p)?;
                 values.push(expr);
@@ -457,7 +759,7 @@ pub fn parse_expression_base(p: &mut Parser) -> Result<Expression, ParseError> {
             if values.len() == 1 {
                 values.into_iter().next().unwrap()
             } else {
-                Expression::Tuple { values }
+                Expression::Tuple { values, span: p.span_from(start) }
             }
         }
         Token::LeftBracket => { // [
@@ -465,7 +767,7 @@ pub fn parse_expression_base(p: &mut Parser) -> Result<Expression, ParseError> {
             let mut items = vec![];
 
             while p.current() != &Token::RightBracket {
-                if p.current() == &Token::Eof { return Err(ParseError::EOF); }
+                if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftBracket, span }); }
 
                 let expr = parse_expression(p)?;
                 items.push(expr);
@@ -475,7 +777,7 @@ pub fn parse_expression_base(p: &mut Parser) -> Result<Expression, ParseError> {
                 }
             }
             p.next();
-            Expression::List { items }
+            Expression::List { items, span: p.span_from(start) }
         }
         it => {
             return Err(ParseError::UnexpectedToken(it, span));
@@ -485,32 +787,195 @@ pub fn parse_expression_base(p: &mut Parser) -> Result<Expression, ParseError> {
     Ok(expr)
 }
 
+/// `pattern -> body` or `pattern if guard -> body`
+fn parse_match_arm(p: &mut Parser) -> Result<MatchArm, ParseError> {
+    let start = p.start();
+    let pattern = parse_pattern(p)?;
+
+    let guard = if p.current() == &Token::Identifier("if".to_string()) {
+        p.next();
+        Some(parse_expression(p)?)
+    } else {
+        None
+    };
+
+    p.expect(Token::Pointer)?;
+    let body = parse_expression(p)?;
+
+    Ok(MatchArm { pattern, guard, body, span: p.span_from(start) })
+}
+
+pub fn parse_pattern(p: &mut Parser) -> Result<Pattern, ParseError> {
+    let (token, span) = p.pop();
+    let start = span.0;
+
+    let pattern = match token {
+        Token::Identifier(name) if name == "_" => Pattern::Wildcard { span: p.span_from(start) },
+        Token::Identifier(mut name) => {
+            // Variant of an imported module, `module.Variant`
+            if p.current() == &Token::Dot {
+                p.next();
+                name = format!("{}.{}", name, p.expect_id()?);
+            }
+
+            let is_variant = name.contains('.') || name.starts_with(char::is_uppercase) || p.current() == &Token::LeftParen;
+
+            if !is_variant {
+                return Ok(Pattern::Binding { name, span: p.span_from(start) });
+            }
+
+            let mut args = vec![];
+
+            if p.current() == &Token::LeftParen {
+                let open = p.current_pos();
+                p.next();
+                args = parse_patterns(p, open)?;
+            }
+
+            Pattern::Variant { name, args, span: p.span_from(start) }
+        }
+        Token::IntLiteral(text) => int_pattern(int_value(&text), p.span_from(start)),
+        Token::FloatLiteral(text) => Pattern::Float { value: float_value(&text), span: p.span_from(start) },
+        Token::StringLiteral(value) => Pattern::String { value, span: p.span_from(start) },
+        Token::Minus => {
+            match p.pop() {
+                (Token::IntLiteral(text), _) => int_pattern(-int_value(&text), p.span_from(start)),
+                (Token::FloatLiteral(text), _) => Pattern::Float { value: -float_value(&text), span: p.span_from(start) },
+                (it, span) => return Err(ParseError::UnexpectedToken(it, span)),
+            }
+        }
+        Token::LeftParen => {
+            let mut items = parse_patterns(p, span)?;
+
+            if items.len() == 1 {
+                items.remove(0)
+            } else {
+                Pattern::Tuple { items, span: p.span_from(start) }
+            }
+        }
+        Token::LeftBracket => {
+            let mut items = vec![];
+            let mut rest = None;
+
+            while p.current() != &Token::RightBracket {
+                if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftBracket, span }); }
+
+                if p.skip(Token::Ellipsis) {
+                    rest = Some(Box::new(parse_pattern(p)?));
+                    p.expect(Token::RightBracket)?;
+                    return Ok(Pattern::List { items, rest, span: p.span_from(start) });
+                }
+
+                items.push(parse_pattern(p)?);
+
+                if !p.skip(Token::Comma) {
+                    break;
+                }
+            }
+            p.expect(Token::RightBracket)?;
+
+            Pattern::List { items, rest, span: p.span_from(start) }
+        }
+        it => {
+            return Err(ParseError::UnexpectedToken(it, span));
+        }
+    };
+
+    Ok(pattern)
+}
+
+/// Comma separated patterns until `)`, the `(` at `open` was already consumed
+fn parse_patterns(p: &mut Parser, open: TokenSpan) -> Result<Vec<Pattern>, ParseError> {
+    let mut items = vec![];
+
+    while p.current() != &Token::RightParen {
+        if p.current() == &Token::Eof { return Err(ParseError::Eof { open: Token::LeftParen, span: open }); }
+
+        items.push(parse_pattern(p)?);
+
+        if !p.skip(Token::Comma) {
+            break;
+        }
+    }
+    p.expect(Token::RightParen)?;
+
+    Ok(items)
+}
+
+/// Value of the text of an `IntLiteral`, which may start with `0x`, `0o` or `0b`. The tokenizer only
+/// accepts valid digits, so any literal has a value no matter its size.
+fn int_value(text: &str) -> BigInt {
+    let (digits, radix) = match text.get(..2) {
+        Some("0x") => (&text[2..], 16),
+        Some("0o") => (&text[2..], 8),
+        Some("0b") => (&text[2..], 2),
+        _ => (text, 10),
+    };
+
+    BigInt::parse_bytes(digits.as_bytes(), radix).unwrap_or_default()
+}
+
+/// Value of the text of a `FloatLiteral`, too big values are infinite
+fn float_value(text: &str) -> f64 {
+    text.parse::<f64>().unwrap_or(f64::NAN)
+}
+
+fn int_pattern(value: BigInt, span: SourceSpan) -> Pattern {
+    match value.to_i64() {
+        Some(value) => Pattern::Int { value, span },
+        None => Pattern::BigInt { value, span },
+    }
+}
+
 fn expression_first(p: &mut Parser) -> bool {
-    match p.current() {
+    matches!(p.current(),
         Token::IntLiteral(_) |
         Token::FloatLiteral(_) |
         Token::StringLiteral(_) |
+        Token::StringStart(_) |
         Token::Identifier(_) |
+        Token::At |
         Token::Minus |
         Token::Plus |
         Token::Not |
         Token::Return |
+        Token::Match |
         Token::LeftBrace |
         Token::LeftParen |
-        Token::LeftBracket => true,
-        _ => false
+        Token::LeftBracket
+    )
+}
+
+/// Checks if the current token starts the first argument of a call, `+` and `-` only do it when they
+/// are glued to the following expression but not to the function name: `f -1` is a call, while
+/// `a - 1` and `a-1` are subtractions
+fn argument_first(p: &mut Parser) -> bool {
+    if p.no_lambda_args && p.current() == &Token::LeftBrace {
+        return false;
+    }
+
+    if !matches!(p.current(), Token::Minus | Token::Plus) {
+        return expression_first(p);
     }
+
+    let start = p.current_pos().0;
+    start != p.last_span.1 && sign_glued_to_next(p)
+}
+
+/// Checks if the current `+` or `-` has no space between it and the next token
+fn sign_glued_to_next(p: &mut Parser) -> bool {
+    let end = p.current_pos().1;
+    end == p.pos_at(1).0
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::source::{CodeSource, SourceReader};
+    use crate::source::SourceReader;
 
     use super::*;
 
     fn parse(code: &'static str) -> Parser {
-        let source = CodeSource::str(code);
-        let reader = SourceReader::new(source);
+        let reader = SourceReader::new(code);
         let tokenizer = Tokenizer::new(reader);
 
         Parser::new(tokenizer)
@@ -600,10 +1065,303 @@ mod tests {
         println!("{:#?}", exp);
     }
 
+    #[test]
+    fn plus_and_minus_arguments() {
+        let mut p = parse("print test + 20 / 3");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        if let Expression::FunCall { args, .. } = &exp {
+            assert!(matches!(&args[0], Expression::Operator { operator: Operator::Plus, .. }), "{:?}", args[0]);
+        } else {
+            panic!("Expected call: {:?}", exp);
+        }
+
+        let mut p = parse("f -1, 2");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        assert!(matches!(&exp, Expression::FunCall { args, .. } if args.len() == 2), "{:?}", exp);
+
+        let mut p = parse("x = a\n-1\ny = b\n- 1");
+        let program = p.parse_program().expect("ParseError");
+        assert_eq!(3, program.statements.len());
+
+        for code in ["a - 1", "a-1", "a -  1"] {
+            let mut p = parse(code);
+            let exp = parse_expression(&mut p).expect("ParseError");
+            assert!(matches!(&exp, Expression::Operator { operator: Operator::Minus, .. }), "{}: {:?}", code, exp);
+        }
+    }
+
+    #[test]
+    fn unary_binds_tighter_than_binary() {
+        let mut p = parse("-a * b");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        if let Expression::Operator { operator: Operator::Times, left, .. } = &exp {
+            assert!(matches!(**left, Expression::UnaryOperator { operator: UnaryOperator::Minus, .. }));
+        } else {
+            panic!("Expected operator: {:?}", exp);
+        }
+    }
+
+    #[test]
+    fn unary_operands() {
+        // The operand of a unary operator is a call with its arguments, not the rest of the expression
+        let mut p = parse("!a && b");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        if let Expression::Operator { operator: Operator::And, left, .. } = &exp {
+            assert!(matches!(**left, Expression::UnaryOperator { operator: UnaryOperator::Not, .. }), "{:?}", left);
+        } else {
+            panic!("Expected operator: {:?}", exp);
+        }
+
+        let mut p = parse("-f x");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        if let Expression::UnaryOperator { operator: UnaryOperator::Minus, expr, .. } = &exp {
+            assert!(matches!(&**expr, Expression::FunCall { name, args, .. } if name == "f" && args.len() == 1), "{:?}", expr);
+        } else {
+            panic!("Expected unary operator: {:?}", exp);
+        }
+    }
+
+    #[test]
+    fn match_expression() {
+        let mut p = parse("match value {\n  Cons(v, [a, ...rest]) if v > 0 -> v\n  (_, \"a\", -1) -> 1,\n  Null -> 0\n}");
+        let exp = parse_expression(&mut p).expect("ParseError");
+
+        if let Expression::Match { value, arms, .. } = &exp {
+            assert!(matches!(**value, Expression::FunCall { ref args, .. } if args.is_empty()));
+            assert_eq!(3, arms.len());
+            assert!(arms[0].guard.is_some());
+            assert_eq!(vec!["v", "a", "rest"], arms[0].pattern.bindings());
+            assert!(matches!(&arms[1].pattern, Pattern::Tuple { items, .. } if items.len() == 3));
+            assert!(matches!(&arms[2].pattern, Pattern::Variant { name, args, .. } if name == "Null" && args.is_empty()));
+        } else {
+            panic!("Expected match: {:?}", exp);
+        }
+    }
+
+    #[test]
+    fn lambda_argument_in_match_value() {
+        let mut p = parse("match (f { a | a + 1 }) {\n  x -> x\n}");
+        let exp = parse_expression(&mut p).expect("ParseError");
+
+        if let Expression::Match { value, arms, .. } = &exp {
+            assert!(matches!(**value, Expression::FunCall { ref args, .. } if matches!(args[..], [Expression::Lambda { .. }])));
+            assert_eq!(1, arms.len());
+        } else {
+            panic!("Expected match: {:?}", exp);
+        }
+
+        // The inner match doesn't let the `{` of the outer arms become an argument of `x`
+        let mut p = parse("match match a { _ -> b } + x {\n  _ -> f { c | c }\n}");
+        let exp = parse_expression(&mut p).expect("ParseError");
+
+        if let Expression::Match { value, arms, .. } = &exp {
+            assert!(matches!(**value, Expression::Operator { .. }));
+            assert!(matches!(&arms[0].body, Expression::FunCall { args, .. } if matches!(args[..], [Expression::Lambda { .. }])));
+        } else {
+            panic!("Expected match: {:?}", exp);
+        }
+    }
+
     #[test]
     fn dot_notation() {
         let mut p = parse("[1, 2, 3, 4].map { it * it }");
         let exp = parse_expression(&mut p).expect("ParseError");
         println!("{:#?}", exp);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn properties() {
+        let mut p = parse("user.name");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        assert!(matches!(&exp, Expression::Property { name, .. } if name == "name"));
+
+        let mut p = parse("user.greet \"hi\"");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        assert!(matches!(&exp, Expression::FunCall { name, args, .. } if name == "greet" && args.len() == 2));
+
+        let mut p = parse("user with { name = \"a\", email = \"b\" }");
+        let exp = parse_expression(&mut p).expect("ParseError");
+        if let Expression::Update { value, properties, .. } = &exp {
+            assert!(matches!(**value, Expression::FunCall { ref name, .. } if name == "user"));
+            let names: Vec<&str> = properties.iter().map(|it| it.name.as_str()).collect();
+            assert_eq!(vec!["name", "email"], names);
+        } else {
+            panic!("Expected update: {:?}", exp);
+        }
+    }
+
+    #[test]
+    fn interpolated_string() {
+        let mut p = parse("\"Hello ${user.name}, you are ${age + 1}\"");
+        let exp = parse_expression(&mut p).expect("ParseError");
+
+        if let Expression::Interpolated { parts, .. } = &exp {
+            assert_eq!(4, parts.len());
+            assert!(matches!(&parts[0], Expression::String { value, .. } if value == "Hello "));
+            assert!(matches!(&parts[1], Expression::Property { name, .. } if name == "name"));
+            assert!(matches!(&parts[2], Expression::String { value, .. } if value == ", you are "));
+            assert!(matches!(&parts[3], Expression::Operator { operator: Operator::Plus, .. }));
+        } else {
+            panic!("Expected interpolated string: {:?}", exp);
+        }
+
+        let mut p = parse("\"a ${x )}\"");
+        assert!(matches!(parse_expression(&mut p), Err(ParseError::Expected { expected: Token::RightBrace, .. })));
+    }
+
+    #[test]
+    fn recover_from_errors() {
+        let mut p = parse("a = (1 2 ]\nb = 2\nc = ) 3; d = 4\n}\ne = 5");
+        let (program, errors) = p.parse_program_partial();
+        println!("{:#?}", program);
+
+        assert_eq!(3, errors.len());
+        let names: Vec<&str> = program.statements.iter()
+            .filter_map(|it| if let Statement::Variable(var) = it { Some(var.name.as_str()) } else { None })
+            .collect();
+        assert_eq!(vec!["b", "d", "e"], names);
+    }
+
+    #[test]
+    fn recover_inside_lambda() {
+        let mut p = parse("f = { a |\n  x = ]\n  print a\n}\ng = 1");
+        let (program, errors) = p.parse_program_partial();
+
+        assert_eq!(1, errors.len());
+        assert_eq!(2, program.statements.len());
+        if let Statement::Variable(Variable { value: Expression::Lambda { code, .. }, .. }) = &program.statements[0] {
+            assert!(matches!(code[0], Statement::Error(_)));
+            assert!(matches!(code[1], Statement::Expression(_)));
+        } else {
+            panic!("Expected lambda: {:?}", program.statements[0]);
+        }
+    }
+
+    #[test]
+    fn arguments_end_at_new_line() {
+        let mut p = parse("x = y\nprint x, 1,\n  2");
+        let program = p.parse_program().expect("ParseError");
+
+        assert_eq!(2, program.statements.len());
+        if let Statement::Expression(Expression::FunCall { args, .. }) = &program.statements[1] {
+            assert_eq!(3, args.len());
+        } else {
+            panic!("Expected call: {:?}", program.statements[1]);
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let mut p = parse("x = 1 + foo 2\ntype A = B(b) | C");
+        let program = p.parse_program().expect("ParseError");
+        let at = |line, column| Span { line, column };
+
+        if let Statement::Variable(var) = &program.statements[0] {
+            assert_eq!((at(1, 1), at(1, 14)), (var.span.start, var.span.end));
+            assert_eq!((at(1, 5), at(1, 14)), (var.value.span().start, var.value.span().end));
+
+            if let Expression::Operator { right, .. } = &var.value {
+                assert_eq!((at(1, 9), at(1, 14)), (right.span().start, right.span().end));
+            } else {
+                panic!("Expected operator: {:?}", var.value);
+            }
+        } else {
+            panic!("Expected variable: {:?}", program.statements[0]);
+        }
+
+        if let Statement::TypeDef(def) = &program.statements[1] {
+            assert_eq!((at(2, 1), at(2, 18)), (def.span.start, def.span.end));
+            assert_eq!((at(2, 10), at(2, 14)), (def.variants[0].span.start, def.variants[0].span.end));
+        } else {
+            panic!("Expected typedef: {:?}", program.statements[1]);
+        }
+    }
+
+    #[test]
+    fn spans_carry_file_id() {
+        let reader = SourceReader::new("print 1");
+        let mut p = Parser::with_file(Tokenizer::new(reader), 7);
+        let program = p.parse_program().expect("ParseError");
+        assert_eq!(7, program.statements[0].span().file);
+    }
+
+    #[test]
+    fn number_literals() {
+        let cases: [(&'static str, i64); 5] = [
+            ("3000000000", 3_000_000_000),
+            ("0xFF", 255),
+            ("0o17", 15),
+            ("0b1010", 10),
+            ("9_223_372_036_854_775_807", i64::MAX),
+        ];
+
+        for (code, expected) in cases {
+            let exp = parse_expression(&mut parse(code)).expect("ParseError");
+            assert!(matches!(exp, Expression::Int { value, .. } if value == expected), "{}: {:?}", code, exp);
+        }
+
+        let cases = [
+            ("9223372036854775808", "9223372036854775808"),
+            ("0xFFFFFFFFFFFFFFFF", "18446744073709551615"),
+            ("0b1_0000000000000000000000000000000000000000000000000000000000000000", "18446744073709551616"),
+        ];
+
+        for (code, expected) in cases {
+            let exp = parse_expression(&mut parse(code)).expect("ParseError");
+            assert!(matches!(&exp, Expression::BigInt { value, .. } if value.to_string() == expected), "{}: {:?}", code, exp);
+        }
+
+        let exp = parse_expression(&mut parse("1e400")).expect("ParseError");
+        assert!(matches!(exp, Expression::Float { value, .. } if value.is_infinite()), "{:?}", exp);
+
+        let exp = parse_expression(&mut parse("match x { -9223372036854775808 -> 1, 18446744073709551616 -> 2 }")).expect("ParseError");
+        if let Expression::Match { arms, .. } = &exp {
+            assert!(matches!(arms[0].pattern, Pattern::Int { value: i64::MIN, .. }), "{:?}", arms[0].pattern);
+            assert!(matches!(&arms[1].pattern, Pattern::BigInt { value, .. } if value.to_string() == "18446744073709551616"), "{:?}", arms[1].pattern);
+        } else {
+            panic!("Expected match: {:?}", exp);
+        }
+    }
+
+    #[test]
+    fn function_references() {
+        let mut p = parse("each list, @print\nf = @utils.double");
+        let program = p.parse_program().expect("ParseError");
+
+        if let Statement::Expression(Expression::FunCall { args, .. }) = &program.statements[0] {
+            assert!(matches!(&args[1], Expression::Reference { name, .. } if name == "print"));
+        } else {
+            panic!("Expected call: {:?}", program.statements[0]);
+        }
+        if let Statement::Variable(var) = &program.statements[1] {
+            assert!(matches!(&var.value, Expression::Property { value, name, .. }
+                if name == "double" && matches!(**value, Expression::Reference { .. })));
+        } else {
+            panic!("Expected variable: {:?}", program.statements[1]);
+        }
+    }
+
+    #[test]
+    fn imports() {
+        let mut p = parse("import \"utils.dl\" as utils\nimport { map, filter } from \"lib/list.dl\"\nmatch x { utils.Some(v) -> v }");
+        let program = p.parse_program().expect("ParseError");
+
+        assert_eq!(3, program.statements.len());
+        assert!(matches!(&program.statements[0], Statement::Import(Import { path, names: ImportNames::Alias(alias), .. })
+            if path == "utils.dl" && alias == "utils"));
+        if let Statement::Import(Import { path, names: ImportNames::Names(names), .. }) = &program.statements[1] {
+            assert_eq!("lib/list.dl", path);
+            assert_eq!(vec!["map", "filter"], names.iter().map(|(name, _)| name).collect::<Vec<_>>());
+        } else {
+            panic!("Expected import: {:?}", program.statements[1]);
+        }
+        if let Statement::Expression(Expression::Match { arms, .. }) = &program.statements[2] {
+            assert!(matches!(&arms[0].pattern, Pattern::Variant { name, args, .. } if name == "utils.Some" && args.len() == 1));
+        } else {
+            panic!("Expected match: {:?}", program.statements[2]);
+        }
+
+        let mut p = parse("import \"utils.dl\" utils");
+        assert!(p.parse_program().is_err());
+    }
+}
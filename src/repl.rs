@@ -0,0 +1,372 @@
+use std::io::{stdin, stdout, BufRead, Write};
+use std::path::Path;
+
+use crate::ast::{Expression, Program, Statement};
+use crate::builtins::register_builtins;
+use crate::compiler::{CompileError, CompileWarning, Compiler};
+use crate::diagnostics::{render_all_in, Diagnostic};
+use crate::modules::ModuleLoader;
+use crate::parser::{ParseError, Parser};
+use crate::run::Value;
+use crate::runtime::Runtime;
+use crate::source::SourceReader;
+use crate::tokenizer::{LexError, Token, Tokenizer};
+
+const HELP: &str = "\
+Commands:
+    :type <expr>        Evaluate an expression and print the type of its value, a variable is not called
+    :bytecode <code>    Print the bytecode of some code without running it
+    :reset              Forget all variables, functions and types
+    :help               Print this message
+    :quit               Exit the REPL";
+
+/// Interactive session, globals defined by one input stay alive for the next ones
+pub struct Repl {
+    compiler: Compiler,
+    runtime: Runtime,
+    /// Modules imported so far, every input is kept in its sources so errors can show the code of previous ones
+    loader: ModuleLoader,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let mut runtime = Runtime::new();
+        register_builtins(&mut runtime);
+
+        let mut compiler = Compiler::new();
+        compiler.declare_builtins(&runtime);
+
+        Repl { compiler, runtime, loader: ModuleLoader::new() }
+    }
+
+    /// Reads inputs from stdin until EOF or `:quit`
+    pub fn run(&mut self) {
+        let stdin = stdin();
+        let mut lines = stdin.lock().lines();
+        let mut input = String::new();
+
+        loop {
+            print!("{}", if input.is_empty() { "> " } else { "... " });
+            stdout().flush().ok();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+
+            input.push_str(&line);
+            input.push('\n');
+
+            if is_incomplete(&input) {
+                continue;
+            }
+
+            if input.trim() == ":quit" {
+                break;
+            }
+
+            for out in self.eval(&input) {
+                println!("{}", out);
+            }
+            input.clear();
+        }
+    }
+
+    /// Evaluates a complete input, returning the lines to show to the user
+    pub fn eval(&mut self, input: &str) -> Vec<String> {
+        let input = input.trim();
+
+        if let Some(command) = input.strip_prefix(':') {
+            return self.eval_command(command);
+        }
+
+        let mut output = vec![];
+        let program = match self.parse(input, &mut output) {
+            Ok(program) => program,
+            Err(msg) => return vec![msg],
+        };
+
+        // Only the value of a trailing expression is shown, values of assignments are not
+        let echo = matches!(program.statements.last(), Some(Statement::Expression(_)));
+        let result = self.run_program(program);
+        output.extend(self.take_warnings());
+
+        match result {
+            Ok(Value::Unit) => {}
+            Ok(value) => {
+                if echo {
+                    output.push(self.runtime.debug_value(&value));
+                }
+            }
+            Err(msg) => output.push(msg),
+        }
+
+        output
+    }
+
+    fn eval_command(&mut self, command: &str) -> Vec<String> {
+        let (name, rest) = match command.find(char::is_whitespace) {
+            Some(index) => (&command[..index], command[index..].trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "type" => {
+                let mut output = vec![];
+                let program = match self.parse(rest, &mut output) {
+                    Ok(program) => program,
+                    Err(msg) => return vec![msg],
+                };
+
+                let mut statements = program.statements;
+                let expr = match (statements.pop(), statements.is_empty()) {
+                    (Some(Statement::Expression(expr)), true) => expr,
+                    _ => {
+                        output.push("error: `:type` takes a single expression".to_string());
+                        return output;
+                    }
+                };
+
+                // A lone variable is described instead of called, so functions don't need arguments
+                let expr = match expr {
+                    Expression::FunCall { name, args, span } if args.is_empty() && self.compiler.is_global(&name) => {
+                        Expression::Reference { name, span }
+                    }
+                    expr => expr,
+                };
+
+                let result = self.run_program(Program { statements: vec![Statement::Expression(expr)] });
+                output.extend(self.take_warnings());
+
+                let value = match result {
+                    Ok(value) => value,
+                    Err(msg) => {
+                        output.push(msg);
+                        return output;
+                    }
+                };
+
+                output.push(self.runtime.type_name(&value));
+                output
+            }
+            "bytecode" => {
+                let mut output = vec![];
+                let program = match self.parse(rest, &mut output) {
+                    Ok(program) => program,
+                    Err(msg) => return vec![msg],
+                };
+
+                // A copy, so the variables of the code are not declared for the next inputs
+                let mut compiler = self.compiler.clone();
+
+                match compiler.compile(program) {
+                    Ok(compiled) => output.push(format!("{:#?}", compiled.root_function)),
+                    Err(errors) => output.push(self.render_compile_errors(&errors)),
+                };
+                output.splice(0..0, self.render_warnings(&compiler.take_warnings()));
+                output
+            }
+            "reset" => {
+                self.runtime.reset();
+                self.compiler = Compiler::new();
+                self.compiler.declare_builtins(&self.runtime);
+                self.loader.reset();
+                vec![]
+            }
+            "help" => vec![HELP.to_string()],
+            _ => vec![format!("unknown command `:{}`, try `:help`", name)],
+        }
+    }
+
+    /// Parses an input and resolves its imports, relative to the working directory. Modules
+    /// imported for the first time run right away, even for `:bytecode`, their output goes to `output`.
+    fn parse(&mut self, code: &str, output: &mut Vec<String>) -> Result<Program, String> {
+        let file = self.loader.sources.add("<repl>", code);
+        let tokenizer = Tokenizer::new(self.loader.sources.reader(file));
+        let mut parser = Parser::with_file(tokenizer, file);
+
+        let program = parser.parse_program().map_err(|errors| {
+            let diagnostics: Vec<Diagnostic> = errors.iter().map(|it| Diagnostic::from(it).with_file(file)).collect();
+            render_all_in(&diagnostics, &self.loader.sources).trim_end().to_string()
+        })?;
+
+        let linked = self.loader.link(program, Path::new("")).map_err(|errors| {
+            let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+            render_all_in(&diagnostics, &self.loader.sources).trim_end().to_string()
+        })?;
+
+        if !linked.modules.is_empty() {
+            let result = self.run_program(Program { statements: linked.modules });
+            output.extend(self.take_warnings());
+            result?;
+        }
+
+        Ok(linked.program)
+    }
+
+    /// Rendered warnings of the inputs compiled since the last call
+    fn take_warnings(&mut self) -> Vec<String> {
+        let warnings = self.compiler.take_warnings();
+        self.render_warnings(&warnings)
+    }
+
+    fn render_warnings(&self, warnings: &[CompileWarning]) -> Vec<String> {
+        warnings.iter()
+            .map(|it| Diagnostic::from(it).render_in(&self.loader.sources).trim_end().to_string())
+            .collect()
+    }
+
+    fn run_program(&mut self, program: Program) -> Result<Value, String> {
+        let compiled = self.compiler.compile(program)
+            .map_err(|errors| self.render_compile_errors(&errors))?;
+
+        self.runtime.run_global(compiled)
+            .map_err(|e| Diagnostic::from(&e).render_in(&self.loader.sources).trim_end().to_string())
+    }
+
+    fn render_compile_errors(&self, errors: &[CompileError]) -> String {
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+        render_all_in(&diagnostics, &self.loader.sources).trim_end().to_string()
+    }
+}
+
+/// Checks if the input has unclosed delimiters, strings or comments, so the REPL must keep reading lines
+fn is_incomplete(code: &str) -> bool {
+    let tokenizer = Tokenizer::new(SourceReader::new(code));
+
+    let errors = match Parser::new(tokenizer).parse_program() {
+        Ok(_) => return false,
+        Err(errors) => errors,
+    };
+
+    errors.iter().any(|error| match error {
+        ParseError::Eof { .. } => true,
+        ParseError::Expected { found, .. } | ParseError::ExpectedId { found, .. } | ParseError::UnexpectedToken(found, _) => {
+            matches!(found, Token::Error(LexError::UnterminatedString | LexError::UnterminatedComment, _))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_input() {
+        assert!(is_incomplete("f = { a |\n"));
+        assert!(is_incomplete("list = [1, 2,\n"));
+        assert!(is_incomplete("print \"abc\n"));
+        assert!(is_incomplete("/* comment\n"));
+        assert!(!is_incomplete("f = { a | a }\n"));
+        assert!(!is_incomplete("print \"{\" // (\n"));
+        assert!(is_incomplete("print r#\"a \" b\n"));
+        assert!(!is_incomplete("print r\"C:\\\" + bar\"(\"\n"));
+        assert!(is_incomplete("print \"a ${[1,\n"));
+        assert!(is_incomplete("print \"a ${x} b\n"));
+        assert!(!is_incomplete("x = )\n"));
+    }
+
+    #[test]
+    fn globals_persist() {
+        let mut repl = Repl::new();
+        assert!(repl.eval("list = [1, 2]").is_empty());
+        assert_eq!(vec!["List".to_string()], repl.eval(":type list"));
+
+        assert!(repl.eval("type Color = Red | Green").is_empty());
+        assert_eq!(vec!["Color".to_string()], repl.eval(":type Red"));
+
+        repl.eval(":reset");
+        assert_eq!(1, repl.eval("list").len());
+        assert!(repl.eval("list")[0].starts_with("error: undefined name `list`"));
+    }
+
+    #[test]
+    fn functions_persist() {
+        let mut repl = Repl::new();
+        repl.eval("pair = { a | (a, a) }");
+        assert_eq!(vec!["(Int, Int)".to_string()], repl.eval(":type pair 1"));
+    }
+
+    #[test]
+    fn type_of_functions() {
+        let mut repl = Repl::new();
+        repl.eval("inc = { n | n + 1 }");
+        assert_eq!(vec!["Function(1)".to_string()], repl.eval(":type inc"));
+        assert_eq!(vec!["Int".to_string()], repl.eval(":type inc 1"));
+
+        assert!(repl.eval(":type y = 1")[0].starts_with("error: `:type` takes a single expression"));
+        assert!(repl.eval("y")[0].starts_with("error: undefined name `y`"));
+    }
+
+    #[test]
+    fn mutually_recursive_input() {
+        let mut repl = Repl::new();
+        assert_eq!(vec!["2".to_string()], repl.eval("f = { n | g n }; g = { n | n + 1 }; f 1"));
+        assert_eq!(vec!["3".to_string()], repl.eval("f 2"));
+    }
+
+    #[test]
+    fn lambdas_assign_globals_of_previous_inputs() {
+        let mut repl = Repl::new();
+        assert!(repl.eval("f = { g = 1 }").is_empty());
+        assert!(repl.eval("g = 5").is_empty());
+        repl.eval("f");
+        assert_eq!(vec!["5".to_string()], repl.eval("g"));
+
+        // Defined after `g`, so the lambda changes it
+        assert!(repl.eval("h = { g = 2 }").is_empty());
+        repl.eval("h");
+        assert_eq!(vec!["2".to_string()], repl.eval("g"));
+    }
+
+    #[test]
+    fn bytecode_command() {
+        let mut repl = Repl::new();
+        assert!(repl.eval("x = 1").is_empty());
+
+        // Inspecting code doesn't define its variables, so both get the slot after `x`
+        let stores_second_global = |output: Vec<String>| {
+            let code: String = output[0].split_whitespace().collect();
+            code.contains("StoreGlobal(1,)")
+        };
+        assert!(stores_second_global(repl.eval(":bytecode y = x + 1")));
+        assert!(stores_second_global(repl.eval(":bytecode z = 2")));
+
+        assert!(repl.eval("z = 2").is_empty());
+        assert_eq!(vec!["3".to_string()], repl.eval("x + z"));
+        assert!(repl.eval("y")[0].starts_with("error: undefined name `y`"));
+    }
+
+    #[test]
+    fn printing_is_not_echoed() {
+        let mut repl = Repl::new();
+        assert!(repl.eval("println 1").is_empty());
+        assert!(repl.eval("debug \"a\"").is_empty());
+        assert_eq!(vec!["2".to_string()], repl.eval("1 + 1"));
+    }
+
+    #[test]
+    fn match_bindings_dont_persist() {
+        let mut repl = Repl::new();
+        assert_eq!(vec!["2".to_string()], repl.eval("match 1 {\n  x -> x + 1\n}"));
+        assert!(repl.eval("x")[0].starts_with("error: undefined name `x`"));
+
+        // Closures keep the ones they captured
+        assert!(repl.eval("add = match 5 {\n  n -> { m | n + m }\n}").is_empty());
+        assert_eq!(vec!["6".to_string()], repl.eval("add 1"));
+    }
+
+    #[test]
+    fn imports_persist() {
+        let path = std::env::temp_dir().join(format!("demo_lang_repl_{}.dl", std::process::id()));
+        let _files = crate::source::TempFiles(vec![path.clone()]);
+        std::fs::write(&path, "triple = { n | n * 3 }").unwrap();
+
+        let mut repl = Repl::new();
+        assert!(repl.eval(&format!("import {:?} as m", path)).is_empty());
+        assert_eq!(vec!["6".to_string()], repl.eval("m.triple 2"));
+
+        assert!(repl.eval("import \"missing.dl\" as missing")[0].starts_with("error: cannot find module `missing.dl`"));
+        assert_eq!(vec!["9".to_string()], repl.eval("m.triple 3"));
+    }
+}
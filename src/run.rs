@@ -1,26 +1,75 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 
-use crate::ast::TypeDef;
+use num_bigint::BigInt;
+
+use crate::ast::{Pattern, TypeDef};
 use crate::runtime::{Runtime, RuntimeError};
+use crate::source::SourceSpan;
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub struct CompiledProgram {
     pub root_function: CompiledFunction,
+    /// Names of the globals, by slot. Includes the globals of the programs compiled before by the same compiler.
+    pub globals: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct CompiledFunction {
+    /// Name of the variable the lambda was assigned to, `<lambda>` for anonymous ones
+    pub name: String,
+    /// Where the function was defined
+    pub span: SourceSpan,
     pub args: usize,
+    /// Names of the local variables by slot, the arguments take the first slots
+    pub locals: Vec<String>,
+    /// Variables of enclosing lambdas captured when the closure is created
+    pub upvalues: Vec<String>,
+    /// Where each upvalue is found in the function that creates the closure
+    pub captures: Vec<Var>,
     pub code: Vec<Inst>,
-    pub functions: HashMap<usize, CompiledFunction>,
+    pub lines: LineTable,
+    pub functions: HashMap<usize, Rc<CompiledFunction>>,
     pub instance_classes: HashMap<String, InstanceClass>,
 }
 
+/// Maps instruction indices back to the source code that generated them
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    /// Index of the first instruction of a run of instructions with the same span, sorted by index
+    entries: Vec<(usize, SourceSpan)>,
+}
+
+pub type BuiltinFn = fn(&mut Runtime, Vec<Value>) -> Result<Value, RuntimeError>;
+
+/// Builtin that calls functions given to it, like `if` or `each`
+pub type ControlFn = fn(&mut Runtime, Vec<Value>) -> Result<Step, RuntimeError>;
+
+/// Rest of a control builtin, resumed with the value returned by the function it called
+pub type Continuation = Box<dyn FnOnce(&mut Runtime, Value) -> Result<Step, RuntimeError>>;
+
+/// What a control builtin does next. Calls run on the frame stack of the runtime, like the calls of
+/// the program, so they count against the recursion limit instead of using the native stack.
+pub enum Step {
+    Return(Value),
+    /// Calls a function, the builtin returns what it returns
+    Call(Value, Vec<Value>),
+    /// Calls a function and then resumes the builtin
+    Then(Value, Vec<Value>, Continuation),
+}
+
+#[derive(Clone, Copy)]
+pub enum Builtin {
+    Value(BuiltinFn),
+    Control(ControlFn),
+}
+
 #[derive(Clone)]
 pub struct BuiltinFunction {
     pub args: usize,
-    pub func: Box<fn(&mut Runtime, Vec<Value>) -> Result<Value, RuntimeError>>,
+    pub func: Builtin,
 }
 
 #[derive(Debug, Clone)]
@@ -31,17 +80,62 @@ pub struct InstanceClass {
     pub properties: Vec<String>,
 }
 
+/// Where a variable is stored, resolved by the compiler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Var {
+    /// Slot of the running function
+    Local(usize),
+    /// Variable captured by the running closure
+    Upvalue(usize),
+    Global(usize),
+}
+
 #[derive(Debug, Clone)]
 pub enum Inst {
-    Set(String),
-    Int(i32),
-    Float(f32),
+    LoadLocal(usize),
+    StoreLocal(usize),
+    LoadUpvalue(usize),
+    StoreUpvalue(usize),
+    LoadGlobal(usize),
+    StoreGlobal(usize),
+    /// Pops a function and calls it with this number of arguments, which must be the number it takes
+    Call(usize),
+    /// Like `Call`, returning the result from the running function, whose frame the callee reuses
+    TailCall(usize),
+    /// Pops a value and calls it without arguments if it's a function, other values are pushed back.
+    /// A variable used without arguments, `name`, is read like this.
+    CallValue,
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
     String(String),
-    Call(String),
+    /// Calls the builtin function or creates an instance of the type with this name, passing this
+    /// number of arguments
+    CallNamed { name: String, argc: usize },
+    /// Pushes a function that calls the builtin function or creates an instance of the type with this name
+    LoadNamed(String),
     List(usize),
     Tuple(usize),
     Function(usize),
     Return,
+    /// Continues at the instruction with this index
+    Jump(usize),
+    /// Pops a boolean and jumps if it's `False`
+    JumpIfFalse(usize),
+    /// Tests the value at the top of the stack without popping it, if it matches pushes the values
+    /// of the variables of the pattern in order, otherwise jumps to `otherwise`
+    Destructure { pattern: Pattern, otherwise: usize },
+    Pop,
+    /// Pops the value no arm of a `match` applied to and fails
+    NoMatch,
+    /// Pops an instance and pushes its property with this name, other values are passed to the
+    /// function in `method`, or to the builtin with this name if it's `None`
+    Property { name: String, method: Option<Var> },
+    /// Pops this number of values and pushes a string with their printed forms one after the other
+    Concat(usize),
+    /// Pops the new values of these properties and an instance, pushes a copy of the instance with
+    /// them changed
+    Update(Vec<String>),
 }
 
 #[derive(Debug, Clone)]
@@ -58,17 +152,97 @@ pub enum Type {
 #[derive(Debug, Clone)]
 pub enum Value {
     Unit,
-    Int(i32),
-    Float(f32),
+    Int(i64),
+    /// Int outside of the range of `i64`, smaller ones are always `Int`
+    BigInt(BigInt),
+    Float(f64),
     String(String),
     List(Vec<Value>),
     Tuple(Vec<Value>),
-    Function { func: usize },
+    Function(Rc<Closure>),
     Instance(Instance),
 }
 
+/// Storage of a variable that closures can share, empty until the variable is assigned
+pub type Cell = Rc<RefCell<Option<Value>>>;
+
+/// A lambda together with the variables it captured from the scope it was created in
+#[derive(Clone)]
+pub struct Closure {
+    pub func: Rc<CompiledFunction>,
+    pub upvalues: Vec<Cell>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Instance {
     pub class: usize,
     pub properties: Vec<Value>,
-}
\ No newline at end of file
+}
+
+impl CompiledFunction {
+    pub fn new(name: &str, span: SourceSpan, args: usize) -> Self {
+        CompiledFunction {
+            name: name.to_string(),
+            span,
+            args,
+            locals: vec![],
+            upvalues: vec![],
+            captures: vec![],
+            code: vec![],
+            lines: Default::default(),
+            functions: Default::default(),
+            instance_classes: Default::default(),
+        }
+    }
+
+    /// Adds an instruction, recording the source code it comes from
+    pub fn push(&mut self, inst: Inst, span: SourceSpan) {
+        self.lines.add(self.code.len(), span);
+        self.code.push(inst);
+    }
+
+    /// Makes the jump instruction at `index` continue at `target`
+    pub fn patch_jump(&mut self, index: usize, target: usize) {
+        match &mut self.code[index] {
+            Inst::Jump(it) | Inst::JumpIfFalse(it) | Inst::Destructure { otherwise: it, .. } => *it = target,
+            other => panic!("Not a jump: {:?}", other),
+        }
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("name", &self.func.name)
+            .field("args", &self.func.args)
+            .finish()
+    }
+}
+
+impl LineTable {
+    pub fn add(&mut self, index: usize, span: SourceSpan) {
+        if let Some((_, last)) = self.entries.last() {
+            if *last == span {
+                return;
+            }
+        }
+
+        self.entries.push((index, span));
+    }
+
+    /// Index of the first instruction of each run of instructions with the same span, and the span
+    pub fn entries(&self) -> &[(usize, SourceSpan)] {
+        &self.entries
+    }
+
+    /// Span of the code that generated the instruction at `index`
+    pub fn get(&self, index: usize) -> Option<SourceSpan> {
+        let pos = self.entries.partition_point(|(start, _)| *start <= index);
+
+        if pos == 0 {
+            None
+        } else {
+            Some(self.entries[pos - 1].1)
+        }
+    }
+}
@@ -1,14 +1,44 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 
-use crate::ast::TypeDef;
-use crate::run::{BuiltinFunction, CompiledFunction, CompiledProgram, Inst, Instance, InstanceClass, Value};
+use crate::ast::{Pattern, TypeDef};
+use crate::operators::values_equal;
+use crate::run::{Builtin, BuiltinFn, BuiltinFunction, Cell, Closure, CompiledFunction, CompiledProgram, Continuation, ControlFn, Inst, Instance, InstanceClass, Step, Value, Var};
+use crate::source::SourceSpan;
 
 #[derive(Debug, Clone)]
 pub enum RuntimeError {
     StackUnderflow,
     UndefinedName(String),
+    /// Operator applied to values of types it doesn't support, with the names of those types
+    InvalidOperands { operator: String, left: String, right: String },
+    InvalidOperand { operator: String, operand: String },
+    DivisionByZero,
+    /// Value of the wrong type given to a builtin, with the name of the expected type
+    TypeMismatch { expected: String, found: String },
+    /// No arm of a `match` applies to the value
+    MatchError { value: String },
+    ArityMismatch { name: String, expected: usize, got: usize },
+    /// More nested calls than the recursion limit of the runtime
+    StackOverflow,
+    IntegerOverflow { operator: String },
+    /// The variant `class` has no property `name`
+    UnknownProperty { class: String, name: String },
     Custom(String),
+    /// Error raised while running user code, with the call stack at that moment, innermost call first
+    Traced { error: Box<RuntimeError>, trace: Vec<TraceFrame> },
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    /// Name of the running function
+    pub function: String,
+    /// Where the running function was defined
+    pub defined_at: SourceSpan,
+    /// Code that was running in this function when the error happened
+    pub span: SourceSpan,
 }
 
 pub struct Runtime {
@@ -16,15 +46,50 @@ pub struct Runtime {
     builtin_instance_classes: HashMap<String, Rc<InstanceClass>>,
     builtin_id_to_class: HashMap<usize, Rc<InstanceClass>>,
     next_id: usize,
+    env: Env,
+    overflow: Overflow,
+    recursion_limit: usize,
 }
 
+/// What the interpreter does after running an instruction
+enum Flow {
+    Next,
+    Return(Value),
+    /// Call that resumes the continuation, if any, with the returned value instead of pushing it
+    Call(Rc<Closure>, Vec<Value>, Option<Continuation>),
+    /// Call that replaces the frame of the running function
+    TailCall(Rc<Closure>, Vec<Value>),
+}
+
+/// What happens when the result of an operation on `Int`s doesn't fit in 64 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The result becomes a big integer, so `Int`s never overflow
+    Promote,
+    /// The operation fails with `RuntimeError::IntegerOverflow`
+    Error,
+}
+
+/// Frames of the running functions, the first one is the root of the program
 struct Env {
     frames: Vec<StackFrame>,
+    /// Variables of the root function, by slot
+    globals: Vec<Cell>,
+    global_names: Vec<String>,
 }
 
 struct StackFrame {
-    variables: HashMap<String, Value>,
-    functions: HashMap<usize, CompiledFunction>,
+    func: Rc<CompiledFunction>,
+    /// Next instruction to run once the function this frame calls returns
+    ip: usize,
+    /// Values pushed by the function while it waits for the function it calls
+    stack: Vec<Value>,
+    /// Control builtin called by the function, waiting for the function it calls
+    resume: Option<Continuation>,
+    /// Variables of the running function, by slot
+    locals: Vec<Cell>,
+    /// Variables captured by the running closure
+    upvalues: Vec<Cell>,
     instance_classes: HashMap<String, Rc<InstanceClass>>,
     id_to_class: HashMap<usize, Rc<InstanceClass>>,
 }
@@ -35,24 +100,236 @@ impl Runtime {
             builtin_functions: Default::default(),
             builtin_instance_classes: Default::default(),
             builtin_id_to_class: Default::default(),
-            next_id: 100_000
+            next_id: 100_000,
+            env: Env::new(),
+            overflow: Overflow::Promote,
+            recursion_limit: 10_000,
         }
     }
 
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    /// Chooses between big integers and errors for `Int` operations that overflow, big integers by default
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Maximum number of nested calls, deeper calls fail with `RuntimeError::StackOverflow`
+    pub fn recursion_limit(&self) -> usize {
+        self.recursion_limit
+    }
+
+    pub fn set_recursion_limit(&mut self, limit: usize) {
+        self.recursion_limit = limit;
+    }
+
+    /// Runs a program with its own globals, separate from the ones of `run_global`
     pub fn run(&mut self, cp: CompiledProgram) -> Result<Value, RuntimeError> {
-        let mut env = Env::new();
+        let previous = std::mem::replace(&mut self.env, Env::new());
+
+        self.env.declare_globals(cp.globals);
+        self.env.push(Rc::new(cp.root_function), vec![], vec![]);
+        let result = self.run_frame();
+        self.env = previous;
+
+        result
+    }
+
+    /// Runs a program inside the global frame left behind by previous calls, so variables, functions
+    /// and types defined by one program are visible to the next one. Used by the REPL.
+    pub fn run_global(&mut self, cp: CompiledProgram) -> Result<Value, RuntimeError> {
+        if self.env.frames.is_empty() {
+            self.env.push(Rc::new(cp.root_function), vec![], vec![]);
+        } else {
+            self.env.extend_root(Rc::new(cp.root_function));
+        }
+
+        self.env.declare_globals(cp.globals);
+        let result = self.run_frame();
+        self.env.truncate(1);
 
-        env.push(&cp.root_function);
-        let value = self.run_function(&mut env, &cp.root_function, vec![])?;
-        env.pop();
+        result
+    }
+
+    /// Drops the global frame, forgetting everything defined by previous calls to `run_global`
+    pub fn reset(&mut self) {
+        self.env = Env::new();
+    }
+
+    /// Name of the type of a value, instances are named after their type definition
+    pub fn type_name(&self, value: &Value) -> String {
+        match value {
+            Value::Unit => "Unit".to_string(),
+            Value::Int(_) | Value::BigInt(_) => "Int".to_string(),
+            Value::Float(_) => "Float".to_string(),
+            Value::String(_) => "String".to_string(),
+            Value::List(_) => "List".to_string(),
+            Value::Tuple(values) => {
+                let names: Vec<String> = values.iter().map(|it| self.type_name(it)).collect();
+                format!("({})", names.join(", "))
+            }
+            Value::Function(closure) => format!("Function({})", closure.func.args),
+            Value::Instance(instance) => {
+                match self.get_class_by_id(instance.class) {
+                    Some(class) => unqualified(&class.typedef.name).to_string(),
+                    None => "Instance".to_string(),
+                }
+            }
+        }
+    }
+
+    /// Text shown by `print`, strings are written as they are
+    pub fn display_value(&self, value: &Value) -> String {
+        match value {
+            Value::String(text) => text.to_string(),
+            _ => self.debug_value(value),
+        }
+    }
+
+    /// Text shown by `debug` and the REPL, strings are quoted so they can't be confused with other values
+    pub fn debug_value(&self, value: &Value) -> String {
+        let mut out = String::new();
+        self.write_value(&mut out, value);
+        out
+    }
+
+    fn write_value(&self, out: &mut String, value: &Value) {
+        match value {
+            Value::Unit => out.push_str("()"),
+            Value::Int(value) => out.push_str(&value.to_string()),
+            Value::BigInt(value) => out.push_str(&value.to_string()),
+            Value::Float(value) => out.push_str(&format!("{:?}", value)),
+            Value::String(text) => out.push_str(&format!("{:?}", text)),
+            Value::List(values) => {
+                out.push('[');
+                self.write_values(out, values);
+                out.push(']');
+            }
+            Value::Tuple(values) => {
+                out.push('(');
+                self.write_values(out, values);
+                out.push(')');
+            }
+            Value::Function(closure) => {
+                if closure.func.name == "<lambda>" {
+                    out.push_str("<lambda>");
+                } else {
+                    out.push_str(&format!("<function {}>", closure.func.name));
+                }
+            }
+            Value::Instance(instance) => {
+                match self.get_class_by_id(instance.class) {
+                    Some(class) => out.push_str(unqualified(&class.variant)),
+                    None => out.push_str("<instance>"),
+                }
+
+                if !instance.properties.is_empty() {
+                    out.push('(');
+                    self.write_values(out, &instance.properties);
+                    out.push(')');
+                }
+            }
+        }
+    }
+
+    fn write_values(&self, out: &mut String, values: &[Value]) {
+        for (index, value) in values.iter().enumerate() {
+            if index > 0 {
+                out.push_str(", ");
+            }
+            self.write_value(out, value);
+        }
+    }
+
+    /// Names of the builtin functions and the number of arguments they take
+    pub fn builtin_functions(&self) -> impl Iterator<Item=(&String, usize)> {
+        self.builtin_functions.iter().map(|(name, func)| (name, func.args))
+    }
+
+    pub fn builtin_classes(&self) -> impl Iterator<Item=&Rc<InstanceClass>> {
+        self.builtin_instance_classes.values()
+    }
+
+    /// The builtin `True` or `False`
+    pub fn boolean(&self, value: bool) -> Value {
+        let class = &self.builtin_instance_classes[if value { "True" } else { "False" }];
+        Value::Instance(Instance { class: class.id, properties: vec![] })
+    }
+
+    /// Checks if the value is the builtin `True` or `False`
+    pub fn as_boolean(&self, value: &Value) -> Option<bool> {
+        if let Value::Instance(instance) = value {
+            for (name, result) in [("True", true), ("False", false)] {
+                if self.builtin_instance_classes.get(name).map(|it| it.id) == Some(instance.class) {
+                    return Some(result);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Checks if `CallNamed` would find something to call
+    fn is_callable(&self, name: &str) -> bool {
+        self.named_arity(name).is_some()
+    }
+
+    /// Number of arguments of the type or builtin function `name`
+    fn named_arity(&self, name: &str) -> Option<usize> {
+        if let Some(class) = self.env.get_instance_class(name) {
+            return Some(class.properties.len());
+        }
+
+        self.builtin_functions.get(name).map(|it| it.args)
+            .or_else(|| self.builtin_instance_classes.get(name).map(|it| it.properties.len()))
+    }
+
+    /// Creates an instance of the type named `name`, types of the program hide builtin functions and
+    /// those hide builtin types
+    fn call_named(&mut self, name: &str, args: Vec<Value>) -> Result<Step, RuntimeError> {
+        if let Some(class) = self.env.get_instance_class(name) {
+            return construct(&class, args).map(Step::Return);
+        }
+
+        if let Some(func) = self.builtin_functions.get(name) {
+            check_arity(name, func.args, args.len())?;
+            return match func.func {
+                Builtin::Value(func) => func(self, args).map(Step::Return),
+                Builtin::Control(func) => func(self, args),
+            };
+        }
+
+        if let Some(class) = self.builtin_instance_classes.get(name) {
+            return construct(class, args).map(Step::Return);
+        }
+
+        Err(RuntimeError::UndefinedName(name.to_string()))
+    }
+
+    pub fn get_class_by_id(&self, id: usize) -> Option<Rc<InstanceClass>> {
+        for frame in self.env.frames.iter().rev() {
+            if let Some(class) = frame.id_to_class.get(&id) {
+                return Some(class.clone());
+            }
+        }
 
-        Ok(value)
+        self.builtin_id_to_class.get(&id).cloned()
     }
 
-    pub fn register_func(&mut self, name: &str, args: usize, func: fn(&mut Runtime, Vec<Value>) -> Result<Value, RuntimeError>) {
+    pub fn register_func(&mut self, name: &str, args: usize, func: BuiltinFn) {
         self.builtin_functions.insert(name.to_string(), BuiltinFunction {
             args,
-            func: Box::new(func),
+            func: Builtin::Value(func),
+        });
+    }
+
+    /// Registers a builtin that calls functions, see `Step`
+    pub fn register_control(&mut self, name: &str, args: usize, func: ControlFn) {
+        self.builtin_functions.insert(name.to_string(), BuiltinFunction {
+            args,
+            func: Builtin::Control(func),
         });
     }
 
@@ -75,150 +352,500 @@ impl Runtime {
         }
     }
 
-    fn run_function(&mut self, env: &mut Env, p: &CompiledFunction, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    /// What the running function does after a builtin it called takes a step
+    fn step(&self, step: Step, stack: &mut Vec<Value>) -> Result<Flow, RuntimeError> {
+        match step {
+            Step::Return(value) => {
+                stack.push(value);
+                Ok(Flow::Next)
+            }
+            Step::Call(func, args) => Ok(Flow::Call(self.closure(&func, args.len())?, args, None)),
+            Step::Then(func, args, resume) => Ok(Flow::Call(self.closure(&func, args.len())?, args, Some(resume))),
+        }
+    }
+
+    /// The closure of a function value called with `args` arguments
+    fn closure(&self, func: &Value, args: usize) -> Result<Rc<Closure>, RuntimeError> {
+        let closure = match func {
+            Value::Function(closure) => closure,
+            _ => {
+                return Err(RuntimeError::TypeMismatch { expected: "Function".to_string(), found: self.type_name(func) });
+            }
+        };
+
+        check_arity(&closure.func.name, closure.func.args, args)?;
+        Ok(closure.clone())
+    }
+
+    /// Runs the frame at the top of the stack until it returns, together with the functions it calls,
+    /// without recursion. The frame is left on the stack.
+    fn run_frame(&mut self) -> Result<Value, RuntimeError> {
+        let base = self.env.frames.len();
+        let mut p = self.env.frames.last().unwrap().func.clone();
         let mut ip = 0;
-        let mut stack = args;
+        let mut stack = vec![];
+        // What to do after a control builtin resumed, before running the next instruction
+        let mut resumed = None;
 
-        while ip < p.code.len() {
-            let inst = &p.code[ip];
-            ip += 1;
+        loop {
+            let flow = if let Some(flow) = resumed.take() {
+                flow
+            } else if ip < p.code.len() {
+                let index = ip;
+                ip += 1;
 
-            match inst {
-                Inst::Set(name) => {
-                    env.set(name, stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?);
+                match self.execute(&p, &p.code[index], &mut stack, &mut ip) {
+                    Ok(flow) => flow,
+                    Err(error) => return Err(self.unwind(base, error, &p, index)),
                 }
-                Inst::Int(value) => {
-                    stack.push(Value::Int(*value));
+            } else {
+                Flow::Return(stack.pop().unwrap_or(Value::Unit))
+            };
+
+            match flow {
+                Flow::Next => {}
+                Flow::Return(value) => {
+                    if self.env.frames.len() == base {
+                        return Ok(value);
+                    }
+
+                    self.env.pop();
+                    let caller = self.env.frames.last_mut().unwrap();
+                    p = caller.func.clone();
+                    ip = caller.ip;
+                    stack = std::mem::take(&mut caller.stack);
+
+                    match caller.resume.take() {
+                        Some(resume) => {
+                            let flow = resume(self, value).and_then(|step| self.step(step, &mut stack));
+                            match flow {
+                                Ok(flow) => resumed = Some(flow),
+                                Err(error) => return Err(self.unwind(base, error, &p, ip - 1)),
+                            }
+                        }
+                        None => stack.push(value),
+                    }
                 }
-                Inst::Float(value) => {
-                    stack.push(Value::Float(*value));
+                Flow::Call(closure, args, resume) => {
+                    if self.env.frames.len() >= self.recursion_limit {
+                        return Err(self.unwind(base, RuntimeError::StackOverflow, &p, ip - 1));
+                    }
+
+                    let caller = self.env.frames.last_mut().unwrap();
+                    caller.ip = ip;
+                    caller.stack = std::mem::take(&mut stack);
+                    caller.resume = resume;
+
+                    self.env.push(closure.func.clone(), closure.upvalues.clone(), args);
+                    p = closure.func.clone();
+                    ip = 0;
                 }
-                Inst::String(value) => {
-                    stack.push(Value::String(value.clone()));
+                Flow::TailCall(closure, args) => {
+                    // The frame of the running function is reused, so it's not in stack traces
+                    self.env.pop();
+                    self.env.push(closure.func.clone(), closure.upvalues.clone(), args);
+                    p = closure.func.clone();
+                    ip = 0;
+                    stack.clear();
                 }
-                Inst::Call(name) => {
-                    // Variable
-                    if let Some(value) = env.get(name) {
-                        if let Value::Function { func } = &value {
-                            let func = env.get_function(*func).unwrap();
-                            let mut args = vec![];
-
-                            for _ in 0..func.args {
-                                let val = stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?;
-                                args.push(val);
-                            }
+            }
+        }
+    }
 
-                            env.push(&func);
-                            let result = self.run_function(env, &func, args)?;
-                            env.pop();
+    /// Adds the frames above `base` to the stack trace of an error and drops them, `p` is the running
+    /// function and `index` the instruction that failed
+    fn unwind(&mut self, base: usize, error: RuntimeError, p: &CompiledFunction, index: usize) -> RuntimeError {
+        let mut error = error.with_frame(trace_frame(p, index));
 
-                            stack.push(result);
-                        } else {
-                            stack.push(value);
-                        }
-                        continue;
-                    }
+        for frame in self.env.frames[base - 1..].iter().rev().skip(1) {
+            error = error.with_frame(trace_frame(&frame.func, frame.ip - 1));
+        }
 
-                    // TypeDef
-                    if let Some(instance_class) = env.get_instance_class(name) {
-                        let mut properties = vec![];
+        self.env.truncate(base);
+        error
+    }
 
-                        for _ in 0..instance_class.properties.len() {
-                            let val = stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?;
-                            properties.push(val);
-                        }
+    /// Runs a single instruction of `p`, returns what to do next. `ip` is the index of the next
+    /// instruction to run.
+    fn execute(&mut self, p: &CompiledFunction, inst: &Inst, stack: &mut Vec<Value>, ip: &mut usize) -> Result<Flow, RuntimeError> {
+        match inst {
+            Inst::LoadLocal(slot) => {
+                stack.push(self.load(p, Var::Local(*slot))?);
+            }
+            Inst::StoreLocal(slot) => {
+                self.env.store(Var::Local(*slot), stack.pop().ok_or(RuntimeError::StackUnderflow)?);
+            }
+            Inst::LoadUpvalue(index) => {
+                stack.push(self.load(p, Var::Upvalue(*index))?);
+            }
+            Inst::StoreUpvalue(index) => {
+                self.env.store(Var::Upvalue(*index), stack.pop().ok_or(RuntimeError::StackUnderflow)?);
+            }
+            Inst::LoadGlobal(slot) => {
+                stack.push(self.load(p, Var::Global(*slot))?);
+            }
+            Inst::StoreGlobal(slot) => {
+                self.env.store(Var::Global(*slot), stack.pop().ok_or(RuntimeError::StackUnderflow)?);
+            }
+            Inst::Call(argc) => {
+                let value = stack.pop().ok_or(RuntimeError::StackUnderflow)?;
+                let args = pop_values(stack, *argc)?;
+                return Ok(Flow::Call(self.closure(&value, *argc)?, args, None));
+            }
+            Inst::TailCall(argc) => {
+                let value = stack.pop().ok_or(RuntimeError::StackUnderflow)?;
+                let args = pop_values(stack, *argc)?;
+                return Ok(Flow::TailCall(self.closure(&value, *argc)?, args));
+            }
+            Inst::CallValue => {
+                let value = stack.pop().ok_or(RuntimeError::StackUnderflow)?;
 
-                        let value = Value::Instance(Instance { class: instance_class.id, properties });
-                        stack.push(value);
-                        continue;
-                    }
+                if let Value::Function(_) = &value {
+                    return Ok(Flow::Call(self.closure(&value, 0)?, vec![], None));
+                }
+                stack.push(value);
+            }
+            Inst::Int(value) => {
+                stack.push(Value::Int(*value));
+            }
+            Inst::BigInt(value) => {
+                stack.push(Value::BigInt(value.clone()));
+            }
+            Inst::Float(value) => {
+                stack.push(Value::Float(*value));
+            }
+            Inst::String(value) => {
+                stack.push(Value::String(value.clone()));
+            }
+            Inst::CallNamed { name, argc } => {
+                let args = pop_values(stack, *argc)?;
+                let step = self.call_named(name, args)?;
+                return self.step(step, stack);
+            }
+            Inst::LoadNamed(name) => {
+                let args = self.named_arity(name).ok_or_else(|| RuntimeError::UndefinedName(name.to_string()))?;
 
-                    // Builtin function
-                    if let Some(func) = self.builtin_functions.get(name) {
-                        let mut args = vec![];
+                // The function just passes its arguments along
+                let span = p.lines.get(*ip - 1).unwrap_or(p.span);
+                let mut func = CompiledFunction::new(name, span, args);
+                for slot in 0..args {
+                    func.locals.push(format!("_{}", slot));
+                    func.push(Inst::LoadLocal(slot), span);
+                }
+                func.push(Inst::CallNamed { name: name.to_string(), argc: args }, span);
 
-                        for _ in 0..func.args {
-                            let val = stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?;
-                            args.push(val);
-                        }
+                stack.push(Value::Function(Rc::new(Closure { func: Rc::new(func), upvalues: vec![] })));
+            }
+            Inst::List(items) => {
+                // TODO check everything has the same type
+                let values = pop_values(stack, *items)?;
+                stack.push(Value::List(values));
+            }
+            Inst::Tuple(items) => {
+                let values = pop_values(stack, *items)?;
+                stack.push(Value::Tuple(values));
+            }
+            Inst::Function(id) => {
+                let func = p.functions[id].clone();
+                let upvalues = func.captures.iter()
+                    .map(|var| self.env.capture(*var))
+                    .collect();
 
-                        let result = (func.func.clone())(self, args)?;
+                stack.push(Value::Function(Rc::new(Closure { func, upvalues })));
+            }
+            Inst::Return => {
+                return Ok(Flow::Return(stack.pop().ok_or(RuntimeError::StackUnderflow)?));
+            }
+            Inst::Jump(target) => {
+                *ip = *target;
+            }
+            Inst::JumpIfFalse(target) => {
+                let value = stack.pop().ok_or(RuntimeError::StackUnderflow)?;
 
-                        stack.push(result);
-                        continue;
+                match self.as_boolean(&value) {
+                    Some(true) => {}
+                    Some(false) => *ip = *target,
+                    None => {
+                        return Err(RuntimeError::TypeMismatch { expected: "Boolean".to_string(), found: self.type_name(&value) });
                     }
+                }
+            }
+            Inst::Destructure { pattern, otherwise } => {
+                let value = stack.last().ok_or(RuntimeError::StackUnderflow)?;
+                let mut bindings = vec![];
+
+                if self.match_pattern(pattern, value, &mut bindings)? {
+                    stack.extend(bindings);
+                } else {
+                    *ip = *otherwise;
+                }
+            }
+            Inst::Pop => {
+                stack.pop().ok_or(RuntimeError::StackUnderflow)?;
+            }
+            Inst::NoMatch => {
+                let value = stack.pop().ok_or(RuntimeError::StackUnderflow)?;
+                return Err(RuntimeError::MatchError { value: self.debug_value(&value) });
+            }
+            Inst::Property { name, method } => {
+                let value = stack.pop().ok_or(RuntimeError::StackUnderflow)?;
 
-                    // Builtin TypeDef
-                    if let Some(instance_class) = self.builtin_instance_classes.get(name) {
-                        let mut properties = vec![];
+                let class = match &value {
+                    Value::Instance(instance) => {
+                        let class = self.get_class_by_id(instance.class);
 
-                        for _ in 0..instance_class.properties.len() {
-                            let val = stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?;
-                            properties.push(val);
+                        if let Some(class) = &class {
+                            let property = unqualified(name);
+                            if let Some(index) = class.properties.iter().position(|it| it == property) {
+                                stack.push(instance.properties[index].clone());
+                                return Ok(Flow::Next);
+                            }
                         }
 
-                        let value = Value::Instance(Instance { class: instance_class.id, properties });
-                        stack.push(value);
-                        continue;
+                        class.map(|it| unqualified(&it.variant).to_string()).unwrap_or_else(|| "Instance".to_string())
                     }
+                    other => self.type_name(other),
+                };
 
-                    // Error not found
-                    return Err(RuntimeError::UndefinedName(name.to_string()));
+                // Without a function to call, `name` is a property of some type, but not of this value
+                if method.is_none() && !self.is_callable(name) {
+                    return Err(RuntimeError::UnknownProperty { class, name: name.to_string() });
                 }
-                Inst::List(items) => {
-                    let mut values = vec![];
 
-                    // TODO check everything has the same type
-                    for _ in 0..*items {
-                        values.push(stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?);
+                // `value.name` is also a short form of `name value`
+                stack.push(value);
+                return match method {
+                    Some(var) => {
+                        stack.push(self.load(p, *var)?);
+                        self.execute(p, &Inst::Call(1), stack, ip)
                     }
+                    None => self.execute(p, &Inst::CallNamed { name: name.to_string(), argc: 1 }, stack, ip),
+                };
+            }
+            Inst::Concat(count) => {
+                let text: String = pop_values(stack, *count)?.iter()
+                    .map(|it| self.display_value(it))
+                    .collect();
+                stack.push(Value::String(text));
+            }
+            Inst::Update(names) => {
+                let values = pop_values(stack, names.len())?;
+                let mut instance = match stack.pop().ok_or(RuntimeError::StackUnderflow)? {
+                    Value::Instance(instance) => instance,
+                    other => {
+                        return Err(RuntimeError::InvalidOperand { operator: "with".to_string(), operand: self.type_name(&other) });
+                    }
+                };
 
-                    stack.push(Value::List(values));
+                let class = self.get_class_by_id(instance.class)
+                    .ok_or_else(|| RuntimeError::Custom("instance of an unknown type".to_string()))?;
+
+                for (name, value) in names.iter().zip(values) {
+                    let index = class.properties.iter().position(|it| it == name)
+                        .ok_or_else(|| RuntimeError::UnknownProperty { class: unqualified(&class.variant).to_string(), name: name.to_string() })?;
+                    instance.properties[index] = value;
                 }
-                Inst::Tuple(items) => {
-                    let mut values = vec![];
 
-                    for _ in 0..*items {
-                        values.push(stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?);
-                    }
+                stack.push(Value::Instance(instance));
+            }
+        }
 
-                    stack.push(Value::Tuple(values));
-                }
-                Inst::Function(func) => {
-                    stack.push(Value::Function { func: *func });
-                }
-                Inst::Return => {
-                    return Ok(stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)?);
+        Ok(Flow::Next)
+    }
+
+    /// Value of a variable of the running function, `p`, which must be assigned
+    fn load(&self, p: &CompiledFunction, var: Var) -> Result<Value, RuntimeError> {
+        self.env.load(var).ok_or_else(|| {
+            let name = match var {
+                Var::Local(slot) => &p.locals[slot],
+                Var::Upvalue(index) => &p.upvalues[index],
+                Var::Global(slot) => &self.env.global_names[slot],
+            };
+            RuntimeError::UndefinedName(name.to_string())
+        })
+    }
+}
+
+impl Runtime {
+    /// Checks if `value` has the shape of `pattern`, collecting the values of its variables in the
+    /// order of `Pattern::bindings`
+    fn match_pattern(&self, pattern: &Pattern, value: &Value, bindings: &mut Vec<Value>) -> Result<bool, RuntimeError> {
+        let matches = match (pattern, value) {
+            (Pattern::Wildcard { .. }, _) => true,
+            (Pattern::Binding { .. }, _) => {
+                bindings.push(value.clone());
+                true
+            }
+            (Pattern::Int { value: expected, .. }, _) => values_equal(&Value::Int(*expected), value),
+            (Pattern::BigInt { value: expected, .. }, _) => values_equal(&Value::BigInt(expected.clone()), value),
+            (Pattern::Float { value: expected, .. }, _) => values_equal(&Value::Float(*expected), value),
+            (Pattern::String { value: expected, .. }, Value::String(value)) => expected == value,
+            (Pattern::Tuple { items, .. }, Value::Tuple(values)) => {
+                items.len() == values.len() && self.match_all(items, values, bindings)?
+            }
+            (Pattern::List { items, rest, .. }, Value::List(values)) => {
+                match rest {
+                    None => items.len() == values.len() && self.match_all(items, values, bindings)?,
+                    Some(rest) => {
+                        items.len() <= values.len()
+                            && self.match_all(items, &values[..items.len()], bindings)?
+                            && self.match_pattern(rest, &Value::List(values[items.len()..].to_vec()), bindings)?
+                    }
                 }
             }
+            (Pattern::Variant { name, args, .. }, Value::Instance(instance)) => {
+                let class = self.env.get_instance_class(name)
+                    .or_else(|| self.builtin_instance_classes.get(name).cloned())
+                    .ok_or_else(|| RuntimeError::UndefinedName(name.to_string()))?;
+
+                class.id == instance.class
+                    && args.len() == instance.properties.len()
+                    && self.match_all(args, &instance.properties, bindings)?
+            }
+            _ => false,
+        };
+
+        Ok(matches)
+    }
+
+    fn match_all(&self, patterns: &[Pattern], values: &[Value], bindings: &mut Vec<Value>) -> Result<bool, RuntimeError> {
+        for (pattern, value) in patterns.iter().zip(values) {
+            if !self.match_pattern(pattern, value, bindings)? {
+                return Ok(false);
+            }
         }
 
-        Ok(stack.pop().unwrap_or(Value::Unit))
+        Ok(true)
+    }
+}
+
+/// Name without the module that defines it, the names of imported modules are qualified like `utils.Some`
+fn unqualified(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+fn construct(class: &InstanceClass, properties: Vec<Value>) -> Result<Value, RuntimeError> {
+    check_arity(&class.variant, class.properties.len(), properties.len())?;
+    Ok(Value::Instance(Instance { class: class.id, properties }))
+}
+
+/// Checks that a call passes as many arguments as the function takes
+fn check_arity(name: &str, expected: usize, got: usize) -> Result<(), RuntimeError> {
+    if expected != got {
+        return Err(RuntimeError::ArityMismatch { name: unqualified(name).to_string(), expected, got });
+    }
+
+    Ok(())
+}
+
+fn trace_frame(p: &CompiledFunction, index: usize) -> TraceFrame {
+    TraceFrame {
+        function: p.name.to_string(),
+        defined_at: p.span,
+        span: p.lines.get(index).unwrap_or(p.span),
+    }
+}
+
+/// Pops the last `count` values, in the order they were pushed
+fn pop_values(stack: &mut Vec<Value>, count: usize) -> Result<Vec<Value>, RuntimeError> {
+    if stack.len() < count {
+        return Err(RuntimeError::StackUnderflow);
+    }
+
+    Ok(stack.split_off(stack.len() - count))
+}
+
+impl RuntimeError {
+    /// The error without the stack trace
+    pub fn kind(&self) -> &RuntimeError {
+        match self {
+            RuntimeError::Traced { error, .. } => error.kind(),
+            _ => self,
+        }
+    }
+
+    pub fn trace(&self) -> &[TraceFrame] {
+        match self {
+            RuntimeError::Traced { trace, .. } => trace,
+            _ => &[],
+        }
+    }
+
+    fn with_frame(self, frame: TraceFrame) -> RuntimeError {
+        match self {
+            RuntimeError::Traced { error, mut trace } => {
+                trace.push(frame);
+                RuntimeError::Traced { error, trace }
+            }
+            _ => RuntimeError::Traced { error: Box::new(self), trace: vec![frame] }
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::StackUnderflow => write!(f, "stack underflow"),
+            RuntimeError::UndefinedName(name) => write!(f, "undefined name `{}`", name),
+            RuntimeError::InvalidOperands { operator, left, right } => {
+                write!(f, "cannot apply `{}` to `{}` and `{}`", operator, left, right)
+            }
+            RuntimeError::InvalidOperand { operator, operand } => {
+                write!(f, "cannot apply `{}` to `{}`", operator, operand)
+            }
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::MatchError { value } => write!(f, "no arm of the match applies to {}", value),
+            RuntimeError::TypeMismatch { expected, found } => write!(f, "expected `{}` but found `{}`", expected, found),
+            RuntimeError::ArityMismatch { name, expected, got } => {
+                let arguments = |count: usize| if count == 1 { "argument" } else { "arguments" };
+                let were = if *got == 1 { "was" } else { "were" };
+                write!(f, "function `{}` takes {} {} but {} {} {} supplied", name, expected, arguments(*expected), got, arguments(*got), were)
+            }
+            RuntimeError::IntegerOverflow { operator } => write!(f, "integer overflow in `{}`", operator),
+            RuntimeError::StackOverflow => write!(f, "stack overflow, too many nested calls"),
+            RuntimeError::UnknownProperty { class, name } => write!(f, "`{}` has no property `{}`", class, name),
+            RuntimeError::Custom(msg) => write!(f, "{}", msg),
+            RuntimeError::Traced { error, .. } => write!(f, "{}", error),
+        }
     }
 }
 
 impl Env {
     fn new() -> Self {
         Env {
-            frames: vec![]
+            frames: vec![],
+            globals: vec![],
+            global_names: vec![],
         }
     }
 
-    fn get(&self, name: &str) -> Option<Value> {
-        for frame in self.frames.iter().rev() {
-            if let Some(val) = frame.variables.get(name) {
-                return Some(val.clone());
-            }
+    /// Makes room for the globals of a program, the ones of previous programs keep their values
+    fn declare_globals(&mut self, names: Vec<String>) {
+        self.globals.resize_with(names.len(), Default::default);
+        self.global_names = names;
+    }
+
+    fn cell(&self, var: Var) -> &Cell {
+        match var {
+            Var::Local(slot) => &self.frames.last().unwrap().locals[slot],
+            Var::Upvalue(index) => &self.frames.last().unwrap().upvalues[index],
+            Var::Global(slot) => &self.globals[slot],
         }
+    }
 
-        None
+    /// Value of a variable of the running function, `None` if it's not assigned yet
+    fn load(&self, var: Var) -> Option<Value> {
+        self.cell(var).borrow().clone()
     }
 
-    fn set(&mut self, name: &str, value: Value) {
-        let frame = self.frames.iter_mut().rev().next();
-        if let Some(frame) = frame {
-            frame.variables.insert(name.to_string(), value);
-        }
+    fn store(&mut self, var: Var, value: Value) {
+        *self.cell(var).borrow_mut() = Some(value);
+    }
+
+    /// Shares a variable of the running function with a new closure, variables not assigned yet are
+    /// captured empty so the closure sees them once they are
+    fn capture(&self, var: Var) -> Cell {
+        self.cell(var).clone()
     }
 
     fn get_instance_class(&self, name: &str) -> Option<Rc<InstanceClass>> {
@@ -231,36 +858,378 @@ impl Env {
         None
     }
 
-    fn get_function(&self, id: usize) -> Option<CompiledFunction> {
-        for frame in self.frames.iter().rev() {
-            if let Some(val) = frame.functions.get(&id) {
-                return Some(val.clone());
-            }
-        }
+    /// Adds the frame of a call to `func`, the arguments are its first variables
+    fn push(&mut self, func: Rc<CompiledFunction>, upvalues: Vec<Cell>, args: Vec<Value>) {
+        let mut locals: Vec<Cell> = args.into_iter().map(|it| Rc::new(RefCell::new(Some(it)))).collect();
+        locals.resize_with(func.locals.len(), Default::default);
 
-        None
+        let mut frame = StackFrame {
+            func: func.clone(),
+            ip: 0,
+            stack: vec![],
+            resume: None,
+            locals,
+            upvalues,
+            id_to_class: Default::default(),
+            instance_classes: Default::default(),
+        };
+
+        frame.extend(&func);
+        self.frames.push(frame);
+    }
+
+    /// Makes the root frame run `func`, keeping the types of the previous root functions. Its locals
+    /// are new, the ones of the previous root functions live on only in the closures that captured them.
+    fn extend_root(&mut self, func: Rc<CompiledFunction>) {
+        let root = &mut self.frames[0];
+        root.extend(&func);
+        root.locals = std::iter::repeat_with(Default::default).take(func.locals.len()).collect();
+        root.func = func;
     }
 
-    fn push(&mut self, func: &CompiledFunction) {
-        let mut id_to_class: HashMap<usize, Rc<InstanceClass>> = HashMap::new();
-        let mut instance_classes: HashMap<String, Rc<InstanceClass>> = HashMap::new();
+    fn pop(&mut self) {
+        self.frames.pop().unwrap();
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.frames.truncate(len);
+    }
+}
 
+impl StackFrame {
+    fn extend(&mut self, func: &CompiledFunction) {
         for class in func.instance_classes.values() {
             let rc = Rc::new(class.clone());
 
-            id_to_class.insert(class.id, rc.clone());
-            instance_classes.insert(class.variant.to_string(), rc);
+            self.id_to_class.insert(class.id, rc.clone());
+            self.instance_classes.insert(class.variant.to_string(), rc);
         }
+    }
+}
 
-        self.frames.push(StackFrame {
-            variables: Default::default(),
-            functions: func.functions.clone(),
-            id_to_class,
-            instance_classes,
-        });
+#[cfg(test)]
+mod tests {
+    use crate::testing::{run, Pipeline};
+
+    use super::*;
+
+    fn run_limited(code: &str, recursion_limit: usize) -> Result<Value, RuntimeError> {
+        let mut pipeline = Pipeline::new();
+        pipeline.runtime.set_recursion_limit(recursion_limit);
+        pipeline.run(code)
     }
 
-    fn pop(&mut self) {
-        self.frames.pop().unwrap();
+    #[test]
+    fn stack_trace() {
+        let error = run("f = { n |\n  print n\n  unary_minus n\n}\nh = { x | f x }\nh \"a\"").unwrap_err();
+
+        assert!(matches!(error.kind(), RuntimeError::InvalidOperand { .. }));
+
+        let frames: Vec<(&str, u32, u32)> = error.trace().iter()
+            .map(|it| (it.function.as_str(), it.span.start.line, it.span.start.column))
+            .collect();
+
+        assert_eq!(vec![("f", 3, 3), ("h", 5, 11), ("<main>", 6, 1)], frames);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn closures_outlive_their_frame() {
+        let value = run("make = { n | { x | n } }\nf = make 5\nf 1").unwrap();
+        assert!(matches!(value, Value::Int(5)));
+
+        let value = run("outer = { a |\n  middle = { b | { c | a } }\n  middle 2\n}\ninner = outer 1\ninner 3").unwrap();
+        assert!(matches!(value, Value::Int(1)));
+    }
+
+    #[test]
+    fn closures_see_later_assignments() {
+        let value = run("counter = { start |\n  get = { value }\n  value = start\n  get\n}\nc = counter 5\nc").unwrap();
+        assert!(matches!(value, Value::Int(5)));
+    }
+
+    #[test]
+    fn lexical_scope() {
+        // `f` sees the global `x`, not the argument of the function that calls it
+        let value = run("x = 1\nf = { x }\ng = { x | f }\ng 2").unwrap();
+        assert!(matches!(value, Value::Int(1)));
+    }
+
+    #[test]
+    fn lambdas_assign_outer_variables() {
+        let value = run("count = 0\ninc = { count = count + 1 }\ninc\ninc\ncount").unwrap();
+        assert!(matches!(value, Value::Int(2)));
+
+        let value = run("make = {\n  n = 0\n  { n = n + 1\n    n }\n}\nnext = make\nnext\nnext").unwrap();
+        assert!(matches!(value, Value::Int(2)));
+
+        // Arguments shadow outer variables
+        let value = run("x = 1\nf = { x | x = x + 10 }\nf 5\nx").unwrap();
+        assert!(matches!(value, Value::Int(1)));
+
+        // Only variables assigned before the lambda, a variable assigned later is another one
+        let value = run("f = { g = 1\n  g }\ng = 5\n(f, g)").unwrap();
+        assert!(matches!(value, Value::Tuple(values) if matches!(values.as_slice(), [Value::Int(1), Value::Int(5)])));
+
+        let value = run("make = {\n  inc = { n = 1 }\n  n = 0\n  inc\n  n\n}\nmake").unwrap();
+        assert!(matches!(value, Value::Int(0)));
+    }
+
+    #[test]
+    fn lambdas_shadow_builtins() {
+        let value = run("f = { x | print = x\n  print }\nf 3").unwrap();
+        assert!(matches!(value, Value::Int(3)));
+
+        // Once assigned at the top level, the builtin name is a global the lambdas change
+        let value = run("print = 1\nf = { print = 2 }\nf\nprint").unwrap();
+        assert!(matches!(value, Value::Int(2)));
+    }
+
+    #[test]
+    fn function_references() {
+        let apply = "apply = { f, x | f x }\n";
+
+        let value = run(&format!("{}double = {{ x | x * 2 }}\napply @double, 4", apply)).unwrap();
+        assert!(matches!(value, Value::Int(8)));
+
+        let value = run("total = 0\nadd = { x | total = total + x }\neach [1, 2, 3], @add\ntotal").unwrap();
+        assert!(matches!(value, Value::Int(6)));
+
+        // Zero argument lambdas are called when used by name, and passed along with `@`
+        let value = run("one = { 1 }\nf = @one\nf").unwrap();
+        assert!(matches!(value, Value::Int(1)));
+
+        let value = run(&format!("{}type Box = Box(value)\n[(apply @unary_minus, 3), (apply @Box, 1).value]", apply)).unwrap();
+        assert!(matches!(value, Value::List(values) if matches!(values.as_slice(), [Value::Int(-3), Value::Int(1)])));
+    }
+
+    #[test]
+    fn call_arity() {
+        let error = run("call = { f | f 1, 2 }\ncall { x | x }").unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::ArityMismatch { expected: 1, got: 2, .. }), "{:?}", error);
+
+        let error = run("call = { f | f }\ncall { x | x }").unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::ArityMismatch { expected: 1, got: 0, .. }), "{:?}", error);
+
+        let error = run("call = { f | f 1 }\ncall 5").unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::TypeMismatch { expected, found } if expected == "Function" && found == "Int"));
+    }
+
+    #[test]
+    fn argument_order() {
+        // Arguments are passed in the order they are written, to lambdas, constructors and builtins
+        let value = run("f = { a, b | a - b }\nf 10, 3").unwrap();
+        assert!(matches!(value, Value::Int(7)));
+
+        let value = run("type Pair = Pair(first, second)\np = Pair 1, 2\np.first").unwrap();
+        assert!(matches!(value, Value::Int(1)));
+
+        let value = run("10 - 3").unwrap();
+        assert!(matches!(value, Value::Int(7)));
+
+        let value = run("[\"a\", 2]").unwrap();
+        assert!(matches!(&value, Value::List(items) if matches!(items[..], [Value::String(_), Value::Int(2)])));
+    }
+
+    #[test]
+    fn tail_calls() {
+        // `return f x` reuses the frame of the running function, so it doesn't count against the limit
+        let count = "count = { n, total |\n  match n {\n    0 -> total\n    _ -> return count (n - 1), (total + 1)\n  }\n}\n";
+        let value = run_limited(&format!("{}count 100000, 0", count), 100).unwrap();
+        assert!(matches!(value, Value::Int(100000)));
+
+        // Mutual recursion too
+        let value = run_limited("even = { n | match n {\n  0 -> 1\n  _ -> return odd (n - 1)\n} }\nodd = { n | match n {\n  0 -> 0\n  _ -> return even (n - 1)\n} }\neven 10001", 100).unwrap();
+        assert!(matches!(value, Value::Int(0)));
+    }
+
+    #[test]
+    fn recursion_limit() {
+        let down = "down = { n | match n {\n  0 -> 0\n  _ -> 1 + down (n - 1)\n} }\n";
+
+        let value = run_limited(&format!("{}down 50", down), 100).unwrap();
+        assert!(matches!(value, Value::Int(50)));
+
+        let error = run_limited(&format!("{}down 200", down), 100).unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::StackOverflow));
+        assert_eq!(error.trace().len(), 100);
+        assert_eq!(error.trace()[0].function, "down");
+        assert_eq!(error.trace()[99].function, "<main>");
+    }
+
+    #[test]
+    fn recursion_through_builtins() {
+        // Lambdas called by builtins run on the frame stack too, so they reach the recursion limit long
+        // before the native stack runs out
+        let down = "down = { n | if n > 0, { down (n - 1) } }\n";
+
+        let value = run(&format!("{}down 1000", down)).unwrap();
+        assert!(matches!(value, Value::Unit));
+
+        let error = run(&format!("{}down 100000", down)).unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::StackOverflow));
+        assert_eq!(error.trace().len(), 10_000);
+        assert_eq!(error.trace()[9_999].function, "<main>");
+
+        let value = run("total = 0\nn = 0\nwhile { n < 10000 }, {\n  each [1, 2], { x | total = total + x }\n  n = n + 1\n}\ntotal").unwrap();
+        assert!(matches!(value, Value::Int(30000)));
+    }
+
+    #[test]
+    fn builtin_arguments() {
+        let mut pipeline = Pipeline::new();
+        pipeline.runtime.register_func("pair", 2, |_, args| Ok(Value::Tuple(args)));
+        pipeline.compiler.declare_builtins(&pipeline.runtime);
+
+        let value = pipeline.run("[(pair 1, 2), 10 - 3]").unwrap();
+        let runtime = &mut pipeline.runtime;
+        assert_eq!("[(1, 2), 7]", runtime.debug_value(&value));
+
+        // Each call takes the arguments of its call site, never values pushed by earlier code
+        let mut root = CompiledFunction::new("<main>", SourceSpan::default(), 0);
+        root.push(Inst::Int(0), SourceSpan::default());
+        root.push(Inst::Int(1), SourceSpan::default());
+        root.push(Inst::CallNamed { name: "pair".to_string(), argc: 1 }, SourceSpan::default());
+
+        let error = runtime.run(CompiledProgram { root_function: root, globals: vec![] }).unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::ArityMismatch { name, expected: 2, got: 1 } if name == "pair"), "{:?}", error);
+        assert_eq!("function `pair` takes 2 arguments but 1 argument was supplied", error.to_string());
+    }
+
+    #[test]
+    fn match_variants() {
+        let code = "type List = Cons(value, next) | Null
+sum = { list |
+  match list {
+    Cons(v, rest) -> v + sum rest
+    Null -> 0
+  }
+}
+sum (Cons 1, (Cons 2, (Cons 3, Null)))";
+        assert!(matches!(run(code), Ok(Value::Int(6))));
+
+        let code = "type Shape = Circle(r) | Rect(w, h)\nmatch (Rect 2, (Circle 3)) {\n  Rect(w, Circle(r)) -> w * r\n  _ -> 0\n}";
+        assert!(matches!(run(code), Ok(Value::Int(6))));
+    }
+
+    #[test]
+    fn match_literals_tuples_and_lists() {
+        let describe = "describe = { x |
+  match x {
+    0 -> \"zero\"
+    -1 -> \"minus one\"
+    \"hi\" -> \"greeting\"
+    (a, _) -> \"pair\"
+    [] -> \"empty\"
+    [first, ...rest] -> rest
+    _ -> \"other\"
+  }
+}
+";
+        let eval = |arg: &str| run(&format!("{}describe {}", describe, arg));
+
+        assert!(matches!(eval("0"), Ok(Value::String(it)) if it == "zero"));
+        assert!(matches!(eval("-1"), Ok(Value::String(it)) if it == "minus one"));
+        assert!(matches!(eval("\"hi\""), Ok(Value::String(it)) if it == "greeting"));
+        assert!(matches!(eval("(1, 2)"), Ok(Value::String(it)) if it == "pair"));
+        assert!(matches!(eval("[]"), Ok(Value::String(it)) if it == "empty"));
+        assert!(matches!(eval("[1, 2, 3]"), Ok(Value::List(rest)) if rest.len() == 2));
+        assert!(matches!(eval("(1, 2, 3)"), Ok(Value::String(it)) if it == "other"));
+    }
+
+    #[test]
+    fn match_guards() {
+        let code = "sign = { n |\n  match n {\n    x if x < 0 -> -1\n    0 -> 0\n    _ -> 1\n  }\n}\n[(sign -5), (sign 0), (sign 7)]";
+        if let Ok(Value::List(values)) = run(code) {
+            assert!(matches!(values.as_slice(), [Value::Int(-1), Value::Int(0), Value::Int(1)]), "{:?}", values);
+        } else {
+            panic!("Expected a list");
+        }
+    }
+
+    #[test]
+    fn match_bindings_are_scoped() {
+        // Pattern variables shadow other variables only in their arm
+        let value = run("x = 1\ny = match 5 {\n  x -> x + 1\n}\n[x, y]").unwrap();
+        assert!(matches!(&value, Value::List(items) if matches!(items[..], [Value::Int(1), Value::Int(6)])), "{:?}", value);
+
+        // Lambdas capture them, also at the top level
+        let value = run("f = match 5 {\n  n -> { m | n + m }\n}\nf 1").unwrap();
+        assert!(matches!(value, Value::Int(6)));
+
+        let value = run("g = { v | match v {\n  n -> { m | n + m }\n} }\nadd = g 2\nadd 3").unwrap();
+        assert!(matches!(value, Value::Int(5)));
+    }
+
+    #[test]
+    fn match_error() {
+        let error = run("x = 5\nmatch x {\n  1 -> 2\n}").unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::MatchError { .. }));
+        assert_eq!(2, error.trace()[0].span.start.line);
+    }
+
+    #[test]
+    fn properties() {
+        let user = "type User = User(name, email)\nu = User \"ann\", \"a@x\"\n";
+
+        let value = run(&format!("{}u.name", user)).unwrap();
+        assert!(matches!(value, Value::String(text) if text == "ann"));
+
+        let value = run(&format!("{}v = u with {{ email = \"b@y\" }}\n(u.email, v.email, v.name)", user)).unwrap();
+        let texts: Vec<String> = match value {
+            Value::Tuple(values) => values.iter().map(|it| format!("{:?}", it)).collect(),
+            other => panic!("Expected a tuple: {:?}", other),
+        };
+        assert_eq!(vec!["String(\"a@x\")", "String(\"b@y\")", "String(\"ann\")"], texts);
+
+        let value = run("double = { x | x * 2 }\n(3).double").unwrap();
+        assert!(matches!(value, Value::Int(6)));
+
+        let value = run("(3).unary_minus").unwrap();
+        assert!(matches!(value, Value::Int(-3)));
+    }
+
+    #[test]
+    fn unknown_properties() {
+        let types = "type User = User(name, email)\ntype Phone = Phone(number)\n";
+
+        let error = run(&format!("{}u = User \"a\", \"b\"\nu.number", types)).unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::UnknownProperty { class, name } if class == "User" && name == "number"));
+
+        let error = run(&format!("{}(Phone 1) with {{ name = 2 }}", types)).unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::UnknownProperty { class, name } if class == "Phone" && name == "name"));
+
+        // Properties of values that are not instances
+        let error = run(&format!("{}u = User \"a\", \"b\"\nu.name.email", types)).unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::UnknownProperty { class, name } if class == "String" && name == "email"));
+        assert_eq!("`String` has no property `email`", error.to_string());
+
+        let error = run(&format!("{}5 with {{ name = 2 }}", types)).unwrap_err();
+        assert!(matches!(error.kind(), RuntimeError::InvalidOperand { operator, .. } if operator == "with"));
+    }
+
+    #[test]
+    fn format_values() {
+        let code = "type User = User(name, email)\n[True, (User \"ann\", \"a@x\"), (1, 2.5, \"b\"), [], { 1 }]";
+        // Run in the global frame, so the classes of the instances are still known when printing them
+        let mut pipeline = Pipeline::new();
+        let compiled = pipeline.compile(code).expect("CompileError");
+        let value = pipeline.runtime.run_global(compiled).unwrap();
+        let runtime = &pipeline.runtime;
+
+        assert_eq!(
+            "[True, User(\"ann\", \"a@x\"), (1, 2.5, \"b\"), [], <lambda>]",
+            runtime.display_value(&value)
+        );
+        assert_eq!("a\"b", runtime.display_value(&Value::String("a\"b".to_string())));
+        assert_eq!("\"a\\\"b\"", runtime.debug_value(&Value::String("a\"b".to_string())));
+    }
+
+    #[test]
+    fn interpolation() {
+        let value = run("type User = User(name)\nu = User \"ann\"\nage = 41\n\"${u.name} is ${age + 1}, ${u} ${[\"a\"]}\"").unwrap();
+        assert!(matches!(value, Value::String(text) if text == "ann is 42, User(\"ann\") [\"a\"]"));
+
+        let value = run("\"${\"a${1}b\"}${2}\"").unwrap();
+        assert!(matches!(value, Value::String(text) if text == "a1b2"));
+    }
+}
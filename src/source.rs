@@ -1,147 +1,220 @@
-use std::collections::VecDeque;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, stdin};
-
-pub enum CodeSource {
-    File { path: String, offset: usize },
-    Str { code: &'static str, offset: usize },
-    Stdin,
-}
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::rc::Rc;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
 pub struct Span {
     pub line: u32,
     pub column: u32,
 }
 
-const BUFFER_SIZE: usize = 64;
-const LOOKAHEAD_AMOUNT: usize = 3;
+/// Identifies a source file, assigned by whoever loads the sources
+pub type FileId = usize;
+
+/// Range of source code covered by a syntax node, `end` is the position right after the last character
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SourceSpan {
+    pub file: FileId,
+    pub start: Span,
+    pub end: Span,
+}
+
+impl SourceSpan {
+    pub fn new(file: FileId, start: Span, end: Span) -> Self {
+        SourceSpan { file, start, end }
+    }
+
+    /// Smallest span that covers both spans
+    pub fn merge(self, other: SourceSpan) -> SourceSpan {
+        SourceSpan { file: self.file, start: self.start, end: other.end }
+    }
+}
+
+/// Source code of a file loaded in a `SourceMap`
+#[derive(Debug)]
+pub struct SourceFile {
+    pub id: FileId,
+    /// Name shown in diagnostics, the path of the file or something like `<stdin>`
+    pub name: String,
+    pub text: Rc<str>,
+    /// Byte offset where each line starts
+    line_starts: Vec<usize>,
+}
+
+/// Owns the code of every file of a program, assigning file ids in the order they are added
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    /// Files loaded from disk by their path, so each one is read once
+    paths: HashMap<String, FileId>,
+}
+
+/// A file that could not be read
+#[derive(Debug)]
+pub struct SourceError {
+    pub name: String,
+    pub error: io::Error,
+}
+
+impl SourceFile {
+    pub fn new(id: FileId, name: impl Into<String>, text: impl Into<Rc<str>>) -> Self {
+        let text = text.into();
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+
+        SourceFile { id, name: name.into(), text, line_starts }
+    }
+
+    /// Line and column of a byte offset, columns count characters like `SourceReader` does
+    pub fn location(&self, offset: usize) -> Span {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let column = self.text.get(line_start..offset)
+            .map(|it| it.chars().count())
+            .unwrap_or(offset - line_start);
+
+        Span { line: line as u32 + 1, column: column as u32 + 1 }
+    }
+
+    /// Text of a line without the line break, lines start at 1
+    pub fn line(&self, line: u32) -> Option<&str> {
+        let start = *self.line_starts.get((line as usize).checked_sub(1)?)?;
+        let end = self.line_starts.get(line as usize).copied().unwrap_or(self.text.len());
+
+        Some(self.text[start..end].trim_end_matches(&['\n', '\r'][..]))
+    }
+
+    pub fn reader(&self) -> SourceReader {
+        SourceReader::new(self.text.clone())
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds code that doesn't come from a file on disk, like the input of the REPL
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> FileId {
+        let id = self.files.len();
+        self.files.push(SourceFile::new(id, name, text.into()));
+        id
+    }
+
+    /// Reads a file, or returns the id it got the first time it was loaded. Invalid UTF-8 is read as U+FFFD.
+    pub fn load(&mut self, path: &str) -> Result<FileId, SourceError> {
+        if let Some(id) = self.paths.get(path) {
+            return Ok(*id);
+        }
+
+        let bytes = fs::read(path).map_err(|error| SourceError { name: path.to_string(), error })?;
+        let id = self.add(path, String::from_utf8_lossy(&bytes));
+        self.paths.insert(path.to_string(), id);
+
+        Ok(id)
+    }
+
+    pub fn file(&self, id: FileId) -> &SourceFile {
+        &self.files[id]
+    }
 
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    pub fn reader(&self, id: FileId) -> SourceReader {
+        self.file(id).reader()
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unable to read `{}`: {}", self.name, self.error)
+    }
+}
+
+/// Reads the characters of the source code one by one, past the end of the input the current character
+/// is `'\0'`, use `is_eof` to tell it apart from a NUL in the text
 pub struct SourceReader {
-    source: CodeSource,
-    lookahead: VecDeque<u8>,
-    buffer: [u8; BUFFER_SIZE],
-    count: usize,
+    text: Rc<str>,
+    /// Byte offset of the current character
     pos: usize,
     span: Span,
-    eof: bool,
 }
 
 impl SourceReader {
-    pub fn new(source: CodeSource) -> Self {
-        let mut this = Self {
-            source,
-            lookahead: VecDeque::with_capacity(LOOKAHEAD_AMOUNT),
-            buffer: [0; BUFFER_SIZE],
-            count: 0,
-            pos: 0,
-            span: Span { line: 1, column: 1 },
-            eof: false,
-        };
-        this.fill_lookahead();
-        this
-    }
-
-    fn fill_buffer(&mut self) {
-        if self.eof { return; }
-        let buff: &mut [u8] = &mut self.buffer;
-
-        let line_size = match &mut self.source {
-            CodeSource::File { path, offset } => {
-                let mut f = File::open(path).unwrap();
-                f.seek(SeekFrom::Start(*offset as _)).unwrap();
-                let line_size = f.read(buff).unwrap();
-                *offset += line_size;
-                line_size
-            }
-            CodeSource::Str { code, offset } => {
-                let remaining: &[u8] = &code.as_bytes()[*offset..];
-                let mut line_size = 0;
-                for (dst, src) in buff.iter_mut().zip(remaining) {
-                    *dst = *src;
-                    line_size += 1;
-                }
-                *offset += line_size;
-                line_size
-            }
-            CodeSource::Stdin => {
-                stdin().read(buff).unwrap()
-            }
-        };
-
-        self.count = line_size;
-
-        if line_size == 0 {
-            self.eof = true;
-        }
+    pub fn new(text: impl Into<Rc<str>>) -> Self {
+        SourceReader { text: text.into(), pos: 0, span: Span { line: 1, column: 1 } }
     }
 
     pub fn shift_multiple(&mut self, amount: usize) {
-        if self.eof { return; }
-
         for _ in 0..amount {
             self.shift();
         }
     }
 
-    fn fill_lookahead(&mut self) {
-        while self.lookahead.len() < LOOKAHEAD_AMOUNT {
-            if self.pos >= self.count {
-                self.fill_buffer();
-                self.pos = 0;
-            }
+    pub fn shift(&mut self) {
+        if self.pos >= self.text.len() {
+            return;
+        }
+
+        let c = self.current();
 
-            if !self.eof {
-                self.lookahead.push_back(self.buffer[self.pos]);
-                self.pos += 1;
-            } else {
-                self.lookahead.push_back(0);
-            }
+        if c == '\n' {
+            self.span.line += 1;
+            self.span.column = 1;
+        } else {
+            self.span.column += 1;
         }
+
+        self.pos += c.len_utf8();
     }
 
-    pub fn shift(&mut self) {
-        if !self.eof {
-            if self.current() == b'\n' {
-                self.span.line += 1;
-                self.span.column = 1;
-            } else {
-                self.span.column += 1;
-            }
-        }
+    /// Checks if every character of the input was read
+    pub fn is_eof(&self) -> bool {
+        self.pos >= self.text.len()
+    }
 
-        self.lookahead.pop_front();
-        self.fill_lookahead();
+    fn peek(&self, index: usize) -> char {
+        self.text[self.pos..].chars().nth(index).unwrap_or('\0')
     }
 
-    pub fn current(&self) -> u8 {
-        self.lookahead[0]
+    pub fn current(&self) -> char {
+        self.peek(0)
     }
 
-    pub fn next(&self) -> u8 {
-        self.lookahead[1]
+    pub fn next(&self) -> char {
+        self.peek(1)
     }
 
-    pub fn next_next(&self) -> u8 {
-        self.lookahead[2]
+    pub fn next_next(&self) -> char {
+        self.peek(2)
     }
 
     pub fn span(&self) -> Span {
         self.span
     }
-}
 
-impl CodeSource {
-    pub fn stdin() -> Self {
-        CodeSource::Stdin
+    /// Byte offset of the current character
+    pub fn offset(&self) -> usize {
+        self.pos
     }
+}
 
-    pub fn file(path: &str) -> Self {
-        CodeSource::File { path: path.to_string(), offset: 0 }
-    }
+/// Removes the files and directories a test writes when it ends, even when it fails
+#[cfg(test)]
+pub struct TempFiles(pub Vec<std::path::PathBuf>);
 
-    pub fn str(code: &'static str) -> Self {
-        CodeSource::Str { code, offset: 0 }
+#[cfg(test)]
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = if path.is_dir() { std::fs::remove_dir_all(path) } else { std::fs::remove_file(path) };
+        }
     }
 }
 
@@ -151,33 +224,91 @@ mod tests {
 
     #[test]
     fn test_file_read() {
-        let source = CodeSource::file("test.c");
+        // Cargo runs tests from the root of the crate, where `pruebas.txt` is the only sample file
+        let mut sources = SourceMap::new();
+        let id = sources.load("pruebas.txt").expect("pruebas.txt");
+        assert_eq!(Ok(id), sources.load("pruebas.txt").map_err(|e| e.to_string()));
+        assert_eq!(1, sources.files().len());
 
-        let mut reader = SourceReader::new(source);
+        let mut reader = sources.reader(id);
 
         loop {
-            let c = reader.current();
-            println!("{:?} => {:?}", c as char, reader.span());
-            if c == 0 { break; }
+            if reader.is_eof() { break; }
             reader.shift();
         }
+        assert_eq!(sources.file(id).location(reader.offset()), reader.span());
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let mut sources = SourceMap::new();
+        let error = sources.load("does_not_exist.dl").unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.error.kind());
+        assert!(error.to_string().starts_with("unable to read `does_not_exist.dl`: "), "{}", error);
+        assert!(sources.files().is_empty());
     }
 
     #[test]
     fn test_str_read() {
-        let source = CodeSource::str("\
-            123456789012345678901234567890123456789012345678901234567890123456789012345\n\
-            6789012345678901234567890123456789012345678901234567890123456789012345678901234567\n\
-            8901234567890123456789012345678901234567890123456789012345678901234567890123456789\n"
-        );
+        let mut sources = SourceMap::new();
+        let first = sources.add("<repl:1>", "a = 1");
+        let second = sources.add("<repl:2>", format!("{}\n{}", "b", "= 2"));
+        assert_eq!((0, 1), (first, second));
+        assert_eq!("<repl:2>", sources.file(second).name);
 
-        let mut reader = SourceReader::new(source);
+        let mut reader = sources.reader(second);
+        reader.shift_multiple(2);
+        assert_eq!('=', reader.current());
+        assert_eq!(Span { line: 2, column: 1 }, reader.span());
+    }
 
-        loop {
-            let c = reader.current();
-            println!("{:?} => {:?}", c as char, reader.span());
-            if c == 0 { break; }
-            reader.shift();
-        }
+    #[test]
+    fn test_span_at_end_of_input() {
+        let mut reader = SourceReader::new("ab\nc");
+        reader.shift_multiple(3);
+        assert_eq!(Span { line: 2, column: 1 }, reader.span());
+        reader.shift();
+        assert_eq!(Span { line: 2, column: 2 }, reader.span());
+        assert_eq!('\0', reader.current());
+        reader.shift();
+        assert_eq!(Span { line: 2, column: 2 }, reader.span());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_utf8_read() {
+        let mut reader = SourceReader::new("aé☕😀\nñ");
+        reader.shift();
+
+        assert_eq!('é', reader.current());
+        assert_eq!('☕', reader.next());
+        assert_eq!('😀', reader.next_next());
+        reader.shift_multiple(3);
+        assert_eq!(Span { line: 1, column: 5 }, reader.span());
+        assert_eq!(10, reader.offset());
+
+        reader.shift();
+        assert_eq!('ñ', reader.current());
+        assert_eq!(Span { line: 2, column: 1 }, reader.span());
+        reader.shift();
+        assert_eq!('\0', reader.current());
+    }
+
+    #[test]
+    fn test_locations() {
+        let file = SourceFile::new(0, "main.dl", "ab\r\nñc\n\nd");
+
+        assert_eq!(Span { line: 1, column: 1 }, file.location(0));
+        assert_eq!(Span { line: 1, column: 3 }, file.location(2));
+        assert_eq!(Span { line: 2, column: 1 }, file.location(4));
+        assert_eq!(Span { line: 2, column: 2 }, file.location(6));
+        assert_eq!(Span { line: 3, column: 1 }, file.location(8));
+        assert_eq!(Span { line: 4, column: 2 }, file.location(100));
+
+        assert_eq!(Some("ab"), file.line(1));
+        assert_eq!(Some("ñc"), file.line(2));
+        assert_eq!(Some(""), file.line(3));
+        assert_eq!(Some("d"), file.line(4));
+        assert_eq!(None, file.line(5));
+        assert_eq!(None, file.line(0));
+    }
+}
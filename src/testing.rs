@@ -0,0 +1,61 @@
+use crate::ast::Program;
+use crate::builtins::register_builtins;
+use crate::compiler::{CompileError, Compiler};
+use crate::parser::Parser;
+use crate::run::{CompiledProgram, Value};
+use crate::runtime::{Runtime, RuntimeError};
+use crate::source::SourceMap;
+use crate::tokenizer::Tokenizer;
+
+/// Parser, compiler and runtime with the builtins, wired like the command line driver does it, for the
+/// tests of every module. Options are set on the fields before running, like
+/// `runtime.set_recursion_limit`, and the code of each call is added to `sources` as `main.dl`.
+pub struct Pipeline {
+    pub sources: SourceMap,
+    pub compiler: Compiler,
+    pub runtime: Runtime,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        let mut runtime = Runtime::new();
+        register_builtins(&mut runtime);
+
+        let mut compiler = Compiler::new();
+        compiler.declare_builtins(&runtime);
+
+        Pipeline { sources: SourceMap::new(), compiler, runtime }
+    }
+
+    pub fn parse(&mut self, code: &str) -> Program {
+        let file = self.sources.add("main.dl", code);
+        let tokenizer = Tokenizer::new(self.sources.reader(file));
+        Parser::with_file(tokenizer, file).parse_program().expect("ParseError")
+    }
+
+    pub fn compile(&mut self, code: &str) -> Result<CompiledProgram, Vec<CompileError>> {
+        let program = self.parse(code);
+        self.compiler.compile(program)
+    }
+
+    pub fn run(&mut self, code: &str) -> Result<Value, RuntimeError> {
+        let program = self.parse(code);
+        self.run_program(program)
+    }
+
+    pub fn run_program(&mut self, program: Program) -> Result<Value, RuntimeError> {
+        let compiled = self.compiler.compile(program).expect("CompileError");
+        self.runtime.run(compiled)
+    }
+
+    /// Runs code that evaluates to `True` or `False`, decoded by the runtime that produced it
+    pub fn run_bool(&mut self, code: &str) -> bool {
+        let value = self.run(code).unwrap();
+        self.runtime.as_boolean(&value).unwrap_or_else(|| panic!("Expected a boolean: {:?}", value))
+    }
+}
+
+/// Runs code with the default options
+pub fn run(code: &str) -> Result<Value, RuntimeError> {
+    Pipeline::new().run(code)
+}
@@ -1,3 +1,7 @@
+use std::fmt;
+
+use unicode_xid::UnicodeXID;
+
 use crate::source::{SourceReader, Span};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -23,8 +27,10 @@ pub enum Token {
     For,
     Goto,
     If,
+    Import,
     Int,
     Long,
+    Match,
     Register,
     Return,
     Short,
@@ -39,6 +45,7 @@ pub enum Token {
     Void,
     Volatile,
     While,
+    With,
     // Symbols
     LeftParen,
     RightParen,
@@ -89,20 +96,163 @@ pub enum Token {
     PlusAssign,
     OrAssign,
     Dot,
+    // Interpolated strings, `"a ${x} b ${y} c"` is `StringStart("a ")`, the tokens of `x`,
+    // `StringMiddle(" b ")`, the tokens of `y` and `StringEnd(" c")`
+    StringStart(String),
+    StringMiddle(String),
+    StringEnd(String),
     // End of file
     Eof,
-    Error(char, Span),
+    /// Text that is not a valid token, the span points at the exact problem
+    Error(LexError, TokenSpan),
 }
 
 pub type TokenSpan = (Span, Span);
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LexError {
+    UnexpectedChar(char),
+    /// The input ended before the closing `"`, the span points at the opening one
+    UnterminatedString,
+    /// The input ended before the closing `*/`, the span points at the opening `/*`
+    UnterminatedComment,
+    InvalidEscape(String),
+    /// Number with digits not valid for its base or followed by letters, like `0b12` or `12px`
+    InvalidNumber(String),
+    /// Decimal integer starting with `0`, like `017`
+    LeadingZero(String),
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnexpectedChar(c) if c.is_control() => write!(f, "unexpected character `{}`", c.escape_debug()),
+            LexError::UnexpectedChar(c) => write!(f, "unexpected character `{}`", c),
+            LexError::UnterminatedString => write!(f, "unterminated string"),
+            LexError::UnterminatedComment => write!(f, "unterminated block comment"),
+            LexError::InvalidEscape(text) => write!(f, "invalid escape sequence `{}`", text),
+            LexError::InvalidNumber(text) => write!(f, "invalid number `{}`", text),
+            LexError::LeadingZero(text) => write!(f, "leading zeros are not allowed in `{}`", text),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Identifier(text) => text,
+            Token::FloatLiteral(text) => text,
+            Token::IntLiteral(text) => text,
+            Token::StringLiteral(text) => return write!(f, "{:?}", text),
+            Token::StringStart(text) => return write!(f, "\"{}${{", text.escape_debug()),
+            Token::StringMiddle(text) => return write!(f, "}}{}${{", text.escape_debug()),
+            Token::StringEnd(text) => return write!(f, "}}{}\"", text.escape_debug()),
+            Token::Auto => "auto",
+            Token::Break => "break",
+            Token::Case => "case",
+            Token::Char => "char",
+            Token::Const => "const",
+            Token::Continue => "continue",
+            Token::Default => "default",
+            Token::Do => "do",
+            Token::Double => "double",
+            Token::Else => "else",
+            Token::Enum => "enum",
+            Token::Extern => "extern",
+            Token::Float => "float",
+            Token::For => "for",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::Import => "import",
+            Token::Int => "int",
+            Token::Long => "long",
+            Token::Match => "match",
+            Token::Register => "register",
+            Token::Return => "return",
+            Token::Short => "short",
+            Token::Signed => "signed",
+            Token::Sizeof => "sizeof",
+            Token::Static => "static",
+            Token::Struct => "struct",
+            Token::Switch => "switch",
+            Token::Typedef => "type",
+            Token::Union => "union",
+            Token::Unsigned => "unsigned",
+            Token::Void => "void",
+            Token::Volatile => "volatile",
+            Token::While => "while",
+            Token::With => "with",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftBracket => "[",
+            Token::RightBracket => "]",
+            Token::Less => "<",
+            Token::LessEquals => "<=",
+            Token::Greater => ">",
+            Token::GreaterEquals => ">=",
+            Token::LeftAssign => "<<=",
+            Token::RightAssign => ">>=",
+            Token::LeftShift => "<<",
+            Token::RightShift => ">>",
+            Token::Ellipsis => "...",
+            Token::Tilde => "~",
+            Token::QuestionMark => "?",
+            Token::Semicolon => ";",
+            Token::Equals => "==",
+            Token::Assign => "=",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::NotEquals => "!=",
+            Token::Not => "!",
+            Token::At => "@",
+            Token::Hash => "#",
+            Token::Dollar => "$",
+            Token::Percent => "%",
+            Token::Xor => "^",
+            Token::Ampersand => "&",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Times => "*",
+            Token::Div => "/",
+            Token::Minus => "-",
+            Token::MinusMinus => "--",
+            Token::Plus => "+",
+            Token::PlusPlus => "++",
+            Token::Pointer => "->",
+            Token::Pipe => "|",
+            Token::PercentAssign => "%=",
+            Token::XorAssign => "^=",
+            Token::AndAssign => "&=",
+            Token::DivAssign => "/=",
+            Token::TimesAssign => "*=",
+            Token::MinusAssign => "-=",
+            Token::PlusAssign => "+=",
+            Token::OrAssign => "|=",
+            Token::Dot => ".",
+            Token::Eof => "end of file",
+            Token::Error(error, _) => return write!(f, "{}", error),
+        };
+
+        write!(f, "{}", text)
+    }
+}
+
 pub struct Tokenizer {
     read: SourceReader,
+    /// Start of each string with an open `${` and the number of unclosed `{` inside it, the
+    /// innermost last
+    interpolations: Vec<(Span, u32)>,
+    /// Start of the string being read, for unterminated string errors
+    string_start: Span,
+    /// Start of a `/*` the input ended in, reported as the next token
+    unterminated_comment: Option<Span>,
 }
 
 impl Tokenizer {
     pub fn new(reader: SourceReader) -> Self {
-        Tokenizer { read: reader }
+        Tokenizer { read: reader, interpolations: vec![], string_start: Span { line: 1, column: 1 }, unterminated_comment: None }
     }
 
     pub fn next_tk(&mut self) -> Token {
@@ -117,162 +267,197 @@ impl Tokenizer {
     pub fn next(&mut self) -> (Token, TokenSpan) {
         self.trim_spaces();
         self.trim_comments();
+
+        if let Some(start) = self.unterminated_comment.take() {
+            let end = Span { line: start.line, column: start.column + 2 };
+            return (Token::Error(LexError::UnterminatedComment, (start, end)), (start, end));
+        }
+
         let start = self.read.span();
         let ty = match self.read.current() {
-            b'a'..=b'z' | b'A'..=b'Z' | b'_' => self.read_identifier(),
-            b'0'..=b'9' => self.read_number(),
-            b'.' => {
-                if let b'0'..=b'9' = self.read.next() {
+            'r' if self.read.next() == '"' || self.read.next() == '#' => self.read_raw_string(),
+            c if c == '_' || c.is_xid_start() => self.read_identifier(),
+            '0'..='9' => self.read_number(),
+            '.' => {
+                if let '0'..='9' = self.read.next() {
                     self.read_number()
-                } else if self.read.next() == b'.' && self.read.next_next() == b'.' {
+                } else if self.read.next() == '.' && self.read.next_next() == '.' {
                     self.read.shift_multiple(2);
                     self.produce(Token::Ellipsis)
                 } else {
                     self.produce(Token::Dot)
                 }
             }
-            b'\"' => {
+            '\"' => {
                 self.read_string()
             }
-            b'(' => self.produce(Token::LeftParen),
-            b')' => self.produce(Token::RightParen),
-            b'{' => self.produce(Token::LeftBrace),
-            b'}' => self.produce(Token::RightBrace),
-            b'[' => self.produce(Token::LeftBracket),
-            b']' => self.produce(Token::RightBracket),
-            b'~' => self.produce(Token::Tilde),
-            b'?' => self.produce(Token::QuestionMark),
-            b'<' => {
-                if self.read.next() == b'<' && self.read.next_next() == b'=' {
+            '(' => self.produce(Token::LeftParen),
+            ')' => self.produce(Token::RightParen),
+            '{' => {
+                if let Some((_, depth)) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.produce(Token::LeftBrace)
+            }
+            '}' => {
+                match self.interpolations.last_mut() {
+                    // End of the `${`, the string continues
+                    Some((start, 0)) => {
+                        self.string_start = *start;
+                        self.interpolations.pop();
+                        self.read.shift();
+                        match self.read_string_part() {
+                            Ok((text, true)) => Token::StringMiddle(text),
+                            Ok((text, false)) => Token::StringEnd(text),
+                            Err(error) => error,
+                        }
+                    }
+                    Some((_, depth)) => {
+                        *depth -= 1;
+                        self.produce(Token::RightBrace)
+                    }
+                    None => self.produce(Token::RightBrace),
+                }
+            }
+            '[' => self.produce(Token::LeftBracket),
+            ']' => self.produce(Token::RightBracket),
+            '~' => self.produce(Token::Tilde),
+            '?' => self.produce(Token::QuestionMark),
+            '<' => {
+                if self.read.next() == '<' && self.read.next_next() == '=' {
                     self.read.shift_multiple(2);
                     self.produce(Token::LeftAssign)
-                } else if self.read.next() == b'<' {
+                } else if self.read.next() == '<' {
                     self.read.shift();
                     self.produce(Token::LeftShift)
-                } else if self.read.next() == b'=' {
+                } else if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::LessEquals)
                 } else {
                     self.produce(Token::Less)
                 }
             }
-            b'>' => {
-                if self.read.next() == b'>' && self.read.next_next() == b'=' {
+            '>' => {
+                if self.read.next() == '>' && self.read.next_next() == '=' {
                     self.read.shift_multiple(2);
                     self.produce(Token::RightAssign)
-                } else if self.read.next() == b'>' {
+                } else if self.read.next() == '>' {
                     self.read.shift();
                     self.produce(Token::RightShift)
-                } else if self.read.next() == b'=' {
+                } else if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::GreaterEquals)
                 } else {
                     self.produce(Token::Greater)
                 }
             }
-            b';' => self.produce(Token::Semicolon),
-            b':' => self.produce(Token::Colon),
-            b',' => self.produce(Token::Comma),
-            b'=' => {
-                if self.read.next() == b'=' {
+            ';' => self.produce(Token::Semicolon),
+            ':' => self.produce(Token::Colon),
+            ',' => self.produce(Token::Comma),
+            '=' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::Equals)
                 } else {
                     self.produce(Token::Assign)
                 }
             }
-            b'!' => {
-                if self.read.next() == b'=' {
+            '!' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::NotEquals)
                 } else {
                     self.produce(Token::Not)
                 }
             }
-            b'@' => self.produce(Token::At),
-            b'#' => self.produce(Token::Hash),
-            b'$' => self.produce(Token::Dollar),
-            b'%' => {
-                if self.read.next() == b'=' {
+            '@' => self.produce(Token::At),
+            '#' => self.produce(Token::Hash),
+            '$' => self.produce(Token::Dollar),
+            '%' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::PercentAssign)
                 } else {
                     self.produce(Token::Percent)
                 }
             }
-            b'^' => {
-                if self.read.next() == b'=' {
+            '^' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::XorAssign)
                 } else {
                     self.produce(Token::Xor)
                 }
             }
-            b'&' => {
-                if self.read.next() == b'=' {
+            '&' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::AndAssign)
-                } else if self.read.next() == b'&' {
+                } else if self.read.next() == '&' {
                     self.read.shift();
                     self.produce(Token::And)
                 } else {
                     self.produce(Token::Ampersand)
                 }
             }
-            b'|' => {
-                if self.read.next() == b'=' {
+            '|' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::OrAssign)
-                } else if self.read.next() == b'|' {
+                } else if self.read.next() == '|' {
                     self.read.shift();
                     self.produce(Token::Or)
                 } else {
                     self.produce(Token::Pipe)
                 }
             }
-            b'*' => {
-                if self.read.next() == b'=' {
+            '*' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::TimesAssign)
                 } else {
                     self.produce(Token::Times)
                 }
             }
-            b'/' => {
-                if self.read.next() == b'=' {
+            '/' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::DivAssign)
                 } else {
                     self.produce(Token::Div)
                 }
             }
-            b'-' => {
-                if self.read.next() == b'=' {
+            '-' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::MinusAssign)
-                } else if self.read.next() == b'-' {
+                } else if self.read.next() == '-' {
                     self.read.shift();
                     self.produce(Token::MinusMinus)
-                } else if self.read.next() == b'>' {
+                } else if self.read.next() == '>' {
                     self.read.shift();
                     self.produce(Token::Pointer)
                 } else {
                     self.produce(Token::Minus)
                 }
             }
-            b'+' => {
-                if self.read.next() == b'=' {
+            '+' => {
+                if self.read.next() == '=' {
                     self.read.shift();
                     self.produce(Token::PlusAssign)
-                } else if self.read.next() == b'+' {
+                } else if self.read.next() == '+' {
                     self.read.shift();
                     self.produce(Token::PlusPlus)
                 } else {
                     self.produce(Token::Plus)
                 }
             }
-            b'\0' => self.produce(Token::Eof),
-            _ => self.produce(Token::Error(self.read.current() as char, self.read.span())),
+            '\0' if self.read.is_eof() => self.produce(Token::Eof),
+            c => {
+                let span = (self.read.span(), self.read.span());
+                self.read.shift();
+                Token::Error(LexError::UnexpectedChar(c), (span.0, self.read.span()))
+            }
         };
         let end = self.read.span();
 
@@ -286,22 +471,26 @@ impl Tokenizer {
     }
 
     fn trim_comments(&mut self) {
-        if self.read.current() != b'/' { return; }
+        if self.read.current() != '/' { return; }
 
-        if self.read.next() == b'/' {
+        if self.read.next() == '/' {
             self.read.shift_multiple(2);
-            while self.read.current() != b'\n' && self.read.current() != 0 {
+            while self.read.current() != '\n' && !self.read.is_eof() {
                 self.read.shift();
             }
 
             self.trim_spaces();
             self.trim_comments();
-        } else if self.read.next() == b'*' {
+        } else if self.read.next() == '*' {
+            let start = self.read.span();
             self.read.shift_multiple(2);
 
             loop {
-                if self.read.current() == 0 { break; }
-                if self.read.current() == b'*' && self.read.next() == b'/' {
+                if self.read.is_eof() {
+                    self.unterminated_comment = Some(start);
+                    break;
+                }
+                if self.read.current() == '*' && self.read.next() == '/' {
                     // Skip the */
                     self.read.shift_multiple(2);
                     break;
@@ -318,8 +507,8 @@ impl Tokenizer {
         let mut id = String::new();
 
         loop {
-            let c = self.read.current() as char;
-            if let 'a'..='z' | 'A'..='Z' | '_' = c {
+            let c = self.read.current();
+            if c.is_xid_continue() {
                 id.push(c);
                 self.read.shift();
             } else {
@@ -348,8 +537,10 @@ impl Tokenizer {
             // "for" => Token::For,
             // "goto" => Token::Goto,
             // "if" => Token::If,
+            "import" => Token::Import,
             // "int" => Token::Int,
             // "long" => Token::Long,
+            "match" => Token::Match,
             // "register" => Token::Register,
             "return" => Token::Return,
             // "short" => Token::Short,
@@ -364,191 +555,288 @@ impl Tokenizer {
             // "void" => Token::Void,
             // "volatile" => Token::Volatile,
             // "while" => Token::While,
+            "with" => Token::With,
             _ => Token::Identifier(id)
         }
     }
 
+    /// Reads an Int or Float literal, `_` can separate digits. The text of the token has no `_` and
+    /// keeps the `0x`, `0o` or `0b` prefix of Ints in other bases.
     fn read_number(&mut self) -> Token {
-        let mut id = String::new();
-
-        if self.read.current() == b'0' {
-            self.read.shift();
-            if let b'0'..=b'9' = self.read.current() {
-                // octal number or decimal starting at 0
-                id.push('0');
-                self.read_digits(&mut id);
+        let start = self.read.span();
+        let mut text = String::new();
 
-                if self.read.current() == b'.' {
-                    id.push('.');
-                    self.read.shift();
-                    self.read_digits(&mut id);
-                    self.read_exponent(&mut id);
+        let radix = match (self.read.current(), self.read.next()) {
+            ('0', 'x') | ('0', 'X') => 16,
+            ('0', 'o') | ('0', 'O') => 8,
+            ('0', 'b') | ('0', 'B') => 2,
+            _ => 10,
+        };
 
-                    if let b'f' | b'F' | b'l' | b'L' = self.read.current() {
-                        self.read.shift();
-                    }
-                    Token::FloatLiteral(id)
-                } else {
-                    while let b'u' | b'U' | b'l' | b'L' = self.read.current() {
-                        self.read.shift();
-                    }
-                    Token::IntLiteral(id)
-                }
-            } else if self.read.current() == b'x' || self.read.current() == b'X' {
-                // hex number
-                id.push('0');
-                id.push('x');
-                self.read.shift();
+        if radix != 10 {
+            text.push('0');
+            text.push(self.read.next().to_ascii_lowercase());
+            self.read.shift_multiple(2);
 
-                loop {
-                    let c = self.read.current();
-                    if let b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' = c {
-                        id.push((c as char).to_ascii_uppercase());
-                        self.read.shift();
-                    } else {
-                        break;
-                    }
-                }
-                while let b'u' | b'U' | b'l' | b'L' = self.read.current() {
-                    self.read.shift();
-                }
-                Token::IntLiteral(id)
-            } else if self.read.current() == b'.' {
-                // decimal number
-                id.push('0');
-                id.push('.');
+            let mut valid = false;
+            while self.read.current().is_xid_continue() {
+                let c = self.read.current();
+                valid |= c != '_';
+                text.push(c);
                 self.read.shift();
-                self.read_digits(&mut id);
-                self.read_exponent(&mut id);
+            }
 
-                if let b'f' | b'F' | b'l' | b'L' = self.read.current() {
-                    self.read.shift();
-                }
-                Token::FloatLiteral(id)
-            } else {
-                // just zero
-                while let b'u' | b'U' | b'l' | b'L' = self.read.current() {
-                    self.read.shift();
-                }
-                Token::IntLiteral("0".to_string())
+            let digits = text[2..].replace('_', "").to_ascii_uppercase();
+            if !valid || !digits.chars().all(|c| c.is_digit(radix)) {
+                return Token::Error(LexError::InvalidNumber(text), (start, self.read.span()));
             }
-        } else if self.read.current() == b'.' {
-            id.push('0');
-            id.push('.');
+
+            return Token::IntLiteral(format!("{}{}", &text[..2], digits));
+        }
+
+        let mut is_float = false;
+
+        if self.read.current() == '.' {
+            text.push('0');
+        } else {
+            self.read_digits(&mut text);
+        }
+
+        // `1.5` but not `1.abs`, that's a call
+        if self.read.current() == '.' && self.read.next().is_ascii_digit() {
+            is_float = true;
+            text.push('.');
             self.read.shift();
-            self.read_digits(&mut id);
-            self.read_exponent(&mut id);
+            self.read_digits(&mut text);
+        }
+
+        let exponent_digit = match self.read.next() {
+            '+' | '-' => self.read.next_next().is_ascii_digit(),
+            c => c.is_ascii_digit(),
+        };
 
-            if let b'f' | b'F' | b'l' | b'L' = self.read.current() {
+        if (self.read.current() == 'e' || self.read.current() == 'E') && exponent_digit {
+            if !is_float {
+                text.push_str(".0");
+            }
+            is_float = true;
+            self.read_exponent(&mut text);
+        }
+
+        // Letters glued to the number, like `12px`
+        if self.read.current().is_xid_continue() {
+            while self.read.current().is_xid_continue() {
+                text.push(self.read.current());
                 self.read.shift();
             }
-            Token::FloatLiteral(id)
+            return Token::Error(LexError::InvalidNumber(text), (start, self.read.span()));
+        }
+
+        if !is_float && text.len() > 1 && text.starts_with('0') {
+            return Token::Error(LexError::LeadingZero(text), (start, self.read.span()));
+        }
+
+        if is_float {
+            Token::FloatLiteral(text)
         } else {
-            self.read_digits(&mut id);
+            Token::IntLiteral(text)
+        }
+    }
 
-            if self.read.current() == b'.' {
-                id.push('.');
-                self.read.shift();
-                self.read_digits(&mut id);
-                self.read_exponent(&mut id);
+    /// Reads decimal digits and `_` separators, the separators are not added to `text`
+    fn read_digits(&mut self, text: &mut String) {
+        loop {
+            let c = self.read.current();
+            if c.is_ascii_digit() {
+                text.push(c);
+            } else if c != '_' {
+                break;
+            }
+            self.read.shift();
+        }
+    }
 
-                if let b'f' | b'F' | b'l' | b'L' = self.read.current() {
-                    self.read.shift();
-                }
-                Token::FloatLiteral(id)
-            } else if self.read.current() == b'e' || self.read.current() == b'E' {
-                id.push('.');
-                id.push('0');
-                self.read_exponent(&mut id);
+    fn read_exponent(&mut self, text: &mut String) {
+        text.push('e');
+        self.read.shift();
+
+        if self.read.current() == '+' || self.read.current() == '-' {
+            text.push(self.read.current());
+            self.read.shift();
+        } else {
+            text.push('+');
+        }
+
+        self.read_digits(text);
+    }
 
-                if let b'f' | b'F' | b'l' | b'L' = self.read.current() {
+    fn read_string(&mut self) -> Token {
+        let start = self.read.span();
+        // First "
+        self.read.shift();
+
+        self.string_start = start;
+        match self.read_string_part() {
+            Ok((text, true)) => Token::StringStart(text),
+            Ok((text, false)) => Token::StringLiteral(text),
+            Err(error) => error,
+        }
+    }
+
+    /// Reads the text of a string until the closing `"` or the next `${`, returns true if it stopped
+    /// at a `${`. After an invalid escape the rest of the string is still read, so the next token
+    /// starts after it.
+    fn read_string_part(&mut self) -> Result<(String, bool), Token> {
+        let mut content = String::new();
+        let mut error = None;
+
+        let interpolated = loop {
+            match self.read.current() {
+                '"' => {
                     self.read.shift();
+                    break false;
                 }
-                Token::FloatLiteral(id)
-            } else {
-                while let b'u' | b'U' | b'l' | b'L' = self.read.current() {
+                '\0' if self.read.is_eof() => {
+                    let start = self.string_start;
+                    let end = Span { line: start.line, column: start.column + 1 };
+                    return Err(Token::Error(LexError::UnterminatedString, (start, end)));
+                }
+                '$' if self.read.next() == '{' => {
+                    self.read.shift_multiple(2);
+                    self.interpolations.push((self.string_start, 0));
+                    break true;
+                }
+                '\\' => {
+                    match self.read_escape() {
+                        Ok(c) => content.push(c),
+                        Err(e) => { error.get_or_insert(e); }
+                    }
+                }
+                c => {
+                    content.push(c);
                     self.read.shift();
                 }
-                Token::IntLiteral(id)
             }
+        };
+
+        match error {
+            Some(error) => Err(error),
+            None => Ok((content, interpolated)),
         }
     }
 
-    fn read_digits(&mut self, id: &mut String) -> u32 {
-        let mut count = 0;
-        loop {
-            let c = self.read.current();
-            if !c.is_ascii_digit() { break; }
-            id.push(c as char);
-            self.read.shift();
-            count += 1;
-        }
+    /// Reads an escape sequence starting at the `\`
+    fn read_escape(&mut self) -> Result<char, Token> {
+        let start = self.read.span();
+        self.read.shift();
 
-        count
+        let c = self.read.current();
+        let value = match c {
+            '0' => '\0',
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '\\' | '"' | '\'' | '$' => c,
+            'u' if self.read.next() == '{' => return self.read_unicode_escape(start),
+            _ => {
+                let mut text = String::from("\\");
+                if !self.read.is_eof() {
+                    text.push(c);
+                    self.read.shift();
+                }
+                return Err(Token::Error(LexError::InvalidEscape(text), (start, self.read.span())));
+            }
+        };
+
+        self.read.shift();
+        Ok(value)
     }
 
-    fn read_exponent(&mut self, id: &mut String) {
-        if self.read.current() != b'e' && self.read.current() != b'E' {
-            return;
+    /// Reads the `u{...}` of a `\u{...}` escape, with 1 to 6 hex digits
+    fn read_unicode_escape(&mut self, start: Span) -> Result<char, Token> {
+        let mut text = String::from("\\u{");
+        // u{
+        self.read.shift_multiple(2);
+
+        while self.read.current().is_ascii_hexdigit() {
+            text.push(self.read.current());
+            self.read.shift();
         }
 
-        if id.as_bytes()[id.len() - 1] == b'.' {
-            id.push('0');
+        let closed = self.read.current() == '}';
+        if closed {
+            text.push('}');
+            self.read.shift();
         }
 
-        id.push('e');
+        let digits = &text[3..text.len() - closed as usize];
+        let value = if closed && !digits.is_empty() && digits.len() <= 6 {
+            u32::from_str_radix(digits, 16).ok().and_then(char::from_u32)
+        } else {
+            None
+        };
+
+        value.ok_or_else(|| Token::Error(LexError::InvalidEscape(text), (start, self.read.span())))
+    }
+
+    /// Reads `r"..."` or `r#"..."#` with any number of `#`, without escapes nor interpolation
+    fn read_raw_string(&mut self) -> Token {
+        let start = self.read.span();
+        // r
         self.read.shift();
 
-        if self.read.current() == b'+' || self.read.current() == b'-' {
-            id.push(self.read.current() as char);
+        let mut hashes = 0;
+        while self.read.current() == '#' {
+            hashes += 1;
             self.read.shift();
-        } else {
-            id.push('+');
         }
 
-        self.read_digits(id);
-    }
+        if self.read.current() != '"' {
+            let span = (self.read.span(), self.read.span());
+            let c = self.read.current();
+            return Token::Error(LexError::UnexpectedChar(c), span);
+        }
+        self.read.shift();
 
-    fn read_string(&mut self) -> Token {
         let mut content = String::new();
-        // First "
-        self.read.shift();
 
         loop {
             match self.read.current() {
-                b'"' => {
+                '\0' if self.read.is_eof() => {
+                    let end = Span { line: start.line, column: start.column + 1 };
+                    return Token::Error(LexError::UnterminatedString, (start, end));
+                }
+                '"' => {
                     self.read.shift();
-                    break;
+                    let mut found = 0;
+                    while found < hashes && self.read.current() == '#' {
+                        found += 1;
+                        self.read.shift();
+                    }
+
+                    if found == hashes {
+                        return Token::StringLiteral(content);
+                    }
+                    content.push('"');
+                    content.push_str(&"#".repeat(found));
                 }
-                b'\\' => {
+                c => {
+                    content.push(c);
                     self.read.shift();
-                    let value = match self.read.current() {
-                        b'0' => 0,
-                        b'n' => b'\n',
-                        b't' => b'\t',
-                        b'r' => b'\r',
-                        c => c
-                    };
-                    content.push(value as char);
                 }
-                c => content.push(c as char)
             }
-            self.read.shift();
         }
-
-        Token::StringLiteral(content)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::source::CodeSource;
 
     use super::*;
 
     #[test]
     fn test_next_token() {
-        let source = CodeSource::str("
+        let source = "
             //
             // Created by cout970 on 12/8/19.
             //
@@ -557,15 +845,16 @@ mod tests {
                return 0;
             }
 
-            ");
+            ";
 
         let reader = SourceReader::new(source);
         let mut tokenizer = Tokenizer::new(reader);
 
-        assert_eq!(Token::Int, tokenizer.next_tk());   // int
+        // `int` is not a keyword, `read_identifier` has it commented out, so it's a plain identifier
+        assert_eq!(Token::Identifier("int".to_string()), tokenizer.next_tk());   // int
         assert_eq!(Token::Identifier("main".to_string()), tokenizer.next_tk());   // main
         assert_eq!(Token::LeftParen, tokenizer.next_tk());    // (
-        assert_eq!(Token::Int, tokenizer.next_tk());   // int
+        assert_eq!(Token::Identifier("int".to_string()), tokenizer.next_tk());   // int
         assert_eq!(Token::Identifier("i".to_string()), tokenizer.next_tk());   // i
         assert_eq!(Token::RightParen, tokenizer.next_tk());   // )
         assert_eq!(Token::LeftBrace, tokenizer.next_tk());    // {
@@ -578,15 +867,13 @@ mod tests {
 
     #[test]
     fn test_constants() {
-        let source = CodeSource::str("\
+        let source = "\
         0x0123456789abcdef\n     0x0123456789ABCDEF
-        0XABC\n                  0x012345L\n        0x012345l\n      0x012345U
-        0x012345u\n              0x012345uL\n       0x012345ul\n     0x012345Ul
-        0x012345ul\n             01234567\n         01234567ul\n     01234567LU
-        123456e+123f\n           123456e10\n        123456E+123f\n   123456e-123l
-        123456e-123\n            0123456.1325\n     .123\n           .123f
-        .123e123l\n              123.e12\n          123.e12F
-        ");
+        0XABC\n                  0xFF_FF\n          0b1010\n       0o17
+        0B1_0\n                  1_000_000\n        0\n            123456e+123
+        123456e10\n              123456E+123\n      123456e-123\n  0.1325
+        .123\n                   .123e123\n         1_0.5_0\n      3.abs
+        ";
 
         let reader = SourceReader::new(source);
         let mut tokenizer = Tokenizer::new(reader);
@@ -594,33 +881,115 @@ mod tests {
         assert_eq!(Token::IntLiteral("0x0123456789ABCDEF".to_string()), tokenizer.next_tk());
         assert_eq!(Token::IntLiteral("0x0123456789ABCDEF".to_string()), tokenizer.next_tk());
         assert_eq!(Token::IntLiteral("0xABC".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("0x012345".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("01234567".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("01234567".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::IntLiteral("01234567".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::IntLiteral("0xFFFF".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::IntLiteral("0b1010".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::IntLiteral("0o17".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::IntLiteral("0b10".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::IntLiteral("1000000".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::IntLiteral("0".to_string()), tokenizer.next_tk());
         assert_eq!(Token::FloatLiteral("123456.0e+123".to_string()), tokenizer.next_tk());
         assert_eq!(Token::FloatLiteral("123456.0e+10".to_string()), tokenizer.next_tk());
         assert_eq!(Token::FloatLiteral("123456.0e+123".to_string()), tokenizer.next_tk());
         assert_eq!(Token::FloatLiteral("123456.0e-123".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::FloatLiteral("123456.0e-123".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::FloatLiteral("0123456.1325".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::FloatLiteral("0.123".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::FloatLiteral("0.1325".to_string()), tokenizer.next_tk());
         assert_eq!(Token::FloatLiteral("0.123".to_string()), tokenizer.next_tk());
         assert_eq!(Token::FloatLiteral("0.123e+123".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::FloatLiteral("123.0e+12".to_string()), tokenizer.next_tk());
-        assert_eq!(Token::FloatLiteral("123.0e+12".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::FloatLiteral("10.50".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::IntLiteral("3".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Dot, tokenizer.next_tk());
+        assert_eq!(Token::Identifier("abs".to_string()), tokenizer.next_tk());
+    }
+
+    #[test]
+    fn test_invalid_constants() {
+        let source = "017 0x 0b102 0o8 12px 1.5f 0x12345L";
+        let reader = SourceReader::new(source);
+        let mut tokenizer = Tokenizer::new(reader);
+
+        let mut next_error = || match tokenizer.next_tk() {
+            Token::Error(error, _) => error,
+            other => panic!("Expected an error: {:?}", other),
+        };
+
+        assert_eq!(LexError::LeadingZero("017".to_string()), next_error());
+        assert_eq!(LexError::InvalidNumber("0x".to_string()), next_error());
+        assert_eq!(LexError::InvalidNumber("0b102".to_string()), next_error());
+        assert_eq!(LexError::InvalidNumber("0o8".to_string()), next_error());
+        assert_eq!(LexError::InvalidNumber("12px".to_string()), next_error());
+        assert_eq!(LexError::InvalidNumber("1.5f".to_string()), next_error());
+        assert_eq!(LexError::InvalidNumber("0x12345L".to_string()), next_error());
+    }
+
+    #[test]
+    fn test_identifiers_with_digits() {
+        let source = "player1 x2y _3";
+        let reader = SourceReader::new(source);
+        let mut tokenizer = Tokenizer::new(reader);
+
+        assert_eq!(Token::Identifier("player1".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Identifier("x2y".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Identifier("_3".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Eof, tokenizer.next_tk());
+    }
+
+    #[test]
+    fn test_string_errors() {
+        let source = "\"a\\qb\" \"\\u{zz}\" x\n  \"open";
+        let reader = SourceReader::new(source);
+        let mut tokenizer = Tokenizer::new(reader);
+
+        let span = |line, start, end| (Span { line, column: start }, Span { line, column: end });
+
+        assert_eq!(Token::Error(LexError::InvalidEscape("\\q".to_string()), span(1, 3, 5)), tokenizer.next_tk());
+        assert_eq!(Token::Error(LexError::InvalidEscape("\\u{".to_string()), span(1, 9, 12)), tokenizer.next_tk());
+        // The rest of the string is skipped after an error
+        assert_eq!(Token::Identifier("x".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Error(LexError::UnterminatedString, span(2, 3, 4)), tokenizer.next_tk());
+        assert_eq!(Token::Eof, tokenizer.next_tk());
+    }
+
+    #[test]
+    fn test_nul_characters() {
+        let source = "a\0b // \0 c\n\"\0\" r\"\0\"";
+        let reader = SourceReader::new(source);
+        let mut tokenizer = Tokenizer::new(reader);
+
+        // A NUL in the text is not the end of the input
+        assert_eq!(Token::Identifier("a".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Error(LexError::UnexpectedChar('\0'), (Span { line: 1, column: 2 }, Span { line: 1, column: 3 })), tokenizer.next_tk());
+        assert_eq!(Token::Identifier("b".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::StringLiteral("\0".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::StringLiteral("\0".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Eof, tokenizer.next_tk());
+
+        assert_eq!("unexpected character `\\0`", LexError::UnexpectedChar('\0').to_string());
+    }
+
+    #[test]
+    fn test_unterminated_comment() {
+        let reader = SourceReader::new("a /* b\n c");
+        let mut tokenizer = Tokenizer::new(reader);
+
+        assert_eq!(Token::Identifier("a".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Error(LexError::UnterminatedComment, (Span { line: 1, column: 3 }, Span { line: 1, column: 5 })), tokenizer.next_tk());
+        assert_eq!(Token::Eof, tokenizer.next_tk());
+    }
+
+    #[test]
+    fn test_raw_strings() {
+        let source = r###"r"C:\dir\${x}" r#"say "hi""# r##"a "# b"## r"open"###;
+        let reader = SourceReader::new(source);
+        let mut tokenizer = Tokenizer::new(reader);
+
+        assert_eq!(Token::StringLiteral(r"C:\dir\${x}".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::StringLiteral(r#"say "hi""#.to_string()), tokenizer.next_tk());
+        assert_eq!(Token::StringLiteral(r##"a "# b"##.to_string()), tokenizer.next_tk());
+        assert!(matches!(tokenizer.next_tk(), Token::Error(LexError::UnterminatedString, _)));
     }
 
     #[test]
     fn test_string() {
-        let source = CodeSource::str("\"Hello world\"\n\" \\t Test \\n \\\\ \"");
+        let source = "\"Hello world\"\n\" \\t Test \\n \\\\ \"";
         let reader = SourceReader::new(source);
         let mut tokenizer = Tokenizer::new(reader);
         assert_eq!(Token::StringLiteral("Hello world".to_string()), tokenizer.next_tk());
@@ -629,9 +998,9 @@ mod tests {
 
     #[test]
     fn test_especial_tokens() {
-        let source = CodeSource::str("\
+        let source = "\
             ... >>= <<= += -= *= /= %= &= ^= |= >> << ++ -- -> && || <= >= == != ;
-            ({|<%) (}|%>) , : = ( ) ([|<:) (]|:>) . & ! ~ - + * / % < > ^ | ?");
+            ({|<%) (}|%>) , : = ( ) ([|<:) (]|:>) . & ! ~ - + * / % < > ^ | ?";
 
         let reader = SourceReader::new(source);
         let mut tokenizer = Tokenizer::new(reader);
@@ -644,10 +1013,52 @@ mod tests {
 
     #[test]
     fn test() {
-        let source = CodeSource::str(".123e123l");
+        let source = ".123e123";
         let reader = SourceReader::new(source);
         let mut tokenizer = Tokenizer::new(reader);
-        assert_eq!(Token::FloatLiteral(".123e+123".to_string()), tokenizer.next_tk());
+        // Floats are normalized with a leading `0`, like `.123` in `test_constants`
+        assert_eq!(Token::FloatLiteral("0.123e+123".to_string()), tokenizer.next_tk());
+    }
+
+    #[test]
+    fn test_unicode() {
+        let source = "größe = \"naïve \\u{1F600}\\u{E9}\" _x1 日本";
+        let reader = SourceReader::new(source);
+        let mut tokenizer = Tokenizer::new(reader);
+
+        assert_eq!((Token::Identifier("größe".to_string()), (Span { line: 1, column: 1 }, Span { line: 1, column: 6 })), tokenizer.next());
+        assert_eq!(Token::Assign, tokenizer.next_tk());
+        assert_eq!(Token::StringLiteral("naïve 😀é".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Identifier("_x1".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Identifier("日本".to_string()), tokenizer.next_tk());
+        assert_eq!(Token::Eof, tokenizer.next_tk());
+    }
+
+    #[test]
+    fn test_interpolated_strings() {
+        let source = r#""a ${x} b ${ { y } } c" "${"in ${z}"}" "\${x} $ {}""#;
+        let reader = SourceReader::new(source);
+        let mut tokenizer = Tokenizer::new(reader);
+
+        let id = |name: &str| Token::Identifier(name.to_string());
+        let text = |text: &str| text.to_string();
+
+        assert_eq!(Token::StringStart(text("a ")), tokenizer.next_tk());
+        assert_eq!(id("x"), tokenizer.next_tk());
+        assert_eq!(Token::StringMiddle(text(" b ")), tokenizer.next_tk());
+        assert_eq!(Token::LeftBrace, tokenizer.next_tk());
+        assert_eq!(id("y"), tokenizer.next_tk());
+        assert_eq!(Token::RightBrace, tokenizer.next_tk());
+        assert_eq!(Token::StringEnd(text(" c")), tokenizer.next_tk());
+
+        assert_eq!(Token::StringStart(text("")), tokenizer.next_tk());
+        assert_eq!(Token::StringStart(text("in ")), tokenizer.next_tk());
+        assert_eq!(id("z"), tokenizer.next_tk());
+        assert_eq!(Token::StringEnd(text("")), tokenizer.next_tk());
+        assert_eq!(Token::StringEnd(text("")), tokenizer.next_tk());
+
+        assert_eq!(Token::StringLiteral(text("${x} $ {}")), tokenizer.next_tk());
+        assert_eq!(Token::Eof, tokenizer.next_tk());
     }
 
 //    #[test]